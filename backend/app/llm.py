@@ -5,6 +5,11 @@ from .config import OPENAI_API_KEY, MODEL_NAME, LLM_MAX_CANDIDATES
 
 # 優先して検索する拡張子
 EXCEL_EXTS = {".xlsx", ".xls", ".xlsm", ".xlsb", ".xltx", ".xltm", ".csv"}
+# 中身から判定された MIME タイプ (拡張子が無い/違うファイル用)
+EXCEL_MIMES = {
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    "application/vnd.oasis.opendocument.spreadsheet",
+}
 
 # LLMに送るプロンプト
 SYSTEM_PROMPT = (
@@ -35,6 +40,8 @@ def _normalize_items(items: Any) -> List[Dict[str, Any]]:
                 "last_opened": x.get("last_opened") or x.get("last_accessed"),
                 "last_modified": x.get("last_modified"),
                 "app_hint": x.get("app_hint"),
+                "mime": x.get("mime"),
+                "family": x.get("family"),
             }
             out.append(d)
     return out
@@ -44,7 +51,8 @@ def _excel_prefilter(items: List[Dict[str, Any]]) -> List[Dict[str, Any]]:
     def is_excel(d: Dict[str, Any]) -> bool:
         ext = (d.get("ext") or "").lower()
         name = (d.get("name") or d.get("path") or "").lower()
-        return any(name.endswith(e) for e in EXCEL_EXTS) or (ext in EXCEL_EXTS)
+        mime = (d.get("mime") or "").lower()
+        return any(name.endswith(e) for e in EXCEL_EXTS) or (ext in EXCEL_EXTS) or (mime in EXCEL_MIMES)
     excels = [d for d in items if is_excel(d)]
     return excels if excels else items
 
@@ -72,13 +80,14 @@ def select_file(prompt: str, raw_items: Any) -> str:
             f"{i}|name={d.get('name','')}|path={d.get('path','')}"
             f"|ext={d.get('ext','')}|last_opened={d.get('last_opened','')}"
             f"|last_modified={d.get('last_modified','')}|app_hint={d.get('app_hint','')}"
+            f"|mime={d.get('mime','')}"
         )
         lines.append(line)
     catalog = "\n".join(lines)
 
     user_message = (
         "User query: " + prompt.strip() + "\n\n"
-        + "Candidates (index|name|path|ext|last_opened|last_modified|app_hint):\n"
+        + "Candidates (index|name|path|ext|last_opened|last_modified|app_hint|mime):\n"
         + catalog + "\n\n"
         + "Return ONLY the winning file path(s). If multiple, one per line."
     )
@@ -0,0 +1,315 @@
+// 100万件の削除済みファイルで、元の HashMap 版 (パス文字列がキー) と今のアリーナ版の
+// インデックスを比べる (メモリ使用量、作成時間、フォルダ一覧とパス解決の時間)
+//
+//   cargo bench --bench index_arena
+//   UNUNLINK_BENCH_FILES=200000 cargo bench --bench index_arena
+//
+// メモリはアロケータで数えた確保中のバイト数 (作成前との差)
+#![allow(dead_code)]
+
+#[path = "../src/filetype.rs"]
+mod filetype;
+#[path = "../src/indexer.rs"]
+mod indexer;
+#[path = "../src/mft_stream.rs"]
+mod mft_stream;
+#[path = "../src/record.rs"]
+mod record;
+#[path = "../src/scan.rs"]
+mod scan;
+#[path = "../src/spill.rs"]
+mod spill;
+#[path = "../src/timestamps.rs"]
+mod timestamps;
+#[path = "../src/upcase.rs"]
+mod upcase;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use widestring::U16String;
+
+struct Counting;
+
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = System.alloc(layout);
+        if !p.is_null() {
+            LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let p = System.realloc(ptr, layout, new_size);
+        if !p.is_null() {
+            LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+            LIVE_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        }
+        p
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn live_bytes() -> usize {
+    LIVE_BYTES.load(Ordering::Relaxed)
+}
+
+// 元の HashMap 版 (d8c3d41 の indexer.rs) をベンチに必要な分だけ写したもの
+// パスは正規化済みで渡すので、キーは小文字にしたパスそのまま
+mod legacy {
+    use std::collections::{HashMap, HashSet};
+    use std::time::SystemTime;
+    use widestring::U16String;
+
+    #[derive(Debug, Clone)]
+    pub struct EntryMeta {
+        pub mft_no: u64,
+        pub is_dir: bool,
+        pub size: u64,
+        pub created: Option<SystemTime>,
+        pub modified: Option<SystemTime>,
+        pub accessed: Option<SystemTime>,
+        pub name_u16: U16String,
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum EntryOrDir {
+        Dir,
+        File(EntryMeta),
+    }
+
+    #[derive(Default)]
+    pub struct DeletedIndex {
+        pub nodes: HashMap<String, EntryOrDir>,
+        pub children_names: HashMap<String, Vec<U16String>>,
+        pub children_ci: HashMap<String, HashSet<String>>,
+    }
+
+    impl DeletedIndex {
+        fn ensure_dirs_from_root(&mut self, dir_path: &str) {
+            let mut parent_key = "\\".to_string();
+            self.nodes
+                .entry(parent_key.clone())
+                .or_insert(EntryOrDir::Dir);
+            self.children_names.entry(parent_key.clone()).or_default();
+            self.children_ci.entry(parent_key.clone()).or_default();
+
+            let mut acc = String::from("\\");
+            for comp in dir_path.split('\\').filter(|s| !s.is_empty()) {
+                let lower = comp.to_lowercase();
+                let names = self.children_names.entry(parent_key.clone()).or_default();
+                let set = self.children_ci.entry(parent_key.clone()).or_default();
+                if set.insert(lower) {
+                    names.push(U16String::from_str(comp));
+                }
+                if acc == "\\" {
+                    acc = format!("\\{}", comp);
+                } else {
+                    acc = format!("{}\\{}", acc, comp);
+                }
+                let dir_key = acc.to_lowercase();
+                self.nodes.entry(dir_key.clone()).or_insert(EntryOrDir::Dir);
+                self.children_names.entry(dir_key.clone()).or_default();
+                self.children_ci.entry(dir_key.clone()).or_default();
+                parent_key = dir_key;
+            }
+        }
+
+        pub fn insert_file(&mut self, full_path: &str, mut meta: EntryMeta) {
+            let (parent, base) = full_path.rsplit_once('\\').unwrap_or(("", full_path));
+            let parent = if parent.is_empty() { "\\" } else { parent };
+            self.ensure_dirs_from_root(parent);
+            let parent_key = parent.to_lowercase();
+            let lower = base.to_lowercase();
+            self.children_ci
+                .entry(parent_key.clone())
+                .or_default()
+                .insert(lower);
+            let name = U16String::from_str(base);
+            self.children_names
+                .entry(parent_key)
+                .or_default()
+                .push(name.clone());
+            meta.name_u16 = name;
+            self.nodes
+                .insert(full_path.to_lowercase(), EntryOrDir::File(meta));
+        }
+
+        pub fn get(&self, key_lc: &str) -> Option<&EntryOrDir> {
+            self.nodes.get(key_lc)
+        }
+
+        pub fn list_children(&self, dir_key_lc: &str) -> Vec<U16String> {
+            self.children_names
+                .get(dir_key_lc)
+                .cloned()
+                .unwrap_or_default()
+        }
+    }
+}
+
+const USERS: usize = 10;
+const PROJECTS: usize = 100;
+
+fn dir_path(d: usize) -> String {
+    format!(r"\Users\User{}\Documents\Project {}", d % USERS, d / USERS)
+}
+
+fn file_path(i: usize) -> String {
+    format!(r"{}\Report {:07}.docx", dir_path(i % (USERS * PROJECTS)), i)
+}
+
+fn arena_meta(i: usize) -> indexer::EntryMeta {
+    indexer::EntryMeta {
+        mft_no: i as u64 + 64,
+        sequence: 2,
+        is_dir: false,
+        size: (i as u64 % 1000) * 4096,
+        parent_ref: 0,
+        parent_status: indexer::ParentStatus::Resolved,
+        times: timestamps::RecordTimes::default(),
+        link_count: 1,
+        attributes: 0x20,
+        live: false,
+        live_match: indexer::LiveMatch::NotChecked,
+        content_type: None,
+    }
+}
+
+fn legacy_meta(i: usize) -> legacy::EntryMeta {
+    legacy::EntryMeta {
+        mft_no: i as u64 + 64,
+        is_dir: false,
+        size: (i as u64 % 1000) * 4096,
+        created: None,
+        modified: None,
+        accessed: None,
+        name_u16: U16String::new(),
+    }
+}
+
+struct Measured {
+    build: Duration,
+    bytes: usize,
+    list: Duration,
+    listed: usize,
+    lookup: Duration,
+}
+
+fn bench_legacy(files: usize) -> Measured {
+    let before = live_bytes();
+    let t = Instant::now();
+    let mut idx = legacy::DeletedIndex::default();
+    for i in 0..files {
+        idx.insert_file(&file_path(i), legacy_meta(i));
+    }
+    let build = t.elapsed();
+    let bytes = live_bytes().saturating_sub(before);
+
+    // Dokan から渡されたパスをキーにして一覧する (元の fs.rs と同じ)
+    let t = Instant::now();
+    let mut listed = 0;
+    for d in 0..USERS * PROJECTS {
+        let names = idx.list_children(&dir_path(d).to_lowercase());
+        listed += black_box(names).len();
+    }
+    let list = t.elapsed();
+
+    let t = Instant::now();
+    for i in (0..files).step_by(7) {
+        black_box(idx.get(&file_path(i).to_lowercase()));
+    }
+    let lookup = t.elapsed();
+    drop(idx);
+    Measured {
+        build,
+        bytes,
+        list,
+        listed,
+        lookup,
+    }
+}
+
+fn bench_arena(files: usize) -> Measured {
+    let before = live_bytes();
+    let t = Instant::now();
+    let mut idx = indexer::DeletedIndex::new(None, upcase::active());
+    for i in 0..files {
+        idx.insert_file(&U16String::from_str(&file_path(i)), None, arena_meta(i));
+    }
+    idx.finalize_names(indexer::DupPolicy::default());
+    let build = t.elapsed();
+    let bytes = live_bytes().saturating_sub(before);
+
+    let t = Instant::now();
+    let mut listed = 0;
+    for d in 0..USERS * PROJECTS {
+        let path: Vec<u16> = dir_path(d).encode_utf16().collect();
+        let id = idx.lookup(&path).expect("directory");
+        let names: Vec<U16String> = idx.list_children(id).map(|(_, n)| n.into_owned()).collect();
+        listed += black_box(names).len();
+    }
+    let list = t.elapsed();
+
+    let t = Instant::now();
+    for i in (0..files).step_by(7) {
+        let path: Vec<u16> = file_path(i).encode_utf16().collect();
+        black_box(idx.get(&path));
+    }
+    let lookup = t.elapsed();
+    drop(idx);
+    Measured {
+        build,
+        bytes,
+        list,
+        listed,
+        lookup,
+    }
+}
+
+fn main() {
+    // cargo bench はベンチ名などの引数を渡してくるが、ここでは使わない
+    let files: usize = std::env::var("UNUNLINK_BENCH_FILES")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(1_000_000);
+    println!(
+        "{} files in {} folders ({} lookups)",
+        files,
+        USERS * PROJECTS,
+        files.div_ceil(7)
+    );
+    println!(
+        "{:<10} {:>10} {:>12} {:>12} {:>12}",
+        "index", "build", "memory", "list all", "lookup"
+    );
+    for (name, r) in [
+        ("hashmap", bench_legacy(files)),
+        ("arena", bench_arena(files)),
+    ] {
+        assert_eq!(
+            r.listed, files,
+            "{} listed a different number of files",
+            name
+        );
+        println!(
+            "{:<10} {:>8.0}ms {:>9.1} MB {:>10.1}ms {:>10.1}ms",
+            name,
+            r.build.as_secs_f64() * 1000.0,
+            r.bytes as f64 / (1024.0 * 1024.0),
+            r.list.as_secs_f64() * 1000.0,
+            r.lookup.as_secs_f64() * 1000.0
+        );
+    }
+}
@@ -1,4 +1,3 @@
-const SEARCH_HOST = "https://internal-searchfile-sendpoint.end2end.tech";
 
 function stripAnsi(s) {
   s = s.replace(/\x1B\[[0-?]*[ -/]*[@-~]/g, "");
@@ -21,6 +20,8 @@ const ui = {
   askInput: document.getElementById("askInput"),
   askBtn: document.getElementById("askBtn"),
   askResults: document.getElementById("askResults"),
+  typesText: document.getElementById("typesText"),
+  askOffline: document.getElementById("askOffline"),
   select: document.getElementById("driveSelect"),
   refreshBtn: document.getElementById("refreshBtn"),
   mountBtn: document.getElementById("mountBtn"),
@@ -31,6 +32,18 @@ const ui = {
   logArea: document.getElementById("logArea"),
   clearLogBtn: document.getElementById("clearLogBtn"),
   hint: document.getElementById("driveHint"),
+  includeLive: document.getElementById("includeLive"),
+  loadSnapBtn: document.getElementById("loadSnapBtn"),
+  saveSnapBtn: document.getElementById("saveSnapBtn"),
+  recoverBtn: document.getElementById("recoverBtn"),
+  recoverFolder: document.getElementById("recoverFolder"),
+  recoverPattern: document.getElementById("recoverPattern"),
+  recoverRangeBtn: document.getElementById("recoverRangeBtn"),
+  recoverConflict: document.getElementById("recoverConflict"),
+  resumeRecoverBtn: document.getElementById("resumeRecoverBtn"),
+  cancelRecoverBtn: document.getElementById("cancelRecoverBtn"),
+  recoverText: document.getElementById("recoverText"),
+  restoreMeta: document.getElementById("restoreMeta"),
 };
 
 function appendLog(line) {
@@ -85,6 +98,7 @@ function stopSoftProgress() {
   if (softTimer) clearInterval(softTimer), (softTimer = null);
 }
 
+// 検索条件の期間は秒単位なので秒までにする
 function fmtIsoLocalFromEpochSec(ts) {
   try {
     const d = new Date(ts * 1000);
@@ -94,18 +108,12 @@ function fmtIsoLocalFromEpochSec(ts) {
     return undefined;
   }
 }
-function extractPathsFromText(text) {
-  const re = /[A-Za-z]:[\\/][^\s"']+/g;
-  const set = new Set();
-  for (const m of text.matchAll(re)) {
-    const p = m[0].replace(/[),.]+$/, "");
-    set.add(p);
-  }
-  return Array.from(set);
-}
+// まとめて復元の対象 (最後に表示した候補)
+let shownPaths = [];
 
 function renderAskResults(prompt, paths, rawText) {
   ui.askResults.innerHTML = "";
+  shownPaths = paths.slice();
 
   const head = document.createElement("div");
   head.className = "rounded-lg border border-slate-800 bg-slate-900/60 p-3";
@@ -136,14 +144,20 @@ function renderAskResults(prompt, paths, rawText) {
         <button class="px-3 py-1.5 rounded bg-sky-600 hover:bg-sky-500" data-act="open">開く</button>
         <button class="px-3 py-1.5 rounded bg-amber-600 hover:bg-amber-500" data-act="copy">デスクトップにコピー</button>
         <button class="px-3 py-1.5 rounded bg-slate-700 hover:bg-slate-600" data-act="reveal">エクスプローラーで表示</button>
+        <button class="px-3 py-1.5 rounded bg-slate-700 hover:bg-slate-600" data-act="recoverDir">フォルダごと復元</button>
       </div>
     `;
     const openBtn = card.querySelector('[data-act="open"]');
     const copyBtn = card.querySelector('[data-act="copy"]');
     const revealBtn = card.querySelector('[data-act="reveal"]');
+    const recoverDirBtn = card.querySelector('[data-act="recoverDir"]');
     openBtn.addEventListener("click", () => openPath(path));
     copyBtn.addEventListener("click", () => copyToDesktop(path));
     revealBtn.addEventListener("click", () => revealInExplorer(path));
+    recoverDirBtn.addEventListener("click", () => {
+      const dir = parentDir(path);
+      recoverSelection({ paths: [dir] }, dir);
+    });
     ui.askResults.appendChild(card);
   }
 }
@@ -162,36 +176,70 @@ async function askGpt() {
   ui.askInput.value = "";
   ui.askInput.blur();
 
+  if (ui.askOffline.checked) {
+    await askLocal(prompt);
+    return;
+  }
+
   appendLog(`gpt-4o-mini < "${prompt}"`);
 
   try {
     const invoke = tauriInvoke();
-    const items = await invoke("build_filelist_cmd", { limit: 50000 });
-
-    const files = items.map((it) => {
-      const obj = { name: it.name, path: it.path, ext: it.ext || "" };
-      if (typeof it.last_opened_ts === "number") obj.last_opened = fmtIsoLocalFromEpochSec(it.last_opened_ts);
-      if (typeof it.last_modified_ts === "number") obj.last_modified = fmtIsoLocalFromEpochSec(it.last_modified_ts);
-      return obj;
-    });
-    const jsonStr = JSON.stringify({ files });
-
-    const form = new FormData();
-    form.append("prompt", prompt);
-    form.append("json_file", new Blob([jsonStr], { type: "application/json" }), "filelist.json");
-
-    const url = `${SEARCH_HOST}/search-file`;
-    const resp = await fetch(url, { method: "POST", body: form });
-    const text = await resp.text();
+    const args = { limit: 50000, utcOffsetMinutes: -new Date().getTimezoneOffset() };
+
+    // 何を送るかを見せて、了承されたときだけ送る
+    const pv = await invoke("preview_remote_search_cmd", args);
+    const samples = pv.samples.map((f) => `  ${f.path}`).join("\n");
+    const redaction = pv.redaction === "off" ? "しない" : `${pv.redacted} 箇所`;
+    const ok = window.confirm(
+      `${pv.upload.files} 件のファイル情報 (${Math.ceil(pv.upload.bytes / 1024)} KB) を送信します。\n` +
+      `送信先: ${pv.upload.endpoint}\n` +
+      `項目: ${pv.upload.fields.join(", ")}\n` +
+      `伏せ字: ${redaction}\n` +
+      (samples ? `例:\n${samples}\n` : "") +
+      `\n送信してよろしいですか？`
+    );
+    if (!ok) {
+      appendLog("gpt-4o-mini: 送信を取り消しました。");
+      return;
+    }
 
-    appendLog(`gpt-4o-mini > ${text}`);
-    const paths = extractPathsFromText(text);
-    renderAskResults(prompt, paths, text);
+    const res = await invoke("remote_search_cmd", { prompt, confirmed: true, ...args });
+    appendLog(`gpt-4o-mini: ${res.upload.files} 件を送信 (${res.upload.fields.join(", ")})`);
+    appendLog(`gpt-4o-mini > ${res.text}`);
+    renderAskResults(prompt, res.paths, res.text);
   } catch (e) {
     appendLog(`gpt-4o-mini: ${String(e)}`);
   }
 }
 
+async function askLocal(prompt) {
+  appendLog(`ローカル検索 < "${prompt}"`);
+  try {
+    const invoke = tauriInvoke();
+    const res = await invoke("local_search_cmd", {
+      prompt,
+      limit: 20,
+      utcOffsetMinutes: -new Date().getTimezoneOffset(),
+    });
+    const it = res.interpretation;
+    const parts = [];
+    if (it.types.length) parts.push(`種類: ${it.types.join(", ")}`);
+    if (typeof it.from === "number") {
+      const to = typeof it.to === "number" ? fmtIsoLocalFromEpochSec(it.to) : "現在";
+      parts.push(`${it.field}: ${fmtIsoLocalFromEpochSec(it.from)} 〜 ${to}`);
+    } else if (it.recent) {
+      parts.push(`${it.field}: 新しい順`);
+    }
+    if (it.keywords.length) parts.push(`キーワード: ${it.keywords.join(" ")}`);
+    const summary = parts.join(" / ") || "条件なし";
+    appendLog(`ローカル検索 > ${summary} (${res.hits.length} 件)`);
+    renderAskResults(prompt, res.hits.map((h) => h.path), summary);
+  } catch (e) {
+    appendLog(`ローカル検索: ${String(e)}`);
+  }
+}
+
 async function openPath(path) {
   try {
     const invoke = tauriInvoke();
@@ -200,10 +248,40 @@ async function openPath(path) {
     appendLog(`開くエラー: ${String(e)}`);
   }
 }
+// 保存先が復元中のドライブと同じなら警告して確かめる
+// 書き込んでよければ allowSameVolume に渡す値を、やめるなら null を返す
+async function confirmDestination(destination) {
+  const invoke = tauriInvoke();
+  let c;
+  try {
+    c = await invoke("check_destination_cmd", { destination });
+  } catch (e) {
+    const ok = window.confirm(`保存先のドライブを確認できませんでした (${String(e)})。\n続けてよろしいですか？`);
+    return ok ? true : null;
+  }
+  if (!c.same_volume) return false;
+  const others = c.suggestions.length
+    ? `別のドライブを選んでください: ${c.suggestions.map((s) => `${s.root} (空き ${fmtBytes(s.free_bytes)})`).join(", ")}`
+    : "他に書き込めるドライブが見つかりません。USBメモリや外付けディスクをつないでください。";
+  const ok = window.confirm(
+    `保存先 ${c.destination} は復元中のドライブ (${c.source}) と同じです。\n` +
+    `書き込むと、まだ復元していない削除済みファイルが上書きされ、戻せなくなることがあります。\n` +
+    `${others}\n\nそれでも書き込みますか？`
+  );
+  if (!ok) appendLog(`書き込みを取り消しました: 保存先が ${c.source} と同じドライブです`);
+  return ok ? true : null;
+}
+
 async function copyToDesktop(path) {
   try {
+    const allowSameVolume = await confirmDestination(null);
+    if (allowSameVolume === null) return;
     const invoke = tauriInvoke();
-    const dest = await invoke("copy_to_desktop_cmd", { path });
+    const dest = await invoke("copy_to_desktop_cmd", {
+      path,
+      allowSameVolume,
+      restoreMetadata: ui.restoreMeta.checked,
+    });
     appendLog(`コピー完了: ${dest}`);
   } catch (e) {
     appendLog(`コピーに失敗しました: ${String(e)}`);
@@ -218,6 +296,118 @@ async function revealInExplorer(path) {
   }
 }
 
+function setRecovering(on) {
+  ui.recoverBtn.disabled = on;
+  ui.recoverRangeBtn.disabled = on;
+  ui.resumeRecoverBtn.disabled = on;
+  ui.cancelRecoverBtn.classList.toggle("hidden", !on);
+}
+
+// R:\a\b.txt -> R:\a (ルート直下なら R:\)
+function parentDir(path) {
+  const i = path.lastIndexOf("\\");
+  return i <= 2 ? "R:\\" : path.slice(0, i);
+}
+
+async function recoverShown() {
+  if (shownPaths.length === 0) {
+    appendLog("復元: 候補が表示されていません。");
+    return;
+  }
+  await recoverSelection({ paths: shownPaths }, `表示中の候補 ${shownPaths.length} 件`);
+}
+
+// フォルダ (空ならドライブ全体) 以下を、名前のパターンがあれば一致するファイルだけ復元する
+async function recoverRange() {
+  let folder = (ui.recoverFolder.value || "").trim();
+  const pattern = (ui.recoverPattern.value || "").trim();
+  if (!folder) folder = "R:\\";
+  if (!pattern) {
+    await recoverSelection({ paths: [folder] }, folder);
+    return;
+  }
+  const query = { name_glob: pattern, path_prefix: folder };
+  await recoverSelection({ query }, `${folder} 以下の ${pattern}`);
+}
+
+async function recoverSelection(selection, what) {
+  appendLog(`復元: ${sanitizeLog(what)}`);
+  try {
+    const destination = await window.__TAURI__.dialog.open({ directory: true });
+    if (!destination) return;
+    const allowSameVolume = await confirmDestination(destination);
+    if (allowSameVolume === null) return;
+    const invoke = tauriInvoke();
+    const n = await invoke("start_recovery_cmd", {
+      selection,
+      destination,
+      conflict: ui.recoverConflict.value,
+      allowSameVolume,
+      restoreMetadata: ui.restoreMeta.checked,
+    });
+    setRecovering(true);
+    appendLog(`復元開始: ${n} 件 -> ${destination}`);
+  } catch (e) {
+    appendLog(`復元エラー: ${String(e)}`);
+  }
+}
+
+// 中止・中断した復元を同じ保存先で続ける
+async function resumeRecovery() {
+  try {
+    const destination = await window.__TAURI__.dialog.open({ directory: true });
+    if (!destination) return;
+    const allowSameVolume = await confirmDestination(destination);
+    if (allowSameVolume === null) return;
+    const invoke = tauriInvoke();
+    const n = await invoke("resume_recovery_cmd", { destination, allowSameVolume });
+    setRecovering(true);
+    appendLog(`復元を再開: ${n} 件 -> ${destination}`);
+  } catch (e) {
+    appendLog(`復元エラー: ${String(e)}`);
+  }
+}
+
+async function cancelRecovery() {
+  try {
+    const invoke = tauriInvoke();
+    await invoke("cancel_recovery_cmd");
+  } catch (e) {
+    appendLog(`cancel_recovery_cmd エラー: ${String(e)}`);
+  }
+}
+
+function fmtBytes(n) {
+  if (n >= 1024 * 1024 * 1024) return `${(n / 1024 / 1024 / 1024).toFixed(1)} GB`;
+  if (n >= 1024 * 1024) return `${(n / 1024 / 1024).toFixed(1)} MB`;
+  return `${Math.ceil(n / 1024)} KB`;
+}
+
+function onRecoverEvent(p) {
+  if (p.event === "started") {
+    ui.recoverText.textContent = `0 / ${p.total} 件 (${fmtBytes(p.bytes)})`;
+    if (p.already_done > 0) appendLog(`復元: ${p.already_done} 件は前回までに完了しています`);
+  } else if (p.event === "file") {
+    ui.recoverText.textContent =
+      `${p.index + 1} / ${p.total} 件 | ${fmtBytes(p.done_bytes)} / ${fmtBytes(p.total_bytes)} | ${sanitizeLog(p.dest)}`;
+    if (p.skipped) appendLog(`復元: 同名のため飛ばしました: ${p.dest}`);
+  } else if (p.event === "failed") {
+    appendLog(`復元失敗: ${p.source}: ${sanitizeLog(p.error)}`);
+  } else if (p.event === "finished") {
+    setRecovering(false);
+    const msg = `${p.cancelled ? "復元を中止しました" : "復元完了"}: ` +
+      `${p.copied} 件コピー, ${p.skipped} 件スキップ, ${p.failed} 件失敗 -> ${p.destination}`;
+    ui.recoverText.textContent = msg;
+    appendLog(msg);
+    if (p.manifest) appendLog(`復元の記録 (SHA-256 / MD5 付き): ${p.manifest} (同じ場所に .csv も)`);
+    if (p.cancelled || p.failed > 0) appendLog("復元: 「中断した復元の続き」で同じ保存先を選ぶと続きから再開できます");
+  } else if (p.event === "error") {
+    setRecovering(false);
+    ui.recoverText.textContent = "";
+    appendLog(`復元エラー: ${sanitizeLog(p.error)}`);
+  }
+}
+
 async function loadDrives() {
   ui.select.innerHTML = `<option value="" disabled selected>読み込み中...</option>`;
   ui.mountBtn.disabled = true;
@@ -256,7 +446,7 @@ async function mountSelected() {
   appendLog(`マウント開始: ${letter}: `);
   try {
     const invoke = tauriInvoke();
-    await invoke("start_mount_cmd", { letter });
+    await invoke("start_mount_cmd", { letter, includeLive: ui.includeLive.checked });
   } catch (e) {
     appendLog(`start_mount_cmd エラー: ${String(e)} `);
     stopSoftProgress();
@@ -265,6 +455,39 @@ async function mountSelected() {
   }
 }
 
+const SNAPSHOT_FILTERS = [{ name: "RecoveryMagic スナップショット", extensions: ["rmsnap"] }];
+
+async function saveSnapshot() {
+  try {
+    const path = await window.__TAURI__.dialog.save({ filters: SNAPSHOT_FILTERS });
+    if (!path) return;
+    const allowSameVolume = await confirmDestination(path);
+    if (allowSameVolume === null) return;
+    const invoke = tauriInvoke();
+    await invoke("save_snapshot_cmd", { path, allowSameVolume });
+    appendLog(`スキャン結果を保存しました: ${path}`);
+  } catch (e) {
+    appendLog(`save_snapshot_cmd エラー: ${String(e)} `);
+  }
+}
+
+// 保存時と同じボリュームなら、スキャンせずにマウントする
+async function loadSnapshot() {
+  try {
+    const path = await window.__TAURI__.dialog.open({ filters: SNAPSHOT_FILTERS });
+    if (!path) return;
+    ui.mountBtn.disabled = true;
+    ui.refreshBtn.disabled = true;
+    appendLog(`保存した結果を開く: ${path}`);
+    const invoke = tauriInvoke();
+    await invoke("load_snapshot_cmd", { path });
+  } catch (e) {
+    appendLog(`load_snapshot_cmd エラー: ${String(e)} `);
+    ui.mountBtn.disabled = false;
+    ui.refreshBtn.disabled = false;
+  }
+}
+
 async function eject() {
   try {
     const invoke = tauriInvoke();
@@ -287,13 +510,24 @@ async function wireEvents() {
     }
   });
 
+  // スナップショットを保存した後に、データのクラスタが使われたファイル
+  await listen("snapshot", (ev) => {
+    const p = ev?.payload || {};
+    if (!p.stale_count) return;
+    appendLog(`保存後に ${p.stale_count} 件のファイルのクラスタが使われています (開くことも復元することもできません):`);
+    for (const path of p.stale || []) appendLog(`  ${sanitizeLog(path)}`);
+    if (p.stale_count > (p.stale || []).length) appendLog(`  ... ほか ${p.stale_count - p.stale.length} 件`);
+  });
+
   await listen("state", (ev) => {
     const st = ev?.payload?.state;
     if (st === "mounted") {
       stopSoftProgress();
       setProgress(100, "マウント完了");
       ui.ejectBtn.classList.remove("hidden");
+      ui.saveSnapBtn.classList.remove("hidden");
       ui.mountBtn.classList.add("hidden");
+      ui.loadSnapBtn.classList.add("hidden");
       ui.progSection.classList.add("hidden");
       ui.askSection.classList.remove("hidden");
       ui.askBtn.disabled = false;
@@ -303,7 +537,9 @@ async function wireEvents() {
       stopSoftProgress();
       setProgress(0, "");
       ui.ejectBtn.classList.add("hidden");
+      ui.saveSnapBtn.classList.add("hidden");
       ui.mountBtn.classList.remove("hidden");
+      ui.loadSnapBtn.classList.remove("hidden");
       ui.progText.textContent = "待機中";
       ui.progSection.classList.remove("hidden");
       ui.askSection.classList.add("hidden");
@@ -311,6 +547,8 @@ async function wireEvents() {
       ui.askInput.disabled = true;
       ui.askInput.value = "";
       ui.askResults.innerHTML = "";
+      ui.typesText.textContent = "";
+      shownPaths = [];
       appendLog(`取り出し完了: R: \\ をアンマウントしました`);
       loadDrives();
       ui.refreshBtn.disabled = false;
@@ -325,6 +563,20 @@ async function wireEvents() {
     }
   });
 
+  await listen("recover", (ev) => onRecoverEvent(ev?.payload || {}));
+
+  // マウントした後の、中身からの種類判定 (終わると R:\$ByType などができる)
+  await listen("content_types", (ev) => {
+    const p = ev?.payload || {};
+    if (!p.done) {
+      ui.typesText.textContent = `ファイルの種類を判定しています: ${p.checked} / ${p.total} 件 (${p.found} 件判定)`;
+      return;
+    }
+    ui.typesText.textContent = "";
+    if (p.cancelled) appendLog(`種類の判定を中止しました (${p.checked} / ${p.total} 件)`);
+    else appendLog(`種類の判定が終わりました: ${p.found} 件 (R:\\$ByType などに分類)`);
+  });
+
   await listen("log", (ev) => {
     const payload = ev?.payload;
     if (typeof payload === "string") appendLog(payload);
@@ -344,11 +596,17 @@ async function startApp() {
   ui.refreshBtn.addEventListener("click", loadDrives);
   ui.mountBtn.addEventListener("click", mountSelected);
   ui.ejectBtn.addEventListener("click", eject);
+  ui.saveSnapBtn.addEventListener("click", saveSnapshot);
+  ui.loadSnapBtn.addEventListener("click", loadSnapshot);
   ui.clearLogBtn.addEventListener("click", () => (ui.logArea.value = ""));
   ui.askBtn.addEventListener("click", askGpt);
   ui.askInput.addEventListener("keydown", (e) => {
     if (e.key === "Enter") askGpt();
   });
+  ui.recoverBtn.addEventListener("click", recoverShown);
+  ui.recoverRangeBtn.addEventListener("click", recoverRange);
+  ui.resumeRecoverBtn.addEventListener("click", resumeRecovery);
+  ui.cancelRecoverBtn.addEventListener("click", cancelRecovery);
 
   try { await wireEvents(); }
   catch (e) { appendLog(`イベントエラー: ${String(e)} `); }
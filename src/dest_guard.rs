@@ -0,0 +1,94 @@
+use crate::drives::{enum_writable_drives, source_volume, volume_of};
+use crate::scan::ScanOptions;
+use crate::spill::SpillBudget;
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::path::Path;
+use tracing::warn;
+
+// 復元したデータをスキャン中のボリュームに書くと、まだ残っている削除済みファイルの
+// 空きクラスタを上書きしてしまうので、書き出す前に保存先のボリュームを確かめる
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DestinationCheck {
+    // 保存先とスキャン元のボリュームのルート (C:\ など)
+    pub destination: String,
+    pub source: String,
+    pub same_volume: bool,
+    // 別のボリュームの候補 (空きの多い順)
+    pub suggestions: Vec<Suggestion>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Suggestion {
+    pub root: String,
+    pub free_bytes: u64,
+}
+
+pub fn check(device: &str, dest: &Path) -> Result<DestinationCheck> {
+    let source = source_volume(device)?;
+    let target = volume_of(dest)?;
+    let same_volume = source.same(&target);
+    let mut suggestions: Vec<Suggestion> = Vec::new();
+    if same_volume {
+        for d in enum_writable_drives() {
+            let root = format!("{}:\\", d.letter);
+            match volume_of(Path::new(&root)) {
+                Ok(v) if !v.same(&source) && d.free_bytes > 0 => suggestions.push(Suggestion {
+                    root,
+                    free_bytes: d.free_bytes,
+                }),
+                _ => {}
+            }
+        }
+        suggestions.sort_by_key(|s| std::cmp::Reverse(s.free_bytes));
+    }
+    Ok(DestinationCheck {
+        destination: target.root,
+        source: source.root,
+        same_volume,
+        suggestions,
+    })
+}
+
+// 同じボリュームなら断る。allow_same_volume で明示的に許したときだけ書く
+pub fn ensure(device: &str, dest: &Path, allow_same_volume: bool) -> Result<()> {
+    if allow_same_volume {
+        warn!(device = %device, dest = %dest.display(), "writing without the same-volume check");
+        return Ok(());
+    }
+    let c = check(device, dest)?;
+    if c.same_volume {
+        let others = if c.suggestions.is_empty() {
+            "no other drive is available".to_string()
+        } else {
+            let roots: Vec<&str> = c.suggestions.iter().map(|s| s.root.as_str()).collect();
+            format!("try {}", roots.join(", "))
+        };
+        bail!(
+            "{} is on the volume being recovered ({}); writing there can overwrite deleted data ({})",
+            dest.display(),
+            c.source,
+            others
+        );
+    }
+    Ok(())
+}
+
+// インデックスが予算を超えた分の書き出し先 (UNUNLINK_SPILL_DIR、既定は一時フォルダ)
+// スキャンするボリューム上なら書き出さない (予算を超えてもメモリに置いたまま)
+pub fn spill_budget(device: &str, opts: &ScanOptions) -> Option<SpillBudget> {
+    let bytes = opts.metadata_budget?;
+    let dir = opts.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+    let dir = match ensure(device, &dir, false) {
+        Ok(()) => Some(dir),
+        Err(e) => {
+            warn!(
+                error = %format!("{:#}", e),
+                "the index will not be spilled; set UNUNLINK_SPILL_DIR to a folder on another drive"
+            );
+            None
+        }
+    };
+    Some(SpillBudget { bytes, dir })
+}
@@ -1,8 +1,13 @@
+use anyhow::{bail, Result};
 use serde::Serialize;
+use std::os::windows::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr::null_mut;
 use widestring::U16CStr;
 use winapi::um::fileapi::GetDriveTypeW;
 use winapi::um::fileapi::GetLogicalDrives;
 use winapi::um::fileapi::{GetDiskFreeSpaceExW, GetVolumeInformationW};
+use winapi::um::fileapi::{GetVolumeNameForVolumeMountPointW, GetVolumePathNameW};
 use winapi::um::winbase::{DRIVE_FIXED, DRIVE_REMOVABLE};
 use winapi::um::winnt::WCHAR;
 
@@ -17,6 +22,15 @@ pub struct SourceDrive {
 // ドライブを取得する関数
 // NTFSのみに絞り込む
 pub fn enum_ntfs_drives() -> Vec<SourceDrive> {
+    enum_drives(true)
+}
+
+// 書き出し先の候補 (ファイルシステムは問わない)
+pub fn enum_writable_drives() -> Vec<SourceDrive> {
+    enum_drives(false)
+}
+
+fn enum_drives(ntfs_only: bool) -> Vec<SourceDrive> {
     let mut out = Vec::<SourceDrive>::new();
     unsafe {
         let mask = GetLogicalDrives();
@@ -54,7 +68,7 @@ pub fn enum_ntfs_drives() -> Vec<SourceDrive> {
             let fs_name = U16CStr::from_ptr_str(fs_name_buf.as_ptr())
                 .to_string_lossy()
                 .to_string();
-            if fs_name.to_uppercase() != "NTFS" {
+            if ntfs_only && fs_name.to_uppercase() != "NTFS" {
                 continue;
             }
 
@@ -81,3 +95,96 @@ pub fn enum_ntfs_drives() -> Vec<SourceDrive> {
     }
     out
 }
+
+// ボリュームの識別 (GUID名が取れればそれで、無ければシリアル番号で比べる)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeId {
+    // C:\ やマウント先のフォルダ
+    pub root: String,
+    pub guid: Option<String>,
+    pub serial: u32,
+}
+
+impl VolumeId {
+    pub fn same(&self, other: &VolumeId) -> bool {
+        match (&self.guid, &other.guid) {
+            (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+            _ => self.serial == other.serial,
+        }
+    }
+}
+
+// path を置いている (置くことになる) ボリューム
+// まだ無いフォルダは、存在する一番近い親で調べる
+pub fn volume_of(path: &Path) -> Result<VolumeId> {
+    let existing = path.ancestors().find(|p| p.exists()).unwrap_or(path);
+    let wide: Vec<u16> = existing
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    let mut root: [WCHAR; 1024] = [0; 1024];
+    let ok = unsafe { GetVolumePathNameW(wide.as_ptr(), root.as_mut_ptr(), root.len() as u32) };
+    if ok == 0 {
+        bail!(
+            "GetVolumePathNameW failed for {}: {}",
+            existing.display(),
+            std::io::Error::last_os_error()
+        );
+    }
+    let root = unsafe { U16CStr::from_ptr_str(root.as_ptr()) }.to_string_lossy();
+    volume_at(&root)
+}
+
+// スキャンしているデバイスのボリューム (イメージファイルならそれを置いているボリューム)
+pub fn source_volume(device: &str) -> Result<VolumeId> {
+    let rest = device
+        .strip_prefix(r"\\.\")
+        .or_else(|| device.strip_prefix(r"\\?\"));
+    match rest {
+        Some(r) if r.len() == 2 && r.ends_with(':') => volume_at(&format!("{}\\", r)),
+        Some(r)
+            if r.get(..7)
+                .is_some_and(|p| p.eq_ignore_ascii_case("volume{")) =>
+        {
+            volume_at(&format!(r"\\?\{}\", r.trim_end_matches('\\')))
+        }
+        _ if Path::new(device).is_file() => volume_of(Path::new(device)),
+        _ => bail!("cannot tell which volume {} is on", device),
+    }
+}
+
+// root は \ で終わるボリュームのルート
+fn volume_at(root: &str) -> Result<VolumeId> {
+    let root_w: Vec<u16> = root.encode_utf16().chain(std::iter::once(0)).collect();
+    let mut serial: u32 = 0;
+    let ok = unsafe {
+        GetVolumeInformationW(
+            root_w.as_ptr(),
+            null_mut(),
+            0,
+            &mut serial,
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            0,
+        )
+    };
+    if ok == 0 {
+        bail!(
+            "GetVolumeInformationW failed for {}: {}",
+            root,
+            std::io::Error::last_os_error()
+        );
+    }
+    let mut guid: [WCHAR; 64] = [0; 64];
+    let ok = unsafe {
+        GetVolumeNameForVolumeMountPointW(root_w.as_ptr(), guid.as_mut_ptr(), guid.len() as u32)
+    };
+    let guid = (ok != 0).then(|| unsafe { U16CStr::from_ptr_str(guid.as_ptr()) }.to_string_lossy());
+    Ok(VolumeId {
+        root: root.to_string(),
+        guid,
+        serial,
+    })
+}
@@ -0,0 +1,140 @@
+use crate::indexer::{EntryMeta, EntryOrDir};
+use crate::timestamps::filetime_to_system_time;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, FileTimes};
+use std::path::Path;
+
+// 書き出したコピーに元の日時と属性を戻す (そのままだとコピーした時刻になってしまう)
+// 作成日時と隠し/システム属性は Windows だけ。Linux では更新/アクセス日時と読み取り専用を戻す
+
+const FILE_ATTRIBUTE_READONLY: u32 = 0x0001;
+const FILE_ATTRIBUTE_HIDDEN: u32 = 0x0002;
+const FILE_ATTRIBUTE_SYSTEM: u32 = 0x0004;
+const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x0020;
+#[cfg(windows)]
+const FILE_ATTRIBUTE_NORMAL: u32 = 0x0080;
+const RESTORED_ATTRIBUTES: u32 = FILE_ATTRIBUTE_READONLY
+    | FILE_ATTRIBUTE_HIDDEN
+    | FILE_ATTRIBUTE_SYSTEM
+    | FILE_ATTRIBUTE_ARCHIVE;
+
+// 時刻は FILETIME (0 は不明)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OriginalMeta {
+    pub created: u64,
+    pub modified: u64,
+    pub accessed: u64,
+    pub attributes: u32,
+}
+
+impl OriginalMeta {
+    pub fn from_meta(m: &EntryMeta) -> Option<Self> {
+        let t = m.times.primary()?;
+        Some(Self {
+            created: t.created,
+            modified: t.modified,
+            accessed: t.accessed,
+            attributes: m.attributes,
+        })
+    }
+
+    pub fn from_entry(e: &EntryOrDir) -> Option<Self> {
+        match e {
+            EntryOrDir::File(m) => Self::from_meta(m),
+            EntryOrDir::Dir(m) => m.as_deref().and_then(Self::from_meta),
+        }
+    }
+}
+
+// UNUNLINK_RESTORE_META=0 で既定を「戻さない」にする
+pub fn enabled_by_default() -> bool {
+    !matches!(
+        std::env::var("UNUNLINK_RESTORE_META")
+            .unwrap_or_default()
+            .trim(),
+        "0" | "false" | "off"
+    )
+}
+
+// 日時を先に戻す (読み取り専用にした後だと開けないことがある)
+pub fn apply(path: &Path, meta: &OriginalMeta) -> Result<()> {
+    let mut times = FileTimes::new();
+    if let Some(t) = filetime_to_system_time(meta.modified) {
+        times = times.set_modified(t);
+    }
+    if let Some(t) = filetime_to_system_time(meta.accessed) {
+        times = times.set_accessed(t);
+    }
+    #[cfg(windows)]
+    if let Some(t) = filetime_to_system_time(meta.created) {
+        use std::os::windows::fs::FileTimesExt;
+        times = times.set_created(t);
+    }
+    open_for_times(path)
+        .and_then(|f| f.set_times(times))
+        .with_context(|| format!("set times on {}", path.display()))?;
+    set_attributes(path, meta.attributes & RESTORED_ATTRIBUTES)
+        .with_context(|| format!("set attributes on {}", path.display()))
+}
+
+// フォルダも開けるように FILE_FLAG_BACKUP_SEMANTICS を付ける
+#[cfg(windows)]
+fn open_for_times(path: &Path) -> std::io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    use winapi::um::winbase::FILE_FLAG_BACKUP_SEMANTICS;
+    use winapi::um::winnt::FILE_WRITE_ATTRIBUTES;
+    File::options()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+}
+
+#[cfg(not(windows))]
+fn open_for_times(path: &Path) -> std::io::Result<File> {
+    File::open(path)
+}
+
+#[cfg(windows)]
+fn set_attributes(path: &Path, attributes: u32) -> std::io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use winapi::um::fileapi::SetFileAttributesW;
+    let wide: Vec<u16> = path
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    let attributes = if attributes == 0 {
+        FILE_ATTRIBUTE_NORMAL
+    } else {
+        attributes
+    };
+    if unsafe { SetFileAttributesW(wide.as_ptr(), attributes) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// 読み取り専用だけ (フォルダを読み取り専用にすると中に書けなくなるのでファイルのみ)
+#[cfg(not(windows))]
+fn set_attributes(path: &Path, attributes: u32) -> std::io::Result<()> {
+    let md = std::fs::metadata(path)?;
+    if attributes & FILE_ATTRIBUTE_READONLY == 0 || md.is_dir() {
+        return Ok(());
+    }
+    let mut perm = md.permissions();
+    perm.set_readonly(true);
+    std::fs::set_permissions(path, perm)
+}
+
+// 上書きする前に読み取り専用を外す
+pub fn clear_readonly(path: &Path) -> Result<()> {
+    let mut perm = std::fs::metadata(path)?.permissions();
+    if perm.readonly() {
+        #[allow(clippy::permissions_set_readonly_false)]
+        perm.set_readonly(false);
+        std::fs::set_permissions(path, perm)
+            .with_context(|| format!("clear read-only on {}", path.display()))?;
+    }
+    Ok(())
+}
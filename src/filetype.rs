@@ -0,0 +1,307 @@
+use serde::{Deserialize, Serialize};
+
+// ファイルの種類 (仮想フォルダや絞り込みで使う大まかな分類)
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFamily {
+    Image,
+    Video,
+    Audio,
+    Document,
+    Archive,
+    Executable,
+    Text,
+    Other,
+}
+
+impl FileFamily {
+    // \$ByType 以下のフォルダ名
+    pub fn folder_name(self) -> &'static str {
+        match self {
+            FileFamily::Image => "Images",
+            FileFamily::Video => "Videos",
+            FileFamily::Audio => "Audio",
+            FileFamily::Document => "Documents",
+            FileFamily::Archive => "Archives",
+            FileFamily::Executable => "Programs",
+            FileFamily::Text => "Text",
+            FileFamily::Other => "Other",
+        }
+    }
+
+    // 拡張子から推測する (大文字小文字は区別しない)
+    pub fn from_name(name: &[u16]) -> Self {
+        let Some(dot) = name.iter().rposition(|&c| c == b'.' as u16) else {
+            return FileFamily::Other;
+        };
+        let ext = String::from_utf16_lossy(&name[dot + 1..]).to_ascii_lowercase();
+        match ext.as_str() {
+            "jpg" | "jpeg" | "png" | "gif" | "bmp" | "tif" | "tiff" | "webp" | "heic" | "heif"
+            | "ico" | "raw" | "cr2" | "nef" | "arw" | "dng" | "svg" | "psd" => FileFamily::Image,
+            "mp4" | "mov" | "avi" | "mkv" | "wmv" | "flv" | "webm" | "m4v" | "mpg" | "mpeg"
+            | "3gp" | "mts" | "m2ts" => FileFamily::Video,
+            "mp3" | "wav" | "flac" | "aac" | "m4a" | "ogg" | "wma" | "opus" | "aiff" | "mid" => {
+                FileFamily::Audio
+            }
+            "pdf" | "doc" | "docx" | "xls" | "xlsx" | "ppt" | "pptx" | "odt" | "ods" | "odp"
+            | "rtf" | "one" | "pages" | "numbers" | "key" | "epub" => FileFamily::Document,
+            "zip" | "rar" | "7z" | "gz" | "tgz" | "bz2" | "xz" | "tar" | "cab" | "iso" | "lzh" => {
+                FileFamily::Archive
+            }
+            "exe" | "dll" | "msi" | "sys" | "com" | "scr" | "bat" | "cmd" | "ps1" => {
+                FileFamily::Executable
+            }
+            "txt" | "csv" | "log" | "md" | "json" | "xml" | "html" | "htm" | "ini" | "yaml"
+            | "yml" | "tsv" => FileFamily::Text,
+            _ => FileFamily::Other,
+        }
+    }
+}
+
+// 中身から判定した種類 (KINDS の添字)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentType(u8);
+
+const KINDS: &[(&str, FileFamily)] = &[
+    ("image/jpeg", FileFamily::Image),
+    ("image/png", FileFamily::Image),
+    ("image/gif", FileFamily::Image),
+    ("image/bmp", FileFamily::Image),
+    ("image/tiff", FileFamily::Image),
+    ("image/webp", FileFamily::Image),
+    ("image/heic", FileFamily::Image),
+    ("image/vnd.adobe.photoshop", FileFamily::Image),
+    ("image/x-icon", FileFamily::Image),
+    ("video/mp4", FileFamily::Video),
+    ("video/quicktime", FileFamily::Video),
+    ("video/x-msvideo", FileFamily::Video),
+    ("video/x-matroska", FileFamily::Video),
+    ("video/x-ms-asf", FileFamily::Video),
+    ("video/3gpp", FileFamily::Video),
+    ("audio/mpeg", FileFamily::Audio),
+    ("audio/wav", FileFamily::Audio),
+    ("audio/flac", FileFamily::Audio),
+    ("audio/ogg", FileFamily::Audio),
+    ("audio/mp4", FileFamily::Audio),
+    ("audio/midi", FileFamily::Audio),
+    ("application/pdf", FileFamily::Document),
+    (
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        FileFamily::Document,
+    ),
+    (
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        FileFamily::Document,
+    ),
+    (
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        FileFamily::Document,
+    ),
+    (
+        "application/vnd.oasis.opendocument.text",
+        FileFamily::Document,
+    ),
+    (
+        "application/vnd.oasis.opendocument.spreadsheet",
+        FileFamily::Document,
+    ),
+    (
+        "application/vnd.oasis.opendocument.presentation",
+        FileFamily::Document,
+    ),
+    ("application/epub+zip", FileFamily::Document),
+    // 古い Office (doc/xls/ppt) などの複合ドキュメント
+    ("application/x-ole-storage", FileFamily::Document),
+    ("application/rtf", FileFamily::Document),
+    ("application/zip", FileFamily::Archive),
+    ("application/x-7z-compressed", FileFamily::Archive),
+    ("application/vnd.rar", FileFamily::Archive),
+    ("application/gzip", FileFamily::Archive),
+    ("application/x-bzip2", FileFamily::Archive),
+    ("application/x-xz", FileFamily::Archive),
+    (
+        "application/vnd.microsoft.portable-executable",
+        FileFamily::Executable,
+    ),
+    ("application/vnd.sqlite3", FileFamily::Other),
+    ("application/xml", FileFamily::Text),
+    ("text/html", FileFamily::Text),
+    ("text/plain", FileFamily::Text),
+];
+
+impl ContentType {
+    fn of(mime: &str) -> Option<Self> {
+        KINDS
+            .iter()
+            .position(|(m, _)| *m == mime)
+            .map(|i| ContentType(i as u8))
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        ((id as usize) < KINDS.len()).then_some(ContentType(id))
+    }
+
+    pub fn id(self) -> u8 {
+        self.0
+    }
+
+    pub fn mime(self) -> &'static str {
+        KINDS[self.0 as usize].0
+    }
+
+    pub fn family(self) -> FileFamily {
+        KINDS[self.0 as usize].1
+    }
+}
+
+// 判定に使う先頭のバイト数
+pub const SNIFF_BYTES: usize = 4096;
+
+// 先頭のバイト列 (マジックナンバーと簡単な構造) から種類を判定する
+pub fn sniff(head: &[u8]) -> Option<ContentType> {
+    ContentType::of(sniff_mime(head)?)
+}
+
+fn sniff_mime(h: &[u8]) -> Option<&'static str> {
+    let at = |off: usize, sig: &[u8]| h.get(off..off + sig.len()) == Some(sig);
+    let mime = if at(0, b"\xFF\xD8\xFF") {
+        "image/jpeg"
+    } else if at(0, b"\x89PNG\r\n\x1A\n") {
+        "image/png"
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        "image/gif"
+    } else if at(0, b"BM") && h.len() >= 26 && at(6, b"\0\0\0\0") {
+        "image/bmp"
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        "image/tiff"
+    } else if at(0, b"8BPS") {
+        "image/vnd.adobe.photoshop"
+    } else if at(0, b"\0\0\x01\0") && h.len() >= 6 && h[4] > 0 {
+        "image/x-icon"
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        "image/webp"
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        "audio/wav"
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        "video/x-msvideo"
+    } else if at(4, b"ftyp") {
+        sniff_ftyp(h.get(8..12)?)
+    } else if at(0, b"\x1A\x45\xDF\xA3") {
+        "video/x-matroska"
+    } else if at(0, b"\x30\x26\xB2\x75\x8E\x66\xCF\x11") {
+        "video/x-ms-asf"
+    } else if at(0, b"ID3") || (h.len() >= 2 && h[0] == 0xFF && h[1] & 0xE6 > 0xE0) {
+        "audio/mpeg"
+    } else if at(0, b"fLaC") {
+        "audio/flac"
+    } else if at(0, b"OggS") {
+        "audio/ogg"
+    } else if at(0, b"MThd") {
+        "audio/midi"
+    } else if at(0, b"%PDF-") {
+        "application/pdf"
+    } else if at(0, b"PK\x03\x04") {
+        sniff_zip(h)
+    } else if at(0, b"\xD0\xCF\x11\xE0\xA1\xB1\x1A\xE1") {
+        "application/x-ole-storage"
+    } else if at(0, b"{\\rtf") {
+        "application/rtf"
+    } else if at(0, b"7z\xBC\xAF\x27\x1C") {
+        "application/x-7z-compressed"
+    } else if at(0, b"Rar!\x1A\x07") {
+        "application/vnd.rar"
+    } else if at(0, b"\x1F\x8B") {
+        "application/gzip"
+    } else if at(0, b"BZh") {
+        "application/x-bzip2"
+    } else if at(0, b"\xFD7zXZ\0") {
+        "application/x-xz"
+    } else if at(0, b"MZ") {
+        "application/vnd.microsoft.portable-executable"
+    } else if at(0, b"SQLite format 3\0") {
+        "application/vnd.sqlite3"
+    } else {
+        return sniff_text(h);
+    };
+    Some(mime)
+}
+
+// ISO BMFF (mp4/mov/heic 等) の major brand
+fn sniff_ftyp(brand: &[u8]) -> &'static str {
+    match brand {
+        b"qt  " => "video/quicktime",
+        b"M4A " | b"M4B " => "audio/mp4",
+        b"heic" | b"heix" | b"mif1" | b"msf1" | b"heim" | b"heis" => "image/heic",
+        b if b.starts_with(b"3g") => "video/3gpp",
+        _ => "video/mp4",
+    }
+}
+
+// ZIP の中身から Office/ODF/EPUB を見分ける
+fn sniff_zip(h: &[u8]) -> &'static str {
+    // ODF と EPUB は先頭のエントリが無圧縮の "mimetype"
+    if h.get(30..38) == Some(b"mimetype") {
+        let rest = &h[38..std::cmp::min(h.len(), 38 + 80)];
+        for mime in [
+            "application/vnd.oasis.opendocument.text",
+            "application/vnd.oasis.opendocument.spreadsheet",
+            "application/vnd.oasis.opendocument.presentation",
+            "application/epub+zip",
+        ] {
+            if rest.starts_with(mime.as_bytes()) {
+                return mime;
+            }
+        }
+    }
+    // OOXML はエントリ名に word/ xl/ ppt/ が現れる
+    let has = |name: &[u8]| h.windows(name.len()).any(|w| w == name);
+    if has(b"[Content_Types].xml") || has(b"_rels/.rels") {
+        if has(b"word/") {
+            return "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+        }
+        if has(b"xl/") {
+            return "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+        }
+        if has(b"ppt/") {
+            return "application/vnd.openxmlformats-officedocument.presentationml.presentation";
+        }
+    }
+    "application/zip"
+}
+
+// 制御文字をほとんど含まない UTF-8 / BOM 付き UTF-16 ならテキスト
+fn sniff_text(h: &[u8]) -> Option<&'static str> {
+    if h.is_empty() {
+        return None;
+    }
+    if h.starts_with(b"\xFF\xFE") || h.starts_with(b"\xFE\xFF") {
+        return Some("text/plain");
+    }
+    let body = h.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(h);
+    // 末尾で文字が途切れている分は許す
+    let valid = match std::str::from_utf8(body) {
+        Ok(s) => s,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&body[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    if valid
+        .bytes()
+        .any(|b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0C))
+    {
+        return None;
+    }
+    let start = valid.trim_start().as_bytes();
+    let lower: Vec<u8> = start
+        .iter()
+        .take(15)
+        .map(|b| b.to_ascii_lowercase())
+        .collect();
+    Some(if lower.starts_with(b"<?xml") {
+        "application/xml"
+    } else if lower.starts_with(b"<!doctype html") || lower.starts_with(b"<html") {
+        "text/html"
+    } else {
+        "text/plain"
+    })
+}
@@ -1,23 +1,24 @@
-use crate::indexer::{DeletedIndex, EntryMeta, EntryOrDir, path_key_lc_from_u16};
-use crate::util::normalize_and_canonicalize_for_key;
+use crate::indexer::{DeletedIndex, EntryMeta, EntryOrDir, NodeId, ROOT_NODE};
+use crate::mft_stream::RecordSource;
+use crate::timestamps::filetime_to_system_time;
 use dokan::{
     CreateFileInfo, DiskSpaceInfo, FileInfo as DokanFileInfo, FileSystemHandler, FileTimeOperation,
     FillDataError, FillDataResult, FindData, OperationInfo, OperationResult, VolumeInfo,
 };
 use ntfs_reader::{api::NtfsAttributeType, mft::Mft, volume::Volume};
 use parking_lot::RwLock;
-use std::collections::BTreeSet;
 use std::fs::File;
 use std::sync::Arc;
-use std::time::SystemTime;
-use std::time::UNIX_EPOCH;
-use tracing::debug;
+use std::time::{SystemTime, UNIX_EPOCH};
 use widestring::{U16CStr, U16CString, U16Str, U16String};
 use winapi::shared::ntstatus::{
     STATUS_ACCESS_DENIED, STATUS_BUFFER_OVERFLOW, STATUS_INVALID_DEVICE_REQUEST,
     STATUS_NOT_IMPLEMENTED, STATUS_OBJECT_NAME_NOT_FOUND,
 };
-use winapi::um::winnt::{FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_READONLY};
+use winapi::um::winnt::{
+    FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_HIDDEN,
+    FILE_ATTRIBUTE_NOT_CONTENT_INDEXED, FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SYSTEM,
+};
 
 // Dokanの利用に必要な構造体/関数/諸々の実装
 
@@ -25,13 +26,14 @@ use winapi::um::winnt::{FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_READONLY};
 pub struct HandleCtx {
     pub is_dir: bool,
     pub mft_no: Option<u64>,
+    pub node: NodeId,
     pub _path_u16: U16String,
 }
 
 pub struct UnUnlinkFs {
     pub _device_path: String,
     pub volume: Volume,
-    pub mft: Mft,
+    pub records: RecordSource,
     pub dev_reader: File,
     pub index: Arc<RwLock<DeletedIndex>>,
 }
@@ -40,24 +42,29 @@ impl UnUnlinkFs {
     pub fn new(
         device_path: String,
         volume: Volume,
-        mft: Mft,
+        records: RecordSource,
         dev_reader: File,
         index: Arc<RwLock<DeletedIndex>>,
     ) -> Self {
         Self {
             _device_path: device_path,
             volume,
-            mft,
+            records,
             dev_reader,
             index,
         }
     }
 
-    fn open_dir_ctx(&self, full: &U16CStr) -> OperationResult<CreateFileInfo<HandleCtx>> {
+    fn open_dir_ctx(
+        &self,
+        full: &U16CStr,
+        node: NodeId,
+    ) -> OperationResult<CreateFileInfo<HandleCtx>> {
         Ok(CreateFileInfo {
             context: HandleCtx {
                 is_dir: true,
                 mft_no: None,
+                node,
                 _path_u16: U16String::from_vec(full.as_slice().to_vec()),
             },
             is_dir: true,
@@ -67,12 +74,14 @@ impl UnUnlinkFs {
     fn open_file_ctx(
         &self,
         full: &U16CStr,
+        node: NodeId,
         mft_no: u64,
     ) -> OperationResult<CreateFileInfo<HandleCtx>> {
         Ok(CreateFileInfo {
             context: HandleCtx {
                 is_dir: false,
                 mft_no: Some(mft_no),
+                node,
                 _path_u16: U16String::from_vec(full.as_slice().to_vec()),
             },
             is_dir: false,
@@ -80,14 +89,15 @@ impl UnUnlinkFs {
         })
     }
 
-    fn read_all_data(&self, mft_no: u64) -> Vec<u8> {
-        let rec: &[u8] = self.mft.get_record_data(mft_no);
-        Mft::read_data_fs(
+    // レコードが無ければ None (スナップショットの後にクラスタが使われたファイル等)
+    fn read_all_data(&self, mft_no: u64) -> Option<Vec<u8>> {
+        let rec = self.records.record_data(mft_no)?;
+        Some(Mft::read_data_fs(
             &self.volume,
             &mut self.dev_reader.try_clone().unwrap(),
-            rec,
+            &rec,
             NtfsAttributeType::Data,
-        )
+        ))
     }
 }
 
@@ -112,44 +122,43 @@ impl<'a> FileSystemHandler<'a, 'a> for UnUnlinkFs {
             FILE_OPEN, FILE_OPEN_IF, FILE_OPEN_REPARSE_POINT, FILE_OVERWRITE, FILE_OVERWRITE_IF,
             FILE_SUPERSEDE,
         };
-        let name_dbg = file_name.to_string_lossy();
-        let passed_key = path_key_lc_from_u16(file_name);
-        let open_key = key_for_dir_listing(file_name);
         if matches!(
             create_disposition,
             FILE_CREATE | FILE_SUPERSEDE | FILE_OVERWRITE | FILE_OVERWRITE_IF
         ) || (create_options & FILE_DELETE_ON_CLOSE) != 0
         {
-            // debug!("create_file: DENY write/create name='{}' passed_key='{}' open_key='{}' disp=0x{:x} opt=0x{:x}", name_dbg, passed_key, open_key, create_disposition, create_options);
             return Err(STATUS_ACCESS_DENIED);
         }
-        if is_root_key(&open_key) {
-            return self.open_dir_ctx(file_name);
-        }
         let _ignore_reparse_point = (create_options & FILE_OPEN_REPARSE_POINT) != 0;
         let idx = self.index.read();
-        match idx.get(&open_key) {
-            Some(EntryOrDir::Dir) => {
+        let (node, entry) = match idx.get(listing_path(file_name)) {
+            Some(found) => found,
+            None => {
+                return if matches!(create_disposition, FILE_OPEN | FILE_OPEN_IF) {
+                    Err(STATUS_OBJECT_NAME_NOT_FOUND)
+                } else {
+                    Err(STATUS_ACCESS_DENIED)
+                };
+            }
+        };
+        if node == ROOT_NODE {
+            return self.open_dir_ctx(file_name, node);
+        }
+        match &*entry {
+            EntryOrDir::Dir(_) => {
                 if (create_options & FILE_NON_DIRECTORY_FILE) != 0 {
                     return Err(winapi::shared::ntstatus::STATUS_FILE_IS_A_DIRECTORY);
                 }
-                self.open_dir_ctx(file_name)
+                self.open_dir_ctx(file_name, node)
             }
-            Some(EntryOrDir::File(m)) => {
+            EntryOrDir::File(m) => {
                 if (create_options & FILE_DIRECTORY_FILE) != 0 {
                     return Err(winapi::shared::ntstatus::STATUS_NOT_A_DIRECTORY);
                 }
                 if !matches!(create_disposition, FILE_OPEN | FILE_OPEN_IF) {
                     return Err(STATUS_ACCESS_DENIED);
                 }
-                self.open_file_ctx(file_name, m.mft_no)
-            }
-            None => {
-                if matches!(create_disposition, FILE_OPEN | FILE_OPEN_IF) {
-                    Err(STATUS_OBJECT_NAME_NOT_FOUND)
-                } else {
-                    Err(STATUS_ACCESS_DENIED)
-                }
+                self.open_file_ctx(file_name, node, m.mft_no)
             }
         }
     }
@@ -176,134 +185,58 @@ impl<'a> FileSystemHandler<'a, 'a> for UnUnlinkFs {
         _info: &OperationInfo<'a, 'a, Self>,
         _context: &'a Self::Context,
     ) -> OperationResult<DokanFileInfo> {
-        let key = key_for_dir_listing(file_name);
         let idx = self.index.read();
-        if is_root_key(&key) {
-            return Ok(DokanFileInfo {
-                attributes: FILE_ATTRIBUTE_DIRECTORY,
-                creation_time: UNIX_EPOCH,
-                last_access_time: UNIX_EPOCH,
-                last_write_time: UNIX_EPOCH,
-                file_size: 0,
-                number_of_links: 1,
-                file_index: file_index_from_key("\\"),
-            });
-        }
-        match idx.get(&key) {
-            Some(EntryOrDir::Dir) => Ok(DokanFileInfo {
+        let (node, entry) = idx
+            .get(listing_path(file_name))
+            .ok_or(STATUS_OBJECT_NAME_NOT_FOUND)?;
+        match &*entry {
+            EntryOrDir::Dir(Some(m)) => {
+                let mut fi = m.to_file_info();
+                fi.file_index = dir_file_index(node);
+                Ok(fi)
+            }
+            EntryOrDir::Dir(None) => Ok(DokanFileInfo {
                 attributes: FILE_ATTRIBUTE_DIRECTORY,
                 creation_time: UNIX_EPOCH,
                 last_access_time: UNIX_EPOCH,
                 last_write_time: UNIX_EPOCH,
                 file_size: 0,
                 number_of_links: 1,
-                file_index: file_index_from_key(&key),
+                file_index: dir_file_index(node),
             }),
-            Some(EntryOrDir::File(m)) => {
+            EntryOrDir::File(m) => {
                 let mut fi = m.to_file_info();
                 if fi.number_of_links == 0 {
                     fi.number_of_links = 1;
                 }
                 if fi.file_index == 0 {
-                    fi.file_index = file_index_from_key(&key);
+                    fi.file_index = dir_file_index(node);
                 }
                 Ok(fi)
             }
-            None => Err(STATUS_OBJECT_NAME_NOT_FOUND),
         }
     }
 
     // ファイル検索
     fn find_files(
         &'a self,
-        file_name: &U16CStr,
+        _file_name: &U16CStr,
         mut fill_find_data: impl FnMut(&FindData) -> FillDataResult,
         _info: &OperationInfo<'a, 'a, Self>,
         context: &'a Self::Context,
     ) -> OperationResult<()> {
-        let ctx_cstr = U16CString::from_ustr(context._path_u16.as_ustr()).unwrap();
-        let ctx_path_ucstr: &U16CStr = ctx_cstr.as_ucstr();
-        let parent_key = key_for_dir_listing(ctx_path_ucstr);
-        let passed_key = path_key_lc_from_u16(file_name);
-        let treat_as_dir = if context.is_dir {
-            true
-        } else if is_root_key(&parent_key) {
-            true
-        } else {
-            let idx = self.index.read();
-            matches!(idx.get(&parent_key), Some(EntryOrDir::Dir))
-        };
-        /* debug!(
-            "find_files: ctx='{}' parent_key='{}' passed_key='{}' is_dir_ctx={} treat_as_dir={}",
-            ctx_path_ucstr.to_string_lossy(),
-            parent_key,
-            passed_key,
-            context.is_dir,
-            treat_as_dir
-        ); */
-        if !treat_as_dir {
-            return Err(STATUS_INVALID_DEVICE_REQUEST);
-        }
-        let mut out: Vec<FindData> = vec![mk_dir_entry("."), mk_dir_entry("..")];
-        let mut children = {
+        let out: Vec<FindData> = {
             let idx = self.index.read();
-            idx.list_children(&parent_key)
-        };
-        if children.is_empty() && is_root_key(&parent_key) {
-            let idx = self.index.read();
-            let mut first_level: BTreeSet<U16String> = BTreeSet::new();
-            for node_key in idx.nodes.keys() {
-                if node_key == "\\" {
-                    continue;
-                }
-                if let Some(rest) = node_key.strip_prefix('\\') {
-                    let first = match rest.find('\\') {
-                        Some(p) => &rest[..p],
-                        None => rest,
-                    };
-                    if !first.is_empty() {
-                        first_level.insert(U16String::from_str(first));
-                    }
-                }
+            if !context.is_dir && !idx.is_dir(context.node) {
+                return Err(STATUS_INVALID_DEVICE_REQUEST);
             }
-            children = first_level.into_iter().collect();
-        }
-        {
-            let idx = self.index.read();
-            for name in children {
-                let child_key = if is_root_key(&parent_key) {
-                    normalize_and_canonicalize_for_key(&format!(r"\{}", name.to_string_lossy()))
-                } else {
-                    normalize_and_canonicalize_for_key(&format!(
-                        r"{}\{}",
-                        parent_key,
-                        name.to_string_lossy()
-                    ))
-                };
-                if let Some(entry) = idx.get(&child_key) {
-                    match entry {
-                        EntryOrDir::Dir => out.push(FindData {
-                            attributes: FILE_ATTRIBUTE_DIRECTORY,
-                            creation_time: UNIX_EPOCH,
-                            last_access_time: UNIX_EPOCH,
-                            last_write_time: UNIX_EPOCH,
-                            file_size: 0,
-                            file_name: U16CString::from_ustr(name.as_ustr()).unwrap(),
-                        }),
-                        EntryOrDir::File(m) => out.push(m.to_find_data()),
-                    }
-                } else if is_root_key(&parent_key) {
-                    out.push(FindData {
-                        attributes: FILE_ATTRIBUTE_DIRECTORY,
-                        creation_time: UNIX_EPOCH,
-                        last_access_time: UNIX_EPOCH,
-                        last_write_time: UNIX_EPOCH,
-                        file_size: 0,
-                        file_name: U16CString::from_ustr(name.as_ustr()).unwrap(),
-                    });
-                }
-            }
-        }
+            let mut out: Vec<FindData> = vec![mk_dir_entry("."), mk_dir_entry("..")];
+            out.extend(
+                idx.list_children(context.node)
+                    .filter_map(|(child, name)| find_data_for(&idx, child, &name)),
+            );
+            out
+        };
         for d in out {
             match fill_find_data(&d) {
                 Ok(_) => {}
@@ -323,89 +256,39 @@ impl<'a> FileSystemHandler<'a, 'a> for UnUnlinkFs {
         _info: &OperationInfo<'a, 'a, Self>,
         context: &'a Self::Context,
     ) -> OperationResult<()> {
-        let ctx_cstr = U16CString::from_ustr(context._path_u16.as_ustr()).unwrap();
-        let ctx_path_ucstr: &U16CStr = ctx_cstr.as_ucstr();
-        let parent_key = key_for_dir_listing(ctx_path_ucstr);
-        let pat = search_pattern.to_string_lossy();
-        let pat_is_all = pat.is_empty() || pat == "*" || pat == "*.*";
-        let literal = is_literal_pattern(&pat);
-        let is_dir_ok = if context.is_dir {
-            true
-        } else {
-            let idx = self.index.read();
-            matches!(idx.get(&parent_key), Some(EntryOrDir::Dir)) || is_root_key(&parent_key)
-        };
-        if !is_dir_ok {
-            return Err(STATUS_INVALID_DEVICE_REQUEST);
-        }
+        let pat = search_pattern.as_slice();
+        let star = b'*' as u16;
+        let dot = b'.' as u16;
+        let pat_is_all = pat.is_empty() || pat == [star] || pat == [star, dot, star];
         let items: Vec<FindData> = {
             let idx = self.index.read();
-            let mut out: Vec<FindData> = Vec::new();
-            let mut children = idx.list_children(&parent_key);
-            if children.is_empty() && is_root_key(&parent_key) {
-                let mut first_level: BTreeSet<U16String> = BTreeSet::new();
-                for node_key in idx.nodes.keys() {
-                    if node_key == "\\" {
-                        continue;
-                    }
-                    if let Some(rest) = node_key.strip_prefix('\\') {
-                        let first = match rest.find('\\') {
-                            Some(p) => &rest[..p],
-                            None => rest,
-                        };
-                        if !first.is_empty() {
-                            first_level.insert(U16String::from_str(first));
-                        }
-                    }
-                }
-                children = first_level.into_iter().collect();
+            if !context.is_dir && !idx.is_dir(context.node) {
+                return Err(STATUS_INVALID_DEVICE_REQUEST);
             }
+            let mut out: Vec<FindData> = Vec::new();
             if pat_is_all {
                 let [dot, dotdot] = dot_entries();
                 out.push(dot);
                 out.push(dotdot);
-            }
-            for name in children {
-                if !pat_is_all && !glob_match_ci(&name.to_string_lossy(), &pat) {
-                    continue;
-                }
-                let child_key = if is_root_key(&parent_key) {
-                    normalize_and_canonicalize_for_key(&format!(r"\{}", name.to_string_lossy()))
-                } else {
-                    normalize_and_canonicalize_for_key(&format!(
-                        r"{}\{}",
-                        parent_key,
-                        name.to_string_lossy()
-                    ))
-                };
-                if let Some(entry) = idx.get(&child_key) {
-                    let d = match entry {
-                        EntryOrDir::Dir => FindData {
-                            attributes: FILE_ATTRIBUTE_DIRECTORY,
-                            creation_time: UNIX_EPOCH,
-                            last_access_time: UNIX_EPOCH,
-                            last_write_time: UNIX_EPOCH,
-                            file_size: 0,
-                            file_name: U16CString::from_ustr(name.as_ustr()).unwrap(),
-                        },
-                        EntryOrDir::File(m) => m.to_find_data(),
-                    };
+                out.extend(
+                    idx.list_children(context.node)
+                        .filter_map(|(child, name)| find_data_for(&idx, child, &name)),
+                );
+            } else if is_literal_pattern(pat) {
+                // ワイルドカードなしなら子の名前表から直接引く
+                if let Some(d) = idx
+                    .find_child(context.node, pat)
+                    .and_then(|child| find_data_for(&idx, child, &idx.name(child)))
+                {
                     out.push(d);
-                    if literal {
-                        break;
+                }
+            } else {
+                for (child, name) in idx.list_children(context.node) {
+                    if !idx.upcase().glob_match(name.as_slice(), pat) {
+                        continue;
                     }
-                } else if is_root_key(&parent_key) {
-                    let d = FindData {
-                        attributes: FILE_ATTRIBUTE_DIRECTORY,
-                        creation_time: UNIX_EPOCH,
-                        last_access_time: UNIX_EPOCH,
-                        last_write_time: UNIX_EPOCH,
-                        file_size: 0,
-                        file_name: U16CString::from_ustr(name.as_ustr()).unwrap(),
-                    };
-                    out.push(d);
-                    if literal {
-                        break;
+                    if let Some(d) = find_data_for(&idx, child, &name) {
+                        out.push(d);
                     }
                 }
             }
@@ -425,14 +308,13 @@ impl<'a> FileSystemHandler<'a, 'a> for UnUnlinkFs {
     // MFTからデータ位置を決めて読み取る
     fn read_file(
         &'a self,
-        file_name: &U16CStr,
+        _file_name: &U16CStr,
         offset: i64,
         buffer: &mut [u8],
         _info: &OperationInfo<'a, 'a, Self>,
         context: &'a Self::Context,
     ) -> OperationResult<u32> {
-        let key_lc = path_key_lc_from_u16(file_name);
-        if context.is_dir || is_root_key(&key_lc) {
+        if context.is_dir {
             return Err(STATUS_INVALID_DEVICE_REQUEST);
         }
         let mft_no = match context.mft_no {
@@ -443,7 +325,8 @@ impl<'a> FileSystemHandler<'a, 'a> for UnUnlinkFs {
         // panicが発生してもシステムクラッシュしないようにcatch_unwindで囲む
         let data =
             std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.read_all_data(mft_no)))
-                .map_err(|_| STATUS_INVALID_DEVICE_REQUEST)?;
+                .map_err(|_| STATUS_INVALID_DEVICE_REQUEST)?
+                .ok_or(winapi::shared::ntstatus::STATUS_FILE_INVALID)?;
         let off = if offset < 0 { 0 } else { offset as usize };
         if off >= data.len() {
             return Ok(0);
@@ -587,39 +470,8 @@ fn mk_dir_entry(name: &str) -> FindData {
     }
 }
 
-fn is_literal_pattern(pat: &str) -> bool {
-    !pat.contains('*') && !pat.contains('?')
-}
-
-fn glob_match_ci(name: &str, pat: &str) -> bool {
-    fn inner(ns: &[u8], ps: &[u8]) -> bool {
-        let (mut i, mut j) = (0usize, 0usize);
-        let (mut star_i, mut star_j) = (None, None);
-        while i < ns.len() {
-            if j < ps.len() && (ps[j] == b'?' || ps[j].eq_ignore_ascii_case(&ns[i])) {
-                i += 1;
-                j += 1;
-            } else if j < ps.len() && ps[j] == b'*' {
-                star_i = Some(i);
-                star_j = Some(j);
-                j += 1;
-            } else if let (Some(si), Some(sj)) = (star_i, star_j) {
-                i = si + 1;
-                star_i = Some(i);
-                j = sj + 1;
-            } else {
-                return false;
-            }
-        }
-        while j < ps.len() && ps[j] == b'*' {
-            j += 1;
-        }
-        j == ps.len()
-    }
-    inner(
-        &name.to_ascii_lowercase().as_bytes(),
-        &pat.to_ascii_lowercase().as_bytes(),
-    )
+fn is_literal_pattern(pat: &[u16]) -> bool {
+    !pat.contains(&(b'*' as u16)) && !pat.contains(&(b'?' as u16))
 }
 
 // 「.」と「..」のエントリを作成
@@ -644,64 +496,82 @@ fn dot_entries() -> [FindData; 2] {
     [dot, dotdot]
 }
 
-fn key_for_dir_listing(name: &U16CStr) -> String {
-    let mut k = path_key_lc_from_u16(name);
-    if k.ends_with("\\*.*") || k.ends_with("\\*") {
-        if let Some(pos) = k.rfind('\\') {
-            if pos == 0 {
-                k = "\\".to_string();
-            } else {
-                k.truncate(pos);
-            }
-        }
-    }
-    if k == "\\." || k == "\\.." || k.is_empty() {
-        k = "\\".to_string();
+// 子1件分の検索結果を作る
+fn find_data_for(idx: &DeletedIndex, node: NodeId, name: &U16Str) -> Option<FindData> {
+    match &*idx.entry(node)? {
+        EntryOrDir::Dir(Some(m)) => Some(m.to_find_data(name)),
+        EntryOrDir::Dir(None) => Some(FindData {
+            attributes: FILE_ATTRIBUTE_DIRECTORY,
+            creation_time: UNIX_EPOCH,
+            last_access_time: UNIX_EPOCH,
+            last_write_time: UNIX_EPOCH,
+            file_size: 0,
+            file_name: U16CString::from_ustr(name).ok()?,
+        }),
+        EntryOrDir::File(m) => Some(m.to_find_data(name)),
     }
-    k
-}
-fn is_root_key(s: &str) -> bool {
-    normalize_and_canonicalize_for_key(s) == "\\"
 }
 
-fn file_index_from_key(key: &str) -> u64 {
-    let mut h: u64 = 0xcbf29ce484222325;
-    for &b in key.as_bytes() {
-        h ^= b as u64;
-        h = h.wrapping_mul(0x100000001b3);
+// 末尾の「\*」「\*.*」を取り除いたパス
+fn listing_path(name: &U16CStr) -> &[u16] {
+    let p = name.as_slice();
+    for suffix in ["\\*.*", "\\*"] {
+        let suf: Vec<u16> = suffix.encode_utf16().collect();
+        if p.ends_with(&suf) {
+            return &p[..p.len() - suf.len()];
+        }
     }
-    if h == 0 { 1 } else { h }
+    p
+}
+
+// ディレクトリにはMFT番号がないので、ノード番号からファイルインデックスを作る
+fn dir_file_index(node: NodeId) -> u64 {
+    (1u64 << 63) | node as u64
 }
 
+// 元のファイル属性のうち、そのまま見せても害のないもの
+// (圧縮/暗号化/スパース/再解析ポイントは R:\ 上の実体と合わないので落とす)
+const PASSTHROUGH_ATTRIBUTES: u32 = FILE_ATTRIBUTE_HIDDEN
+    | FILE_ATTRIBUTE_SYSTEM
+    | FILE_ATTRIBUTE_ARCHIVE
+    | FILE_ATTRIBUTE_NOT_CONTENT_INDEXED;
+
 impl EntryMeta {
-    pub fn to_find_data(&self) -> FindData {
-        let attrs: u32 = if self.is_dir {
+    fn dokan_attributes(&self) -> u32 {
+        let kind = if self.is_dir {
             FILE_ATTRIBUTE_DIRECTORY
         } else {
             FILE_ATTRIBUTE_READONLY
         };
+        kind | (self.attributes & PASSTHROUGH_ATTRIBUTES)
+    }
+    // (作成, アクセス, 更新) の時刻
+    // レコードの FILETIME から 100ns 精度のまま変換する (1970年より前もそのまま)
+    fn dokan_times(&self) -> (SystemTime, SystemTime, SystemTime) {
+        let ft = self.times.primary().unwrap_or_default();
+        let pick = |t: u64| filetime_to_system_time(t).unwrap_or(UNIX_EPOCH);
+        (pick(ft.created), pick(ft.accessed), pick(ft.modified))
+    }
+    pub fn to_find_data(&self, name: &U16Str) -> FindData {
+        let (created, accessed, modified) = self.dokan_times();
         FindData {
-            attributes: attrs,
-            creation_time: self.created.unwrap_or(UNIX_EPOCH),
-            last_access_time: self.accessed.unwrap_or(UNIX_EPOCH),
-            last_write_time: self.modified.unwrap_or(UNIX_EPOCH),
+            attributes: self.dokan_attributes(),
+            creation_time: created,
+            last_access_time: accessed,
+            last_write_time: modified,
             file_size: self.size,
-            file_name: U16CString::from_ustr(self.name_u16.as_ustr()).unwrap(),
+            file_name: U16CString::from_ustr(name).unwrap(),
         }
     }
     pub fn to_file_info(&self) -> DokanFileInfo {
-        let attrs: u32 = if self.is_dir {
-            FILE_ATTRIBUTE_DIRECTORY
-        } else {
-            FILE_ATTRIBUTE_READONLY
-        };
+        let (created, accessed, modified) = self.dokan_times();
         DokanFileInfo {
-            attributes: attrs,
-            creation_time: self.created.unwrap_or(UNIX_EPOCH),
-            last_access_time: self.accessed.unwrap_or(UNIX_EPOCH),
-            last_write_time: self.modified.unwrap_or(UNIX_EPOCH),
+            attributes: self.dokan_attributes(),
+            creation_time: created,
+            last_access_time: accessed,
+            last_write_time: modified,
             file_size: self.size,
-            number_of_links: 1,
+            number_of_links: self.link_count.max(1) as u32,
             file_index: self.mft_no,
         }
     }
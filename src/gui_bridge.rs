@@ -1,14 +1,37 @@
+use crate::dest_guard::{self, DestinationCheck};
 use crate::drives::enum_ntfs_drives;
+use crate::file_meta::{self, OriginalMeta};
 use crate::fs::UnUnlinkFs;
-use crate::indexer::DeletedIndex;
-use crate::scan::{CANCEL, indexer_worker, progress_loop_emit, start_scanner_pool};
-use crate::util::{humanize_bytes, normalize_device};
+use crate::filetype::{sniff, ContentType, SNIFF_BYTES};
+use crate::indexer::{DeletedIndex, EntryMeta, EntryOrDir, NodeId};
+use crate::local_search::{self, LocalSearchResult};
+use crate::mft_stream::{DirTable, MftStream, RecordSource};
+use crate::query::{Query, QueryHit, QueryResult};
+use crate::recover::{self, ConflictPolicy, Recovery, Selection, RECOVER_CANCEL};
+use crate::recoverability::{ClusterBitmap, Recoverability};
+use crate::redact::{RedactMode, RedactionConfig, Redactor};
+use crate::scan::{
+    CANCEL, ScanOptions, indexer_worker, progress_loop_emit, start_scanner_pool,
+    start_streaming_scanner_pool,
+};
+use crate::search_provider::{
+    HttpSearchProvider, SearchAnswer, SearchFile, SearchProvider, UploadSummary,
+};
+use crate::snapshot;
+use crate::timestamps::filetime_to_system_time;
+use crate::upcase::{self, UpCaseTable};
+use crate::util::{
+    escape_u16, humanize_bytes, normalize_device, system_time_to_unix_parts, unescape_u16,
+};
 use anyhow::{Context, Result};
 use dokan::{FileSystemMounter, MountOptions, shutdown, unmount};
 use ntfs_reader::{mft::Mft, volume::Volume};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
+use std::os::windows::ffi::OsStringExt;
 use std::path::{Path, PathBuf};
 use std::sync::{
     Arc,
@@ -17,7 +40,7 @@ use std::sync::{
 use std::time::Instant;
 use std::{mem, os::windows::ffi::OsStrExt, ptr::null_mut};
 use tauri::{AppHandle, Manager};
-use tracing::info;
+use tracing::{info, warn};
 use widestring::U16CString;
 use winapi::um::{
     handleapi::CloseHandle,
@@ -31,6 +54,37 @@ use winapi::um::{
 #[derive(Default)]
 pub struct AppState {
     pub mounted: AtomicBool,
+    // 最後にスキャンしたボリュームの結果 (取り出した後も一覧等に使う)
+    pub session: RwLock<Option<Arc<MountSession>>>,
+    // まとめての復元が動いている間
+    pub recovering: AtomicBool,
+}
+
+pub struct MountSession {
+    pub device: String,
+    pub index: Arc<RwLock<DeletedIndex>>,
+    // 復元可否の判定に使う $Bitmap と判定結果 (refresh_recoverability_cmd で読み直す)
+    pub assessment: Mutex<AssessCache>,
+    // スナップショットから読み込んだか (読み込んだ結果は保存し直さない)
+    pub from_snapshot: bool,
+    // スナップショットの後にクラスタが使われたレコード (読み出しも復元もしない)
+    pub withheld: HashSet<u64>,
+}
+
+// マウントするインデックスの出どころ
+enum Origin {
+    // スキャンした結果。detect_types なら、マウントした後に中身から種類を判定する
+    Scan { detect_types: bool },
+    // スナップショット。withheld はクラスタが変わったレコード
+    Snapshot { withheld: HashSet<u64> },
+}
+
+#[derive(Default)]
+pub struct AssessCache {
+    // 最初に判定するときに読む。読めなければ Some(None) のまま (読み直すまで Unknown)
+    volume: Option<Option<Arc<(MftStream, ClusterBitmap)>>>,
+    // レコード番号ごとの判定結果
+    results: HashMap<u64, Recoverability>,
 }
 
 #[tauri::command]
@@ -70,6 +124,7 @@ pub fn eject_cmd(app: AppHandle, state: tauri::State<AppState>) -> Result<(), St
 #[tauri::command]
 pub fn start_mount_cmd(
     letter: String,
+    include_live: Option<bool>,
     app: AppHandle,
     state: tauri::State<AppState>,
 ) -> Result<(), String> {
@@ -77,8 +132,16 @@ pub fn start_mount_cmd(
         return Err("already mounted or in progress".into());
     }
 
-    // 管理者として実行されていない場合は再起動
-    // その場合は自分自身を自動で終了
+    if relaunch_unless_elevated() {
+        return Err("relaunching as administrator".into());
+    }
+
+    spawn_mount(&app, &state, move |app| do_mount(letter, include_live, app));
+    Ok(())
+}
+
+// 管理者として実行されていない場合は再起動する (true なら自分自身を終了させる)
+fn relaunch_unless_elevated() -> bool {
     unsafe {
         let mut tok: HANDLE = null_mut();
         if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut tok) != 0 {
@@ -105,99 +168,239 @@ pub fn start_mount_cmd(
                         SW_SHOW,
                     );
                 }
-                return Err("relaunching as administrator".into());
+                return true;
             }
         }
     }
 
+    false
+}
+
+// マウント処理を別スレッドで動かし、失敗したら画面に知らせる
+fn spawn_mount<F>(app: &AppHandle, state: &AppState, f: F)
+where
+    F: FnOnce(AppHandle) -> Result<()> + Send + 'static,
+{
     state.mounted.store(true, Ordering::Relaxed);
     CANCEL.store(false, Ordering::Relaxed);
 
     let app_for_thread = app.clone();
-    let letter_for_thread = letter.clone();
     std::thread::spawn(move || {
         let st = app_for_thread.state::<AppState>();
-        match do_mount(letter_for_thread, app_for_thread.clone()) {
-            Ok(()) => {
-                st.mounted.store(false, Ordering::Relaxed);
-            }
-            Err(e) => {
-                st.mounted.store(false, Ordering::Relaxed);
-                let _ = app_for_thread.emit_all(
-                    "state",
-                    serde_json::json!({"state":"error","error": e.to_string()}),
-                );
-            }
+        let res = f(app_for_thread.clone());
+        st.mounted.store(false, Ordering::Relaxed);
+        if let Err(e) = res {
+            let _ = app_for_thread.emit_all(
+                "state",
+                serde_json::json!({"state":"error","error": e.to_string()}),
+            );
         }
     });
-
-    Ok(())
 }
 
 // マウント開始
-fn do_mount(letter: String, app: AppHandle) -> Result<()> {
+fn do_mount(letter: String, include_live: Option<bool>, app: AppHandle) -> Result<()> {
     let device = normalize_device(&letter)?;
     info!(device = %device, "selected device");
 
     let volume = Volume::new(&device).with_context(|| format!("failed to open {}", device))?;
-    let mft_for_scan = Mft::new(volume.clone()).context("failed to open $MFT for scan")?;
-    let shared_mft = Arc::new(RwLock::new(mft_for_scan));
-    let max_record = { shared_mft.read().max_record as u64 };
-    {
-        let _ = shared_mft
-            .read()
-            .get_record(ntfs_reader::api::FIRST_NORMAL_RECORD);
+    let mut opts = ScanOptions::from_env();
+    if let Some(v) = include_live {
+        opts.include_live = v;
     }
+    let stream = MftStream::open(&device).context("failed to read $MFT layout")?;
+    let streaming = opts.use_streaming(stream.mft_bytes());
+    info!(
+        streaming,
+        mft_bytes = stream.mft_bytes(),
+        metadata_budget = ?opts.metadata_budget,
+        spill_dir = ?opts.spill_dir,
+        include_live = opts.include_live,
+        dup_policy = ?opts.dup_policy,
+        "scan mode"
+    );
+
+    // 名前の比較はボリューム自身の $UpCase に合わせる
+    let upcase = match UpCaseTable::load_from_volume(&stream) {
+        Ok(t) => Arc::new(t),
+        Err(e) => {
+            warn!(error = %e, "failed to load $UpCase, using built-in table");
+            Arc::new(UpCaseTable::builtin())
+        }
+    };
+    upcase::set_active(upcase.clone());
 
-    let total_records = (max_record - ntfs_reader::api::FIRST_NORMAL_RECORD as u64) as u64;
     let processed = Arc::new(AtomicU64::new(0));
     let found = Arc::new(AtomicU64::new(0));
     let running = Arc::new(AtomicBool::new(true));
     let start_time = Instant::now();
 
     let (tx, rx) = crossbeam_channel::unbounded();
-    let scan_threads = start_scanner_pool(
-        shared_mft.clone(),
-        tx.clone(),
-        processed.clone(),
-        max_record,
-    );
-    drop(tx);
     let found_for_worker = found.clone();
-    let idx_handle = std::thread::spawn(move || indexer_worker(rx, found_for_worker, 4096));
-
-    let prog_thr = progress_loop_emit(
-        app.clone(),
-        processed.clone(),
-        found.clone(),
-        total_records,
-        running.clone(),
-        start_time,
-    );
+    let budget = dest_guard::spill_budget(&device, &opts);
+    let dup_policy = opts.dup_policy;
+    // 失敗しうる準備 ($MFT を開く、ディレクトリ表を作る) が済んでから起動する
+    let spawn_indexer = move || {
+        std::thread::spawn(move || {
+            indexer_worker(rx, found_for_worker, 4096, budget, upcase, dup_policy)
+        })
+    };
+
+    let (records, mut built_index) = if streaming {
+        let total_records = stream
+            .max_record
+            .saturating_sub(ntfs_reader::api::FIRST_NORMAL_RECORD as u64);
+        let prog_thr = progress_loop_emit(
+            app.clone(),
+            processed.clone(),
+            found.clone(),
+            total_records,
+            running.clone(),
+            start_time,
+        );
+        let stream = Arc::new(stream);
+        // 1周目: ディレクトリの親子関係だけを集める (失敗して抜けても prog_thr は drop で止まる)
+        let dirs = Arc::new(DirTable::build(&stream, opts.window_records)?);
+        // 2周目: 削除済みレコードを拾ってパスを解決
+        let idx_handle = spawn_indexer();
+        let scan_threads = start_streaming_scanner_pool(
+            stream.clone(),
+            dirs.clone(),
+            tx.clone(),
+            processed.clone(),
+            opts.window_records,
+            opts.include_live,
+        );
+        drop(tx);
+        for h in scan_threads {
+            let _ = h.join();
+        }
+        let mut built_index: DeletedIndex = idx_handle.join().unwrap();
+        built_index.fill_dir_meta(&dirs);
+        drop(dirs);
+        if opts.include_live {
+            built_index.mark_live_matches();
+        }
+        prog_thr.stop();
+        let stream = Arc::try_unwrap(stream)
+            .unwrap_or_else(|_| panic!("mft stream still has strong refs at FS handoff"));
+        (RecordSource::Streamed(stream), built_index)
+    } else {
+        drop(stream);
+        let mft_for_scan = Mft::new(volume.clone()).context("failed to open $MFT for scan")?;
+        let shared_mft = Arc::new(RwLock::new(mft_for_scan));
+        let max_record = { shared_mft.read().max_record as u64 };
+        {
+            let _ = shared_mft
+                .read()
+                .get_record(ntfs_reader::api::FIRST_NORMAL_RECORD);
+        }
+
+        let total_records = (max_record - ntfs_reader::api::FIRST_NORMAL_RECORD as u64) as u64;
+        let dirs = Arc::new(DirTable::from_mft(&shared_mft.read()));
+        let idx_handle = spawn_indexer();
+        let scan_threads = start_scanner_pool(
+            shared_mft.clone(),
+            dirs.clone(),
+            tx.clone(),
+            processed.clone(),
+            max_record,
+            opts.include_live,
+        );
+        drop(tx);
+
+        let prog_thr = progress_loop_emit(
+            app.clone(),
+            processed.clone(),
+            found.clone(),
+            total_records,
+            running.clone(),
+            start_time,
+        );
+
+        for h in scan_threads {
+            let _ = h.join();
+        }
+        let mut built_index: DeletedIndex = idx_handle.join().unwrap();
+        built_index.fill_dir_meta(&dirs);
+        drop(dirs);
+        if opts.include_live {
+            built_index.mark_live_matches();
+        }
+        prog_thr.stop();
+
+        let mft_for_fs: Mft = Arc::try_unwrap(shared_mft)
+            .map(|rw| rw.into_inner())
+            .unwrap_or_else(|_| panic!("shared_mft still has strong refs at FS handoff"));
+        (RecordSource::Loaded(mft_for_fs), built_index)
+    };
 
-    for h in scan_threads {
-        let _ = h.join();
+    built_index.build_versions();
+    // 中身からの種類判定はマウントした後に行い、仮想フォルダもその後に作る
+    if !opts.detect_types {
+        built_index.build_views();
     }
-    let built_index: DeletedIndex = idx_handle.join().unwrap();
-    running.store(false, Ordering::Relaxed);
-    let _ = prog_thr.join();
+    let origin = Origin::Scan {
+        detect_types: opts.detect_types,
+    };
+    serve(&app, device, volume, records, built_index, origin)
+}
 
-    let mft_for_fs: Mft = Arc::try_unwrap(shared_mft)
-        .map(|rw| rw.into_inner())
-        .unwrap_or_else(|_| panic!("shared_mft still has strong refs at FS handoff"));
+const STALE_SHOWN: usize = 20;
+
+// スナップショットを読み込んでマウント (スキャンはしない)
+fn do_mount_snapshot(path: PathBuf, letter: Option<String>, app: AppHandle) -> Result<()> {
+    let device = letter.as_deref().map(normalize_device).transpose()?;
+    let opts = ScanOptions::from_env();
+    let snap = snapshot::load(&path, device.as_deref(), &opts)?;
+    // 保存後にクラスタが使われたファイルを知らせる (画面には先頭の一部だけ)
+    if !snap.stale.is_empty() {
+        let shown = &snap.stale[..snap.stale.len().min(STALE_SHOWN)];
+        let _ = app.emit_all(
+            "snapshot",
+            serde_json::json!({"stale_count": snap.stale.len(), "stale": shown}),
+        );
+    }
+    let volume =
+        Volume::new(&snap.device).with_context(|| format!("failed to open {}", snap.device))?;
+    serve(
+        &app,
+        snap.device,
+        volume,
+        RecordSource::Saved(snap.records),
+        snap.index,
+        Origin::Snapshot {
+            withheld: snap.withheld,
+        },
+    )
+}
 
+// インデックスを Dokan でマウントして、取り出されるまで待つ
+fn serve(
+    app: &AppHandle,
+    device: String,
+    volume: Volume,
+    records: RecordSource,
+    index: DeletedIndex,
+    origin: Origin,
+) -> Result<()> {
     let dev_reader = File::options()
         .read(true)
         .open(&device)
         .with_context(|| format!("open device for Data attribute: {}", device))?;
-    let idx_arc = Arc::new(RwLock::new(built_index));
-    let fs = UnUnlinkFs::new(
-        device.clone(),
-        volume,
-        mft_for_fs,
-        dev_reader,
-        idx_arc.clone(),
-    );
+    let idx_arc = Arc::new(RwLock::new(index));
+    let (detect_types, withheld) = match origin {
+        Origin::Scan { detect_types } => (detect_types, None),
+        Origin::Snapshot { withheld } => (false, Some(withheld)),
+    };
+    *app.state::<AppState>().session.write() = Some(Arc::new(MountSession {
+        device: device.clone(),
+        index: idx_arc.clone(),
+        assessment: Mutex::new(AssessCache::default()),
+        from_snapshot: withheld.is_some(),
+        withheld: withheld.unwrap_or_default(),
+    }));
+    let fs = UnUnlinkFs::new(device.clone(), volume, records, dev_reader, idx_arc.clone());
 
     let mut flags = dokan::MountFlags::ALT_STREAM | dokan::MountFlags::REMOVABLE;
     #[cfg(debug_assertions)]
@@ -221,6 +424,9 @@ fn do_mount(letter: String, app: AppHandle) -> Result<()> {
     );
 
     let _ = std::process::Command::new("explorer").arg("R:\\").spawn();
+    if detect_types {
+        spawn_type_detection(app.clone(), device, idx_arc);
+    }
 
     // CANCEL が立つまで待機
     loop {
@@ -233,95 +439,514 @@ fn do_mount(letter: String, app: AppHandle) -> Result<()> {
     Ok(())
 }
 
-#[derive(Serialize)]
-pub struct FileListItem {
-    pub name: String,
-    pub path: String,
-    pub ext: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub last_opened_ts: Option<i64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub last_modified_ts: Option<i64>,
+// 最後のスキャン結果をスナップショットとして保存
+#[tauri::command]
+pub fn save_snapshot_cmd(
+    path: String,
+    allow_same_volume: Option<bool>,
+    state: tauri::State<AppState>,
+) -> Result<(), String> {
+    let session = state
+        .session
+        .read()
+        .clone()
+        .ok_or_else(|| "no scan result".to_string())?;
+    // 保存し直すと今のレコードでダイジェストを作り直し、変わったことが分からなくなる
+    if session.from_snapshot {
+        return Err("the mounted index was loaded from a snapshot; scan again to save".into());
+    }
+    dest_guard::ensure(&session.device, Path::new(&path), allow_same_volume.unwrap_or(false))
+        .map_err(|e| format!("{:#}", e))?;
+    let index = session.index.read();
+    snapshot::save(Path::new(&path), &session.device, &index).map_err(|e| format!("{:#}", e))
 }
 
+// スナップショットからマウント (letter を省略すると保存時のデバイス)
 #[tauri::command]
-pub fn build_filelist_cmd(
+pub fn load_snapshot_cmd(
+    path: String,
+    letter: Option<String>,
     app: AppHandle,
     state: tauri::State<AppState>,
-    limit: Option<usize>,
-) -> Result<Vec<FileListItem>, String> {
-    if !state.mounted.load(Ordering::Relaxed) {
-        return Err("not mounted".into());
+) -> Result<(), String> {
+    if state.mounted.load(Ordering::Relaxed) {
+        return Err("already mounted or in progress".into());
     }
-    let limit = limit.unwrap_or(50_000);
-    let root = Path::new(r"R:\");
-    if !root.exists() {
-        return Err("mount point not found".into());
+    if relaunch_unless_elevated() {
+        return Err("relaunching as administrator".into());
     }
+    let path = PathBuf::from(path);
+    spawn_mount(&app, &state, move |app| do_mount_snapshot(path, letter, app));
+    Ok(())
+}
 
-    let mut out: Vec<FileListItem> = Vec::with_capacity(4096);
-    let mut stack: Vec<PathBuf> = vec![root.to_path_buf()];
+// "content_types" イベントで送る判定の進み具合
+#[derive(Serialize, Clone)]
+struct TypeDetectProgress {
+    checked: usize,
+    total: usize,
+    found: usize,
+    done: bool,
+    cancelled: bool,
+}
+
+const TYPE_DETECT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+// 削除済みファイルの先頭を読んで中身から種類を判定し、終わったら仮想フォルダを作る
+// マウントしたまま別スレッドで行う。取り出す (CANCEL) と途中でやめる
+fn spawn_type_detection(app: AppHandle, device: String, index: Arc<RwLock<DeletedIndex>>) {
+    std::thread::spawn(move || {
+        let started = Instant::now();
+        let progress = match MftStream::open(&device) {
+            Ok(stream) => detect_content_types(&app, &stream, &index),
+            Err(e) => {
+                warn!(error = %e, "skip content type detection");
+                TypeDetectProgress {
+                    checked: 0,
+                    total: 0,
+                    found: 0,
+                    done: true,
+                    cancelled: false,
+                }
+            }
+        };
+        if !progress.cancelled {
+            index.write().build_views();
+        }
+        info!(
+            found = progress.found,
+            cancelled = progress.cancelled,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "content type detection finished"
+        );
+        let _ = app.emit_all("content_types", progress);
+    });
+}
 
-    while let Some(dir) = stack.pop() {
-        if out.len() >= limit {
+// 読むときはロックを取らず、判定結果をまとめて書き込む (その間も R:\ は使える)
+fn detect_content_types(
+    app: &AppHandle,
+    stream: &MftStream,
+    index: &RwLock<DeletedIndex>,
+) -> TypeDetectProgress {
+    let todo = index.read().untyped_files();
+    let mut p = TypeDetectProgress {
+        checked: 0,
+        total: todo.len(),
+        found: 0,
+        done: false,
+        cancelled: false,
+    };
+    let mut batch: Vec<(Vec<NodeId>, ContentType)> = Vec::new();
+    let mut last_emit = Instant::now();
+    for (mft_no, ids) in todo {
+        if CANCEL.load(Ordering::Relaxed) {
+            p.cancelled = true;
             break;
         }
-        let rd = match std::fs::read_dir(&dir) {
-            Ok(rd) => rd,
-            Err(_) => continue,
-        };
-        for entry_res in rd {
-            if out.len() >= limit {
-                break;
+        let detected = stream
+            .read_record(mft_no)
+            .and_then(|rec| stream.read_data_head(&rec, SNIFF_BYTES))
+            .and_then(|h| sniff(&h));
+        if let Some(c) = detected {
+            p.found += ids.len();
+            batch.push((ids, c));
+        }
+        p.checked += 1;
+        if last_emit.elapsed() >= TYPE_DETECT_INTERVAL {
+            apply_types(index, &mut batch);
+            let _ = app.emit_all("content_types", p.clone());
+            last_emit = Instant::now();
+        }
+    }
+    apply_types(index, &mut batch);
+    p.done = true;
+    p
+}
+
+fn apply_types(index: &RwLock<DeletedIndex>, batch: &mut Vec<(Vec<NodeId>, ContentType)>) {
+    if batch.is_empty() {
+        return;
+    }
+    let mut idx = index.write();
+    for (ids, c) in batch.drain(..) {
+        idx.set_content_type(&ids, c);
+    }
+}
+
+// "filelist" イベントで送る一覧の一部
+#[derive(Serialize, Clone)]
+struct FileListChunk<'a> {
+    offset: usize,
+    // 並べ替えない (sort: "none") ときは最後のチャンクまで分からない
+    total: Option<usize>,
+    items: &'a [QueryHit],
+    done: bool,
+}
+
+const DEFAULT_FILELIST_CHUNK: usize = 2000;
+
+// limit も query.limit も無いときに返す件数
+const DEFAULT_FILELIST_LIMIT: usize = 10_000;
+
+// インデックスから直接ファイル一覧を作る (マウントしていなくても最後のスキャン結果を使う)
+// query を省略すると全ての削除済みファイル。limit は query.limit より優先
+// どちらも無ければ DEFAULT_FILELIST_LIMIT 件まで (続きは query.offset で取る)
+// stream を指定すると chunk_size 件ずつ "filelist" イベントで送り、戻り値は空になる
+#[tauri::command]
+pub fn build_filelist_cmd(
+    app: AppHandle,
+    state: tauri::State<AppState>,
+    query: Option<Query>,
+    limit: Option<usize>,
+    stream: Option<bool>,
+    chunk_size: Option<usize>,
+) -> Result<Vec<QueryHit>, String> {
+    let session = state
+        .session
+        .read()
+        .clone()
+        .ok_or_else(|| "no scan result".to_string())?;
+    let mut query = query.unwrap_or_default();
+    query.limit = limit.or(query.limit).or(Some(DEFAULT_FILELIST_LIMIT));
+    let assess = recoverability_assessor(&session);
+    let idx = session.index.read();
+
+    if !stream.unwrap_or(false) {
+        return query
+            .run(&idx, assess)
+            .map(|r| r.hits)
+            .map_err(|e| format!("{:#}", e));
+    }
+
+    let chunk_size = chunk_size.unwrap_or(DEFAULT_FILELIST_CHUNK).max(1);
+    let mut buf: Vec<QueryHit> = Vec::with_capacity(chunk_size);
+    let mut sent = 0usize;
+    let total = query
+        .run_each(&idx, assess, |total, h| {
+            buf.push(h);
+            if buf.len() == chunk_size {
+                let _ = app.emit_all(
+                    "filelist",
+                    FileListChunk {
+                        offset: sent,
+                        total,
+                        items: &buf,
+                        done: false,
+                    },
+                );
+                sent += buf.len();
+                buf.clear();
             }
-            let entry = match entry_res {
-                Ok(e) => e,
-                Err(_) => continue,
-            };
-            let p = entry.path();
-            let md = match entry.metadata() {
-                Ok(m) => m,
-                Err(_) => continue,
+        })
+        .map_err(|e| format!("{:#}", e))?;
+    let _ = app.emit_all(
+        "filelist",
+        FileListChunk {
+            offset: sent,
+            total: Some(total),
+            items: &buf,
+            done: true,
+        },
+    );
+    Ok(Vec::new())
+}
+
+#[derive(Serialize)]
+pub struct VersionView {
+    pub name: String,
+    // R:\$Versions\... のパス (エスケープ済み)
+    pub path: String,
+    pub mft_no: u64,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_ts: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_nsec: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_ts: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_nsec: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accessed_ts: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accessed_nsec: Option<u32>,
+    pub recoverability: Recoverability,
+}
+
+// 同じパスの削除済みファイルの版を古い順に返す
+// path は R:\ 以下のパス (元のパス、連番付きの名前、\$Versions 以下のどれでもよい)
+#[tauri::command]
+pub fn list_versions_cmd(
+    path: String,
+    state: tauri::State<AppState>,
+) -> Result<Vec<VersionView>, String> {
+    let session = state
+        .session
+        .read()
+        .clone()
+        .ok_or_else(|| "no scan result".to_string())?;
+    let rel = match path.get(..2) {
+        Some(d) if d.eq_ignore_ascii_case("R:") => &path[2..],
+        _ => path.as_str(),
+    };
+    let key = unescape_u16(rel);
+
+    let mut assess = recoverability_assessor(&session);
+    let idx = session.index.read();
+    let unix_parts = |ft: u64| filetime_to_system_time(ft).map(system_time_to_unix_parts);
+    let out = idx
+        .versions_of(&key)
+        .into_iter()
+        .filter_map(|id| {
+            let entry = idx.entry(id)?;
+            let EntryOrDir::File(m) = &*entry else {
+                return None;
             };
-            if md.is_dir() {
-                stack.push(p);
-                continue;
-            }
-            let name = entry.file_name().to_string_lossy().to_string();
-            let mut path_str = p.to_string_lossy().to_string();
-            if let Some(stripped) = path_str.strip_prefix(r"\\?\") {
-                path_str = stripped.to_string();
-            }
-            let ext = p
-                .extension()
-                .map(|e| format!(".{}", e.to_string_lossy()))
-                .unwrap_or_else(|| "".to_string());
-            let last_opened_ts = md
-                .accessed()
-                .ok()
-                .and_then(|st| st.duration_since(std::time::UNIX_EPOCH).ok())
-                .map(|d| d.as_secs() as i64);
-            let last_modified_ts = md
-                .modified()
-                .ok()
-                .and_then(|st| st.duration_since(std::time::UNIX_EPOCH).ok())
-                .map(|d| d.as_secs() as i64);
-
-            out.push(FileListItem {
-                name,
-                path: path_str,
-                ext,
-                last_opened_ts,
-                last_modified_ts,
-            });
+            let recoverability = assess(m);
+            let t = m.times.primary().unwrap_or_default();
+            let (created, modified, accessed) = (
+                unix_parts(t.created),
+                unix_parts(t.modified),
+                unix_parts(t.accessed),
+            );
+            Some(VersionView {
+                name: escape_u16(idx.name(id).as_slice()),
+                path: format!("R:{}", escape_u16(idx.path_of(id).as_slice())),
+                mft_no: m.mft_no,
+                size: m.size,
+                created_ts: created.map(|t| t.0),
+                created_nsec: created.map(|t| t.1),
+                modified_ts: modified.map(|t| t.0),
+                modified_nsec: modified.map(|t| t.1),
+                accessed_ts: accessed.map(|t| t.0),
+                accessed_nsec: accessed.map(|t| t.1),
+                recoverability,
+            })
+        })
+        .collect();
+    Ok(out)
+}
 
-            if out.len() % 5000 == 0 {
-                let _ = app.emit_all("log", format!("filelist: {} items...", out.len()));
+// 復元可否を判定する関数を作る
+// $Bitmap は最初に判定するときにだけ読み、結果とともにセッションに残す
+// (マウント中にボリュームが変わった分は refresh_recoverability_cmd で読み直す)
+// ボリュームが読めなければ (取り外された等) 全て Unknown
+fn recoverability_assessor(
+    session: &MountSession,
+) -> impl FnMut(&EntryMeta) -> Recoverability + '_ {
+    move |m: &EntryMeta| {
+        if m.live {
+            return Recoverability::Live;
+        }
+        let volume = {
+            let mut cache = session.assessment.lock();
+            if let Some(r) = cache.results.get(&m.mft_no) {
+                return *r;
             }
+            cache
+                .volume
+                .get_or_insert_with(|| load_bitmap(&session.device))
+                .clone()
+        };
+        let Some(volume) = volume else {
+            return Recoverability::Unknown;
+        };
+        let (stream, bitmap) = &*volume;
+        let r = match stream.read_record(m.mft_no) {
+            Some(rec) => bitmap.assess(&rec),
+            None => Recoverability::Unknown,
+        };
+        session.assessment.lock().results.insert(m.mft_no, r);
+        r
+    }
+}
+
+fn load_bitmap(device: &str) -> Option<Arc<(MftStream, ClusterBitmap)>> {
+    let loaded = MftStream::open(device).and_then(|stream| {
+        let bitmap = ClusterBitmap::load(&stream)?;
+        Ok((stream, bitmap))
+    });
+    match loaded {
+        Ok(l) => Some(Arc::new(l)),
+        Err(e) => {
+            warn!(error = %e, "failed to load $Bitmap");
+            None
         }
     }
+}
 
-    Ok(out)
+// 復元可否の判定をやり直す (次に判定するときに $Bitmap を読み直す)
+#[tauri::command]
+pub fn refresh_recoverability_cmd(state: tauri::State<AppState>) -> Result<(), String> {
+    let session = state
+        .session
+        .read()
+        .clone()
+        .ok_or_else(|| "no scan result".to_string())?;
+    *session.assessment.lock() = AssessCache::default();
+    info!("recoverability cache cleared");
+    Ok(())
+}
+
+// 条件で削除済みファイルを検索する (条件は query::Query を参照)
+#[tauri::command]
+pub fn query_files_cmd(
+    query: Query,
+    state: tauri::State<AppState>,
+) -> Result<QueryResult, String> {
+    let session = state
+        .session
+        .read()
+        .clone()
+        .ok_or_else(|| "no scan result".to_string())?;
+    let assess = recoverability_assessor(&session);
+    let idx = session.index.read();
+    query.run(&idx, assess).map_err(|e| format!("{:#}", e))
+}
+
+const DEFAULT_LOCAL_SEARCH_LIMIT: usize = 20;
+
+// 自然文での検索をこのPCの中だけで行う (一覧を外へ送らない)
+// utc_offset_minutes は JS の -getTimezoneOffset() (省略時は UTC)
+#[tauri::command]
+pub fn local_search_cmd(
+    prompt: String,
+    limit: Option<usize>,
+    utc_offset_minutes: Option<i32>,
+    state: tauri::State<AppState>,
+) -> Result<LocalSearchResult, String> {
+    if prompt.trim().is_empty() {
+        return Err("empty prompt".into());
+    }
+    let session = state
+        .session
+        .read()
+        .clone()
+        .ok_or_else(|| "no scan result".to_string())?;
+    let now = system_time_to_unix_parts(std::time::SystemTime::now()).0;
+    let assess = recoverability_assessor(&session);
+    let idx = session.index.read();
+    Ok(local_search::search(
+        &idx,
+        &prompt,
+        now,
+        i64::from(utc_offset_minutes.unwrap_or(0)) * 60,
+        limit.unwrap_or(DEFAULT_LOCAL_SEARCH_LIMIT).max(1),
+        assess,
+    ))
+}
+
+const DEFAULT_SEARCH_FILES: usize = 50_000;
+
+const PREVIEW_SAMPLES: usize = 5;
+
+// 外部の検索サービスに送る一覧を作って伏せ字にする
+fn search_upload(
+    session: &MountSession,
+    query: Option<Query>,
+    limit: Option<usize>,
+    utc_offset_minutes: Option<i32>,
+    redaction: &RedactionConfig,
+) -> Result<(Vec<SearchFile>, Redactor)> {
+    let mut query = query.unwrap_or_default();
+    query.limit = limit.or(query.limit).or(Some(DEFAULT_SEARCH_FILES));
+    let utc_offset = i64::from(utc_offset_minutes.unwrap_or(0)) * 60;
+    let mut redactor = Redactor::new(redaction)?;
+    // 送る一覧に復元できるかは含めないので $Bitmap は読まない
+    let hits = query
+        .run(&session.index.read(), |_| Recoverability::Unknown)?
+        .hits;
+    let files = hits
+        .iter()
+        .map(|h| redactor.redact(SearchFile::from_hit(h, utc_offset)))
+        .collect();
+    Ok((files, redactor))
+}
+
+#[derive(Serialize)]
+pub struct UploadPreview {
+    pub upload: UploadSummary,
+    pub redaction: RedactMode,
+    // 伏せ字にした箇所の数
+    pub redacted: usize,
+    // 実際に送る内容の先頭数件
+    pub samples: Vec<SearchFile>,
+}
+
+// 送信前の確認用 (何も送らない)
+#[tauri::command]
+pub async fn preview_remote_search_cmd(
+    query: Option<Query>,
+    limit: Option<usize>,
+    utc_offset_minutes: Option<i32>,
+    redaction: Option<RedactionConfig>,
+    state: tauri::State<'_, AppState>,
+) -> Result<UploadPreview, String> {
+    let session = state
+        .session
+        .read()
+        .clone()
+        .ok_or_else(|| "no scan result".to_string())?;
+    let preview = tauri::async_runtime::spawn_blocking(move || -> Result<UploadPreview> {
+        let redaction = redaction.unwrap_or_else(RedactionConfig::from_env);
+        let (mut files, redactor) =
+            search_upload(&session, query, limit, utc_offset_minutes, &redaction)?;
+        let upload = HttpSearchProvider::from_env().summarize(&files)?;
+        files.truncate(PREVIEW_SAMPLES.min(upload.files));
+        Ok(UploadPreview {
+            upload,
+            redaction: redaction.mode,
+            redacted: redactor.redacted(),
+            samples: files,
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    preview.map_err(|e| format!("{:#}", e))
+}
+
+// 自然文での検索を外部のサービスに頼む
+// 接続先等は UNUNLINK_SEARCH_*、伏せ字は UNUNLINK_REDACT_* (redaction を渡すとそちらを優先)
+// 利用者が preview_remote_search_cmd の内容を確認して confirmed を付けたときだけ送る
+// 通信を待つ間に画面が止まらないよう別スレッドで行う
+#[tauri::command]
+pub async fn remote_search_cmd(
+    prompt: String,
+    confirmed: bool,
+    query: Option<Query>,
+    limit: Option<usize>,
+    utc_offset_minutes: Option<i32>,
+    redaction: Option<RedactionConfig>,
+    state: tauri::State<'_, AppState>,
+) -> Result<SearchAnswer, String> {
+    if !confirmed {
+        return Err("upload not confirmed".into());
+    }
+    if prompt.trim().is_empty() {
+        return Err("empty prompt".into());
+    }
+    let session = state
+        .session
+        .read()
+        .clone()
+        .ok_or_else(|| "no scan result".to_string())?;
+    let answer = tauri::async_runtime::spawn_blocking(move || -> Result<SearchAnswer> {
+        let redaction = redaction.unwrap_or_else(RedactionConfig::from_env);
+        let (files, redactor) =
+            search_upload(&session, query, limit, utc_offset_minutes, &redaction)?;
+        info!(
+            redacted = redactor.redacted(),
+            enabled = redactor.enabled(),
+            "file list redacted"
+        );
+        let answer = HttpSearchProvider::from_env().search(&prompt, &files)?;
+        // 伏せ字のまま返ってきたパスを元に戻す
+        Ok(redactor.restore(answer))
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    answer.map_err(|e| format!("{:#}", e))
 }
 
 #[tauri::command]
@@ -330,18 +955,54 @@ pub fn open_path_cmd(path: String) -> Result<(), String> {
         return Err("empty path".into());
     }
     std::process::Command::new("explorer")
-        .arg(&path)
+        .arg(path_from_escaped(&path))
         .spawn()
         .map_err(|e| e.to_string())?;
     Ok(())
 }
 
+// 書き出す前に確かめる (destination を省略するとデスクトップ)
+#[tauri::command]
+pub fn check_destination_cmd(
+    state: tauri::State<AppState>,
+    destination: Option<String>,
+) -> Result<DestinationCheck, String> {
+    let session = state
+        .session
+        .read()
+        .clone()
+        .ok_or_else(|| "no scan result".to_string())?;
+    let dest = match destination {
+        Some(d) => path_from_escaped(&d),
+        None => desktop_dir(),
+    };
+    dest_guard::check(&session.device, &dest).map_err(|e| format!("{:#}", e))
+}
+
+fn desktop_dir() -> PathBuf {
+    let desktop = std::env::var("USERPROFILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("."))
+        .join("Desktop");
+    if desktop.exists() {
+        desktop
+    } else {
+        PathBuf::from(".")
+    }
+}
+
 #[tauri::command]
-pub fn copy_to_desktop_cmd(path: String) -> Result<String, String> {
+pub fn copy_to_desktop_cmd(
+    state: tauri::State<AppState>,
+    path: String,
+    allow_same_volume: Option<bool>,
+    restore_metadata: Option<bool>,
+) -> Result<String, String> {
     if path.trim().is_empty() {
         return Err("empty path".into());
     }
-    let src = Path::new(&path);
+    let src_buf = path_from_escaped(&path);
+    let src = src_buf.as_path();
     if !src.exists() {
         return Err("source not found".into());
     }
@@ -350,36 +1011,145 @@ pub fn copy_to_desktop_cmd(path: String) -> Result<String, String> {
         return Err("directory copy is not supported".into());
     }
 
-    let desktop = std::env::var("USERPROFILE")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| PathBuf::from("."))
-        .join("Desktop");
-    let dst_dir = if desktop.exists() {
-        desktop
-    } else {
-        PathBuf::from(".")
-    };
+    let dst_dir = desktop_dir();
+    let session = state.session.read().clone();
+    if let Some(session) = &session {
+        dest_guard::ensure(&session.device, &dst_dir, allow_same_volume.unwrap_or(false))
+            .map_err(|e| format!("{:#}", e))?;
+    }
 
-    let file_name = src
+    let file_name: Vec<u16> = src
         .file_name()
-        .map(|s| s.to_string_lossy().to_string())
+        .map(|s| s.encode_wide().collect())
         .ok_or_else(|| "invalid file name".to_string())?;
 
-    let mut base = file_name.clone();
-    let mut ext = String::new();
-    if let Some(dot) = file_name.rfind('.') {
-        base = file_name[..dot].to_string();
-        ext = file_name[dot..].to_string();
-    }
-    let mut cand = dst_dir.join(&file_name);
+    // 名前はUTF-16のまま扱い、連番だけを差し込む
+    let dot = file_name
+        .iter()
+        .rposition(|&c| c == b'.' as u16)
+        .unwrap_or(file_name.len());
+    let (base, ext) = file_name.split_at(dot);
+    let mut cand = dst_dir.join(OsString::from_wide(&file_name));
     let mut n = 2usize;
     while cand.exists() {
-        cand = dst_dir.join(format!("{} ({}){}", base, n, ext));
+        let mut numbered = base.to_vec();
+        numbered.extend(format!(" ({})", n).encode_utf16());
+        numbered.extend_from_slice(ext);
+        cand = dst_dir.join(OsString::from_wide(&numbered));
         n += 1;
     }
 
     std::fs::copy(src, &cand).map_err(|e| e.to_string())?;
-    Ok(cand.to_string_lossy().to_string())
+    if restore_metadata.unwrap_or_else(file_meta::enabled_by_default) {
+        let meta = session.and_then(|s| {
+            let idx = s.index.read();
+            let id = recover::resolve(&idx, &path)?;
+            let entry = idx.entry(id);
+            entry.as_deref().and_then(OriginalMeta::from_entry)
+        });
+        if let Some(meta) = meta {
+            if let Err(e) = file_meta::apply(&cand, &meta) {
+                warn!(error = %format!("{:#}", e), "failed to restore file times");
+            }
+        }
+    }
+    Ok(escape_os(cand.as_os_str()))
+}
+
+// 選んだファイル/フォルダ/検索結果をまとめて destination 以下に書き出す
+// 経過は "recover" イベントで送る (recover::RecoverEvent)。戻り値は書き出す件数
+#[tauri::command]
+pub fn start_recovery_cmd(
+    app: AppHandle,
+    state: tauri::State<AppState>,
+    selection: Selection,
+    destination: String,
+    conflict: Option<ConflictPolicy>,
+    allow_same_volume: Option<bool>,
+    restore_metadata: Option<bool>,
+) -> Result<usize, String> {
+    if state.recovering.load(Ordering::Relaxed) {
+        return Err("a recovery is already running".into());
+    }
+    if destination.trim().is_empty() {
+        return Err("empty destination".into());
+    }
+    let session = state
+        .session
+        .read()
+        .clone()
+        .ok_or_else(|| "no scan result".to_string())?;
+    let items =
+        recover::plan(&session.index.read(), &selection).map_err(|e| format!("{:#}", e))?;
+    let dest = path_from_escaped(&destination);
+    dest_guard::ensure(&session.device, &dest, allow_same_volume.unwrap_or(false))
+        .map_err(|e| format!("{:#}", e))?;
+    let job = Recovery::create(
+        &dest,
+        &session.device,
+        conflict.unwrap_or_default(),
+        restore_metadata.unwrap_or_else(file_meta::enabled_by_default),
+        items,
+    )
+    .map_err(|e| format!("{:#}", e))?;
+    spawn_recovery(&app, &state, job)
+}
+
+// 取り消した/失敗が残った復元を続きからやり直す
+#[tauri::command]
+pub fn resume_recovery_cmd(
+    app: AppHandle,
+    state: tauri::State<AppState>,
+    destination: String,
+    allow_same_volume: Option<bool>,
+) -> Result<usize, String> {
+    if state.recovering.load(Ordering::Relaxed) {
+        return Err("a recovery is already running".into());
+    }
+    let dest = path_from_escaped(&destination);
+    let job = Recovery::open(&dest).map_err(|e| format!("{:#}", e))?;
+    dest_guard::ensure(job.device(), &dest, allow_same_volume.unwrap_or(false))
+        .map_err(|e| format!("{:#}", e))?;
+    spawn_recovery(&app, &state, job)
+}
+
+#[tauri::command]
+pub fn cancel_recovery_cmd() {
+    RECOVER_CANCEL.store(true, Ordering::Relaxed);
+}
+
+fn spawn_recovery(app: &AppHandle, state: &AppState, mut job: Recovery) -> Result<usize, String> {
+    if state.recovering.swap(true, Ordering::SeqCst) {
+        return Err("a recovery is already running".into());
+    }
+    RECOVER_CANCEL.store(false, Ordering::Relaxed);
+    // スナップショットの後にクラスタが使われたファイルは書き出さない
+    if let Some(session) = state.session.read().as_ref() {
+        if session.device == job.device() {
+            job.withhold(session.withheld.clone());
+        }
+    }
+    let total = job.len();
+    let app = app.clone();
+    std::thread::spawn(move || {
+        let res = MftStream::open(job.device())
+            .context("failed to read $MFT layout")
+            .and_then(|stream| {
+                job.run(&stream, |ev| {
+                    let _ = app.emit_all("recover", ev);
+                })
+            });
+        app.state::<AppState>()
+            .recovering
+            .store(false, Ordering::SeqCst);
+        if let Err(e) = res {
+            let _ = app.emit_all(
+                "recover",
+                serde_json::json!({"event": "error", "error": format!("{:#}", e)}),
+            );
+        }
+    });
+    Ok(total)
 }
 
 #[tauri::command]
@@ -387,24 +1157,34 @@ pub fn reveal_in_explorer_cmd(path: String) -> Result<(), String> {
     if path.trim().is_empty() {
         return Err("empty path".into());
     }
-    let p = Path::new(&path);
+    let p = path_from_escaped(&path);
     if !p.exists() {
         return Err("path not found".into());
     }
-    let display_path = match p.canonicalize() {
-        Ok(c) => {
-            let mut s = c.to_string_lossy().to_string();
-            if let Some(stripped) = s.strip_prefix(r"\\?\") {
-                s = stripped.to_string();
-            }
-            s
-        }
-        Err(_) => path.clone(),
+    let mut target: Vec<u16> = match p.canonicalize() {
+        Ok(c) => c.as_os_str().encode_wide().collect(),
+        Err(_) => p.as_os_str().encode_wide().collect(),
     };
+    let verbatim: Vec<u16> = r"\\?\".encode_utf16().collect();
+    if target.starts_with(&verbatim) {
+        target.drain(..verbatim.len());
+    }
+    let mut arg: Vec<u16> = "/select,".encode_utf16().collect();
+    arg.extend_from_slice(&target);
 
     std::process::Command::new("explorer")
-        .arg(format!("/select,{}", display_path))
+        .arg(OsString::from_wide(&arg))
         .spawn()
         .map_err(|e| e.to_string())?;
     Ok(())
 }
+
+// GUIとの間でやり取りするパスは util::escape_u16 の形式
+fn escape_os(s: &OsStr) -> String {
+    let wide: Vec<u16> = s.encode_wide().collect();
+    escape_u16(&wide)
+}
+
+fn path_from_escaped(s: &str) -> PathBuf {
+    PathBuf::from(OsString::from_wide(&unescape_u16(s)))
+}
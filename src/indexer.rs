@@ -1,184 +1,1622 @@
+use serde::Serialize;
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
-use std::time::SystemTime;
-use widestring::{U16CStr, U16Str, U16String};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use widestring::{U16Str, U16String};
 
-use crate::util::{
-    normalize_and_canonicalize_for_key, normalize_candidate_path, unix_ts_to_system_time,
+use crate::filetype::{sniff, ContentType, FileFamily};
+use crate::mft_stream::{DirInfo, DirTable};
+use crate::record::ROOT_RECORD;
+use crate::scan::{LIVE_DIR, ORPHANS_DIR, REUSED_PARENT_DIR};
+use crate::spill::{
+    decode_meta, disk_io, encode_meta, FixedRecord, PagedFile, SpillBudget, SpillStore, SpillVec,
 };
+use crate::timestamps::{filetime_to_civil, RecordTimes};
+use crate::upcase::{self, UpCaseTable};
 
 #[derive(Debug, Clone)]
 pub struct EntryMeta {
     pub mft_no: u64,
+    // 読んだときのシーケンス番号 (後で読み直したレコードが別のファイルのものでないか確かめる)
+    pub sequence: u16,
     pub is_dir: bool,
     pub size: u64,
-    pub created: Option<SystemTime>,
-    pub modified: Option<SystemTime>,
-    pub accessed: Option<SystemTime>,
-    pub name_u16: U16String,
+    // レコードに書かれていた親参照 (シーケンス番号込み) とその検証結果
+    pub parent_ref: u64,
+    pub parent_status: ParentStatus,
+    // SI と全ての FN の時刻 (FILETIME のまま、変換は表示する側で行う)
+    pub times: RecordTimes,
+    // ハードリンクの数 (同じレコードを指す名前の数)
+    pub link_count: u16,
+    // $STANDARD_INFORMATION のファイル属性
+    pub attributes: u32,
+    // 削除されていないファイル (\$Live 以下) なら true
+    pub live: bool,
+    // 同じパスにある削除されていないファイルとの比較結果
+    pub live_match: LiveMatch,
+    // 中身から判定した種類 (判定していない/分からなければ None)
+    pub content_type: Option<ContentType>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LiveMatch {
+    // 比較していない (削除されていないファイルを取り込まなかった場合など)
+    NotChecked,
+    // 同じパスにファイルがない
+    Absent,
+    // 同じパスにあるが大きさか更新時刻が違う
+    Differs,
+    // 同じパスに大きさも更新時刻も同じファイルがある
+    Identical,
+}
+
+// 親ディレクトリをシーケンス番号で確かめた結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParentStatus {
+    // ルートまで辿れた
+    Resolved,
+    // 途中のレコードが見つからない (\$Orphans 以下に置く)
+    Orphan,
+    // 親 (または途中の祖先) のレコードが別物に再利用されている (\$ReusedParent 以下に置く)
+    Reused,
+}
+
+// ディレクトリの情報は、元のレコードが分かった場合だけ持つ
 #[derive(Debug, Clone)]
 pub enum EntryOrDir {
+    Dir(Option<Box<EntryMeta>>),
+    File(Box<EntryMeta>),
+}
+
+pub type NodeId = u32;
+pub const ROOT_NODE: NodeId = 0;
+const NIL: u32 = u32::MAX;
+
+// 同じ元のパスを持つファイルを版としてまとめて見せるフォルダ
+pub const VERSIONS_DIR: &str = "$Versions";
+
+// 同じフォルダに同じ名前のファイルが複数あったときの名前の付け方
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DupNaming {
+    // file_2.txt, file_3.txt
+    #[default]
+    Numeric,
+    // file [mft 12345].txt
+    MftNumber,
+    // file [2024-08-01 123005].txt (更新時刻、UTC)
+    Timestamp,
+}
+
+// 重複した名前を付け直すときの並び順 (先頭が元の名前のまま残る)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DupOrder {
+    // MFTレコード番号の小さい順
+    #[default]
+    MftNumber,
+    // 更新時刻の古い順
+    Modified,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DupPolicy {
+    pub naming: DupNaming,
+    pub order: DupOrder,
+}
+
+// 種類/日付/大きさで分ける仮想フォルダ
+pub const BY_TYPE_DIR: &str = "$ByType";
+pub const BY_DATE_DIR: &str = "$ByDate";
+pub const BY_SIZE_DIR: &str = "$BySize";
+
+// 仮想フォルダ1つ分 (月は 年*12 + (月-1)、時刻が無ければ None)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum ViewKey {
+    Type(FileFamily),
+    Modified(Option<i64>),
+    // 削除時刻はNTFSに残らないので、レコードの最終変更時刻 ($MFT changed) で近似する
+    Deleted(Option<i64>),
+    Size(SizeBucket),
+}
+
+impl ViewKey {
+    fn folder_path(self) -> Vec<String> {
+        let month = |m: Option<i64>| match m {
+            Some(m) => format!("{:04}-{:02}", m.div_euclid(12), m.rem_euclid(12) + 1),
+            None => "Unknown".to_string(),
+        };
+        match self {
+            ViewKey::Type(f) => vec![BY_TYPE_DIR.into(), f.folder_name().into()],
+            ViewKey::Modified(m) => vec![BY_DATE_DIR.into(), "Modified".into(), month(m)],
+            ViewKey::Deleted(m) => vec![BY_DATE_DIR.into(), "Deleted".into(), month(m)],
+            ViewKey::Size(b) => vec![BY_SIZE_DIR.into(), b.folder_name().into()],
+        }
+    }
+}
+
+fn month_of(ft: u64) -> Option<i64> {
+    filetime_to_civil(ft).map(|t| t.year * 12 + t.month as i64 - 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum SizeBucket {
+    Empty,
+    Tiny,
+    Small,
+    Medium,
+    Large,
+    Huge,
+}
+
+impl SizeBucket {
+    fn of(size: u64) -> Self {
+        match size {
+            0 => SizeBucket::Empty,
+            1..=0xFFF => SizeBucket::Tiny,
+            0x1000..=0xF_FFFF => SizeBucket::Small,
+            0x10_0000..=0x63F_FFFF => SizeBucket::Medium,
+            0x640_0000..=0x3FFF_FFFF => SizeBucket::Large,
+            _ => SizeBucket::Huge,
+        }
+    }
+
+    // 名前順に並べたときに小さい順になるよう番号を付ける
+    fn folder_name(self) -> &'static str {
+        match self {
+            SizeBucket::Empty => "0 empty",
+            SizeBucket::Tiny => "1 under 4 KB",
+            SizeBucket::Small => "2 4 KB - 1 MB",
+            SizeBucket::Medium => "3 1 MB - 100 MB",
+            SizeBucket::Large => "4 100 MB - 1 GB",
+            SizeBucket::Huge => "5 over 1 GB",
+        }
+    }
+}
+
+// 名前を付け直す1件分の情報
+struct DupInfo {
+    number: u32,
+    mft_no: u64,
+    modified: u64,
+}
+
+// ノードの実体
+// 予算 (metadata_budget) を超えた後のファイル情報は一時ファイルに置き、オフセットだけを持つ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IndexSlot {
+    // 元のレコードが分からないディレクトリ
     Dir,
-    File(EntryMeta),
+    // metas の番号
+    Resident(u32),
+    // 一時ファイル上の位置
+    Spilled { offset: u64, dir: bool },
+    // 別のノードと同じファイル (\$Versions 等の仮想フォルダ用)
+    Link(NodeId),
+}
+
+// 木構造のノード
+// 子は兄弟リスト。名前の索引は (親, 名前ハッシュ) のバケットから hash_next で繋ぐ
+// 8.3形式の短い名前を持つノードは、短い名前の索引にも short_next で繋ぐ
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    parent: NodeId,
+    name: NameId,
+    first_child: NodeId,
+    last_child: NodeId,
+    next_sibling: NodeId,
+    hash_next: NodeId,
+    short: NameId,
+    short_next: NodeId,
+    slot: IndexSlot,
+}
+
+impl FixedRecord for Node {
+    const SIZE: usize = 41;
+    fn put(&self, out: &mut [u8]) {
+        let ids = [
+            self.parent,
+            self.name,
+            self.first_child,
+            self.last_child,
+            self.next_sibling,
+            self.hash_next,
+            self.short,
+            self.short_next,
+        ];
+        for (i, v) in ids.iter().enumerate() {
+            out[i * 4..i * 4 + 4].copy_from_slice(&v.to_le_bytes());
+        }
+        let (kind, value) = match self.slot {
+            IndexSlot::Dir => (0, 0),
+            IndexSlot::Resident(i) => (1, i as u64),
+            IndexSlot::Spilled { offset, dir: false } => (2, offset),
+            IndexSlot::Spilled { offset, dir: true } => (3, offset),
+            IndexSlot::Link(t) => (4, t as u64),
+        };
+        out[32] = kind;
+        out[33..41].copy_from_slice(&value.to_le_bytes());
+    }
+    fn get(b: &[u8]) -> Self {
+        let id = |i: usize| u32::get(&b[i * 4..i * 4 + 4]);
+        let mut v = [0u8; 8];
+        v.copy_from_slice(&b[33..41]);
+        let value = u64::from_le_bytes(v);
+        let slot = match b[32] {
+            0 => IndexSlot::Dir,
+            1 => IndexSlot::Resident(value as u32),
+            2 => IndexSlot::Spilled {
+                offset: value,
+                dir: false,
+            },
+            3 => IndexSlot::Spilled {
+                offset: value,
+                dir: true,
+            },
+            _ => IndexSlot::Link(value as u32),
+        };
+        Node {
+            parent: id(0),
+            name: id(1),
+            first_child: id(2),
+            last_child: id(3),
+            next_sibling: id(4),
+            hash_next: id(5),
+            short: id(6),
+            short_next: id(7),
+            slot,
+        }
+    }
+}
+
+// 名前の索引 (長い名前 / 短い名前)
+#[derive(Debug, Clone, Copy)]
+enum Chain {
+    Long,
+    Short,
+}
+
+impl Node {
+    fn name_in(&self, chain: Chain) -> NameId {
+        match chain {
+            Chain::Long => self.name,
+            Chain::Short => self.short,
+        }
+    }
+
+    fn next_in(&self, chain: Chain) -> NodeId {
+        match chain {
+            Chain::Long => self.hash_next,
+            Chain::Short => self.short_next,
+        }
+    }
+
+    fn set_next_in(&mut self, chain: Chain, next: NodeId) {
+        match chain {
+            Chain::Long => self.hash_next = next,
+            Chain::Short => self.short_next = next,
+        }
+    }
+}
+
+type NameId = u32;
+
+// 名前の位置 (メモリの名前は buf の、一時ファイルの名前はファイルの先頭からの u16 単位)
+#[derive(Debug, Clone, Copy)]
+struct NameSpan {
+    off: u64,
+    len: u16,
+}
+
+impl FixedRecord for NameSpan {
+    const SIZE: usize = 10;
+    fn put(&self, out: &mut [u8]) {
+        out[..8].copy_from_slice(&self.off.to_le_bytes());
+        out[8..10].copy_from_slice(&self.len.to_le_bytes());
+    }
+    fn get(b: &[u8]) -> Self {
+        let mut off = [0u8; 8];
+        off.copy_from_slice(&b[..8]);
+        NameSpan {
+            off: u64::from_le_bytes(off),
+            len: u16::from_le_bytes([b[8], b[9]]),
+        }
+    }
 }
 
+// UTF-16の名前を1箇所にまとめて保持する (同じ名前は共有)
+// 一時ファイルに置き始めた後の名前は共有せずに足していく (next を持つのはメモリの名前だけ)
 #[derive(Default)]
+struct NameTable {
+    buf: Vec<u16>,
+    disk: Option<PagedFile>,
+    disk_len: u64,
+    spans: SpillVec<NameSpan>,
+    by_hash: HashMap<u64, NameId>,
+    next: Vec<NameId>,
+}
+
+impl NameTable {
+    fn get(&self, id: NameId) -> Cow<'_, [u16]> {
+        let span = self.spans.get(id as usize);
+        if (id as usize) < self.next.len() {
+            let off = span.off as usize;
+            return Cow::Borrowed(&self.buf[off..off + span.len as usize]);
+        }
+        let mut bytes = vec![0u8; span.len as usize * 2];
+        if let Some(d) = &self.disk {
+            disk_io(d.read(span.off * 2, &mut bytes));
+        }
+        Cow::Owned(
+            bytes
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect(),
+        )
+    }
+
+    fn intern(&mut self, name: &[u16]) -> (NameId, bool) {
+        let name = &name[..std::cmp::min(name.len(), u16::MAX as usize)];
+        let h = hash_exact(name);
+        let mut cur = self.by_hash.get(&h).copied().unwrap_or(NIL);
+        while cur != NIL {
+            if *self.get(cur) == *name {
+                return (cur, false);
+            }
+            cur = self.next[cur as usize];
+        }
+        let id = self.spans.len() as NameId;
+        if let Some(d) = &self.disk {
+            let bytes: Vec<u8> = name.iter().flat_map(|c| c.to_le_bytes()).collect();
+            disk_io(d.write(self.disk_len * 2, &bytes));
+            self.spans.push(NameSpan {
+                off: self.disk_len,
+                len: name.len() as u16,
+            });
+            self.disk_len += name.len() as u64;
+            return (id, true);
+        }
+        self.spans.push(NameSpan {
+            off: self.buf.len() as u64,
+            len: name.len() as u16,
+        });
+        self.buf.extend_from_slice(name);
+        let prev = self.by_hash.insert(h, id).unwrap_or(NIL);
+        self.next.push(prev);
+        (id, true)
+    }
+
+    fn spill_to(&mut self, chars: PagedFile, spans: PagedFile) {
+        self.buf.shrink_to_fit();
+        self.disk = Some(chars);
+        self.spans.spill_to(spans);
+    }
+}
+
+// (親, 名前ハッシュ) ごとのバケット。中身は同じバケットの先頭のノード
+// ノードの数を超えたら倍にして作り直す
+struct Buckets {
+    heads: SpillVec<NodeId>,
+    count: usize,
+}
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Self {
+            heads: SpillVec::filled(16, NIL, None),
+            count: 0,
+        }
+    }
+}
+
+impl Buckets {
+    fn of(&self, parent: NodeId, name_hash: u64) -> usize {
+        let h = name_hash ^ (parent as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        (h ^ (h >> 32)) as usize & (self.heads.len() - 1)
+    }
+}
+
 pub struct DeletedIndex {
-    pub nodes: HashMap<String, EntryOrDir>,
-    pub children_names: HashMap<String, Vec<U16String>>,
-    pub children_ci: HashMap<String, HashSet<String>>,
+    nodes: SpillVec<Node>,
+    names: NameTable,
+    // メモリに置いたエントリ情報 (IndexSlot::Resident の番号)
+    metas: Vec<EntryOrDir>,
+    long_names: Buckets,
+    // 8.3形式の短い名前 (Dokanに短い名前のパスで開かれたときに引く)
+    short_names: Buckets,
+    // 同じ名前の子が複数できた (親, 名前ハッシュ)。スキャン後に finalize_names で付け直す
+    pending_dups: HashSet<(NodeId, u64)>,
+    // 同名のファイルがあって連番を付けたファイルの元の名前
+    orig_names: HashMap<NodeId, NameId>,
+    upcase: Arc<UpCaseTable>,
+    metadata_budget: Option<SpillBudget>,
+    approx_bytes: u64,
+    spill: Option<SpillStore>,
+}
+
+impl Default for DeletedIndex {
+    fn default() -> Self {
+        Self::new(None, upcase::active())
+    }
 }
 
 // 削除済みファイルのインデックス構築
 impl DeletedIndex {
-    pub fn ensure_dirs_from_root(&mut self, dir_path_str: &str) {
-        let norm = normalize_candidate_path(dir_path_str);
-        let comps: Vec<&str> = norm.split('\\').filter(|s| !s.is_empty()).collect();
-
-        let mut parent_key = normalize_and_canonicalize_for_key("\\");
-        self.nodes
-            .entry(parent_key.clone())
-            .or_insert(EntryOrDir::Dir);
-        self.children_names
-            .entry(parent_key.clone())
-            .or_insert_with(Vec::new);
-        self.children_ci
-            .entry(parent_key.clone())
-            .or_insert_with(HashSet::new);
-
-        let mut acc = String::from("\\");
+    pub fn new(metadata_budget: Option<SpillBudget>, upcase: Arc<UpCaseTable>) -> Self {
+        let mut names = NameTable::default();
+        let (root_name, _) = names.intern(&[]);
+        let mut nodes = SpillVec::default();
+        nodes.push(Node {
+            parent: ROOT_NODE,
+            name: root_name,
+            first_child: NIL,
+            last_child: NIL,
+            next_sibling: NIL,
+            hash_next: NIL,
+            short: NIL,
+            short_next: NIL,
+            slot: IndexSlot::Dir,
+        });
+        Self {
+            nodes,
+            names,
+            metas: Vec::new(),
+            long_names: Buckets::default(),
+            short_names: Buckets::default(),
+            pending_dups: HashSet::new(),
+            orig_names: HashMap::new(),
+            upcase,
+            metadata_budget,
+            approx_bytes: 0,
+            spill: None,
+        }
+    }
+
+    pub fn upcase(&self) -> &UpCaseTable {
+        &self.upcase
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn node(&self, id: NodeId) -> Node {
+        self.nodes.get(id as usize)
+    }
+
+    fn update_node(&mut self, id: NodeId, f: impl FnOnce(&mut Node)) {
+        let mut n = self.node(id);
+        f(&mut n);
+        self.nodes.set(id as usize, n);
+    }
+
+    fn add_child(&mut self, parent: NodeId, name: &[u16], slot: IndexSlot) -> NodeId {
+        self.spill_if_over();
+        let (name_id, fresh) = self.names.intern(name);
+        let id = self.nodes.len() as NodeId;
+        self.nodes.push(Node {
+            parent,
+            name: name_id,
+            first_child: NIL,
+            last_child: NIL,
+            next_sibling: NIL,
+            hash_next: NIL,
+            short: NIL,
+            short_next: NIL,
+            slot,
+        });
+        match self.node(parent).last_child {
+            NIL => self.update_node(parent, |p| p.first_child = id),
+            last => self.update_node(last, |n| n.next_sibling = id),
+        }
+        self.update_node(parent, |p| p.last_child = id);
+        self.link(Chain::Long, id);
+
+        // メモリに置いたノードとバケット1つ分、名前
+        if !self.nodes.is_spilled() {
+            self.approx_bytes += (Node::SIZE + 8) as u64;
+            if fresh {
+                self.approx_bytes += (name.len() * 2 + 24) as u64;
+            }
+        }
+        id
+    }
+
+    fn buckets(&self, chain: Chain) -> &Buckets {
+        match chain {
+            Chain::Long => &self.long_names,
+            Chain::Short => &self.short_names,
+        }
+    }
+
+    fn buckets_mut(&mut self, chain: Chain) -> &mut Buckets {
+        match chain {
+            Chain::Long => &mut self.long_names,
+            Chain::Short => &mut self.short_names,
+        }
+    }
+
+    fn chain_hash(&self, chain: Chain, node: &Node) -> u64 {
+        self.upcase.hash(&self.names.get(node.name_in(chain)))
+    }
+
+    // ノードを索引のバケットの先頭に繋ぐ
+    fn link(&mut self, chain: Chain, id: NodeId) {
+        let node = self.node(id);
+        let b = self
+            .buckets(chain)
+            .of(node.parent, self.chain_hash(chain, &node));
+        let head = self.buckets(chain).heads.get(b);
+        self.update_node(id, |n| n.set_next_in(chain, head));
+        let buckets = self.buckets_mut(chain);
+        buckets.heads.set(b, id);
+        buckets.count += 1;
+        if buckets.count > buckets.heads.len() {
+            self.rebuild_buckets(chain);
+        }
+    }
+
+    // 索引から外す (名前を変える前に呼ぶ)
+    fn unlink(&mut self, chain: Chain, id: NodeId) {
+        let node = self.node(id);
+        let b = self
+            .buckets(chain)
+            .of(node.parent, self.chain_hash(chain, &node));
+        let next = node.next_in(chain);
+        let mut cur = self.buckets(chain).heads.get(b);
+        if cur == id {
+            self.buckets_mut(chain).heads.set(b, next);
+        } else {
+            while cur != NIL && self.node(cur).next_in(chain) != id {
+                cur = self.node(cur).next_in(chain);
+            }
+            if cur == NIL {
+                return;
+            }
+            self.update_node(cur, |n| n.set_next_in(chain, next));
+        }
+        self.buckets_mut(chain).count -= 1;
+    }
+
+    // バケットを倍にして繋ぎ直す (一時ファイルに置き始めた後ならバケットも一時ファイルに置く)
+    fn rebuild_buckets(&mut self, chain: Chain) {
+        let disk = match &self.spill {
+            Some(s) => match s.sibling("buckets") {
+                Ok(f) => Some(f),
+                Err(e) => {
+                    tracing::warn!(error = %e, "failed to create spill file for name buckets");
+                    None
+                }
+            },
+            None => None,
+        };
+        let size = self.buckets(chain).heads.len() * 2;
+        let mut buckets = Buckets {
+            heads: SpillVec::filled(size, NIL, disk),
+            count: 0,
+        };
+        for id in 1..self.nodes.len() as NodeId {
+            let node = self.node(id);
+            if node.name_in(chain) == NIL {
+                continue;
+            }
+            let b = buckets.of(node.parent, self.chain_hash(chain, &node));
+            let head = buckets.heads.get(b);
+            self.update_node(id, |n| n.set_next_in(chain, head));
+            buckets.heads.set(b, id);
+            buckets.count += 1;
+        }
+        *self.buckets_mut(chain) = buckets;
+    }
+
+    // 大文字小文字を区別せずに子を探す (見つからなければ短い名前でも探す)
+    pub fn find_child(&self, parent: NodeId, name: &[u16]) -> Option<NodeId> {
+        self.find_long_child(parent, name)
+            .or_else(|| self.find_short_child(parent, name))
+    }
+
+    fn find_long_child(&self, parent: NodeId, name: &[u16]) -> Option<NodeId> {
+        self.find_in(Chain::Long, parent, name, |_| true)
+    }
+
+    fn find_long_child_by(
+        &self,
+        parent: NodeId,
+        name: &[u16],
+        pred: impl Fn(NodeId) -> bool,
+    ) -> Option<NodeId> {
+        self.find_in(Chain::Long, parent, name, pred)
+    }
+
+    fn find_short_child(&self, parent: NodeId, name: &[u16]) -> Option<NodeId> {
+        self.find_in(Chain::Short, parent, name, |_| true)
+    }
+
+    // 索引のバケットを辿って、親と名前 (大文字小文字は無視) が合い pred を満たすノードを探す
+    fn find_in(
+        &self,
+        chain: Chain,
+        parent: NodeId,
+        name: &[u16],
+        pred: impl Fn(NodeId) -> bool,
+    ) -> Option<NodeId> {
+        let buckets = self.buckets(chain);
+        let mut cur = buckets
+            .heads
+            .get(buckets.of(parent, self.upcase.hash(name)));
+        while cur != NIL {
+            let n = self.node(cur);
+            if n.parent == parent
+                && self.upcase.eq(&self.names.get(n.name_in(chain)), name)
+                && pred(cur)
+            {
+                return Some(cur);
+            }
+            cur = n.next_in(chain);
+        }
+        None
+    }
+
+    // 8.3形式の名前を別名として登録する
+    // 同じフォルダに同じ長い名前/短い名前が既にあれば先に登録された方を優先
+    fn add_short_name(&mut self, node: NodeId, short: &[u16]) {
+        let n = self.node(node);
+        if short.is_empty()
+            || self.upcase.eq(short, &self.names.get(n.name))
+            || self.find_long_child(n.parent, short).is_some()
+            || self.find_short_child(n.parent, short).is_some()
+        {
+            return;
+        }
+        let (name_id, _) = self.names.intern(short);
+        self.update_node(node, |n| n.short = name_id);
+        self.link(Chain::Short, node);
+        self.approx_bytes += (short.len() * 2 + 8) as u64;
+    }
+
+    // 同じ名前のファイルがあってもディレクトリはその名前のまま作る (ファイルの方を後で付け直す)
+    fn ensure_dir_comps(&mut self, comps: &[&[u16]]) -> NodeId {
+        let mut parent = ROOT_NODE;
         for comp in comps {
-            let comp_u16 = U16String::from_str(comp);
-            let lower = comp_u16.to_string_lossy().to_lowercase();
-            let names = self
-                .children_names
-                .entry(parent_key.clone())
-                .or_insert_with(Vec::new);
-            let set = self
-                .children_ci
-                .entry(parent_key.clone())
-                .or_insert_with(HashSet::new);
-            if set.insert(lower) {
-                names.push(comp_u16.clone());
-            }
-            if acc == "\\" {
-                acc = format!("\\{}", comp);
-            } else {
-                acc = format!("{}\\{}", acc, comp);
-            }
-            // エントリを正規化してから処理
-            let dir_key = normalize_and_canonicalize_for_key(&acc);
-            self.nodes.entry(dir_key.clone()).or_insert(EntryOrDir::Dir);
-            self.children_names
-                .entry(dir_key.clone())
-                .or_insert_with(Vec::new);
-            self.children_ci
-                .entry(dir_key.clone())
-                .or_insert_with(HashSet::new);
-            parent_key = dir_key;
-        }
-    }
-
-    // 同じ名前の削除済みファイルがあった場合、連番を付けてユニークな名前を生成
-    fn unique_child_name(&mut self, parent_key: &str, desired: &U16String) -> U16String {
-        let set = self
-            .children_ci
-            .entry(parent_key.to_string())
-            .or_insert_with(HashSet::new);
-        let names = self
-            .children_names
-            .entry(parent_key.to_string())
-            .or_insert_with(Vec::new);
-        let mut candidate = desired.clone();
-        let mut lower = candidate.to_string_lossy().to_lowercase();
-        if set.contains(&lower) {
-            let mut n = 2u32;
-            loop {
-                let s = candidate.to_string_lossy();
-                if let Some(dot) = s.rfind('.') {
-                    if dot > 0 {
-                        let (base, ext) = s.split_at(dot);
-                        // 例: file.txt -> file_2.txt
-                        candidate = U16String::from_str(&format!("{base}_{n}{ext}"));
-                    } else {
-                        candidate = U16String::from_str(&format!("{}_{}", s, n));
+            parent = match self.find_long_child_by(parent, comp, |id| self.is_dir(id)) {
+                Some(id) => id,
+                None => self.add_unfinalized_child(parent, comp, IndexSlot::Dir),
+            };
+        }
+        parent
+    }
+
+    // 名前が重なっていれば記録しておいて、そのまま追加する
+    fn add_unfinalized_child(&mut self, parent: NodeId, name: &[u16], slot: IndexSlot) -> NodeId {
+        if self.find_long_child(parent, name).is_some() {
+            self.pending_dups.insert((parent, self.upcase.hash(name)));
+        }
+        self.add_child(parent, name, slot)
+    }
+
+    // 同じ名前のファイルがあった場合に、方針に従ってユニークな名前を生成
+    // 例: file.txt -> file_2.txt / file [mft 12345].txt / file [2024-08-01 123005].txt
+    // それでも重なる場合は後ろに連番を足す
+    fn unique_child_name(
+        &self,
+        parent: NodeId,
+        desired: &[u16],
+        naming: DupNaming,
+        dup: &DupInfo,
+    ) -> Vec<u16> {
+        let dot = desired
+            .iter()
+            .rposition(|&c| c == b'.' as u16)
+            .filter(|&d| d > 0)
+            .unwrap_or(desired.len());
+        let (base, ext) = desired.split_at(dot);
+        let with_tag = |tag: &str| {
+            let mut candidate = base.to_vec();
+            candidate.extend(tag.encode_utf16());
+            candidate.extend_from_slice(ext);
+            candidate
+        };
+        let tag = match (naming, filetime_to_civil(dup.modified)) {
+            (DupNaming::Numeric, _) => String::new(),
+            (DupNaming::Timestamp, Some(t)) => format!(
+                " [{:04}-{:02}-{:02} {:02}{:02}{:02}]",
+                t.year, t.month, t.day, t.hour, t.minute, t.second
+            ),
+            // 時刻が無ければレコード番号にする
+            (DupNaming::MftNumber, _) | (DupNaming::Timestamp, None) => {
+                format!(" [mft {}]", dup.mft_no)
+            }
+        };
+        let mut n = if tag.is_empty() { dup.number } else { 1 };
+        loop {
+            let candidate = match n {
+                1 => with_tag(&tag),
+                _ => with_tag(&format!("{}_{}", tag, n)),
+            };
+            if self.find_long_child(parent, &candidate).is_none() {
+                return candidate;
+            }
+            n += 1;
+        }
+    }
+
+    // パスはUTF-16のまま分解する (文字列への変換で名前を壊さないように)
+    // short_name は最後のコンポーネントの8.3形式の名前
+    pub fn insert_file(
+        &mut self,
+        full_path_u16: &U16Str,
+        short_name: Option<&U16Str>,
+        meta: EntryMeta,
+    ) {
+        let comps = split_components(full_path_u16.as_slice());
+        let Some((base, dirs)) = comps.split_last() else {
+            return;
+        };
+        let parent = self.ensure_dir_comps(dirs);
+        let slot = self.slot_for_file(meta);
+        let id = self.add_unfinalized_child(parent, base, slot);
+        if let Some(short) = short_name {
+            self.add_short_name(id, short.as_slice());
+        }
+    }
+
+    // 予算を超えたら、以降に足すノード、名前、エントリ情報を一時ファイルに置く
+    // それまでに作った分はメモリに置いたまま
+    fn spill_if_over(&mut self) {
+        let over = matches!(&self.metadata_budget, Some(b) if self.approx_bytes > b.bytes);
+        if !over || self.spill.is_some() {
+            return;
+        }
+        let Some(dir) = self.metadata_budget.as_ref().and_then(|b| b.dir.clone()) else {
+            tracing::warn!(
+                approx_bytes = self.approx_bytes,
+                "index budget exceeded, but there is no spill directory; keeping the index in memory"
+            );
+            self.metadata_budget = None;
+            return;
+        };
+        let files = SpillStore::create(&dir).and_then(|store| {
+            let nodes = store.sibling("nodes")?;
+            let chars = store.sibling("names")?;
+            let spans = store.sibling("spans")?;
+            Ok((store, nodes, chars, spans))
+        });
+        match files {
+            Ok((store, nodes, chars, spans)) => {
+                tracing::info!(
+                    approx_bytes = self.approx_bytes,
+                    dir = %dir.display(),
+                    "index budget exceeded, spilling the index to disk"
+                );
+                self.nodes.spill_to(nodes);
+                self.names.spill_to(chars, spans);
+                self.metas.shrink_to_fit();
+                self.spill = Some(store);
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "failed to create spill file");
+                self.metadata_budget = None;
+            }
+        }
+    }
+
+    // エントリ情報の置き場所 (予算を超えていれば一時ファイル)
+    fn slot_for(&mut self, entry: EntryOrDir) -> IndexSlot {
+        self.spill_if_over();
+        if let Some(spill) = self.spill.as_mut() {
+            let (meta, dir) = match &entry {
+                EntryOrDir::File(m) => (m, false),
+                EntryOrDir::Dir(Some(m)) => (m, true),
+                EntryOrDir::Dir(None) => return IndexSlot::Dir,
+            };
+            match spill.append(meta) {
+                Ok(offset) => return IndexSlot::Spilled { offset, dir },
+                Err(e) => tracing::warn!(error = %e, "failed to spill index entry"),
+            }
+        }
+        self.approx_bytes += std::mem::size_of::<EntryMeta>() as u64;
+        self.metas.push(entry);
+        IndexSlot::Resident(self.metas.len() as u32 - 1)
+    }
+
+    fn slot_for_file(&mut self, meta: EntryMeta) -> IndexSlot {
+        self.slot_for(EntryOrDir::File(Box::new(meta)))
+    }
+
+    pub fn insert_dir(
+        &mut self,
+        full_dir_u16: &U16Str,
+        short_name: Option<&U16Str>,
+        meta: EntryMeta,
+    ) {
+        let comps = split_components(full_dir_u16.as_slice());
+        let id = self.ensure_dir_comps(&comps);
+        if id == ROOT_NODE {
+            return;
+        }
+        if let Some(short) = short_name {
+            self.add_short_name(id, short.as_slice());
+        }
+        self.set_dir_meta(id, meta);
+    }
+
+    // まだ情報のないディレクトリにだけ設定する (同じパスの削除済みフォルダが複数あれば先勝ち)
+    fn set_dir_meta(&mut self, id: NodeId, meta: EntryMeta) -> bool {
+        if self.node(id).slot != IndexSlot::Dir {
+            return false;
+        }
+        let slot = self.slot_for(EntryOrDir::Dir(Some(Box::new(meta))));
+        self.update_node(id, |n| n.slot = slot);
+        true
+    }
+
+    // スキャン後に、途中のフォルダ (削除されていない親など) へ元のレコードの情報を付ける
+    // ルートからレコード番号と名前を突き合わせながら辿る
+    // \$Orphans\<n> 等の下は、レコード n を親に持っていたフォルダとして辿る
+    pub fn fill_dir_meta(&mut self, dirs: &DirTable) {
+        let mut by_parent: HashMap<(u64, u64), Vec<_>> = HashMap::new();
+        for (record, parent, name) in dirs.entries() {
+            if record == ROOT_RECORD {
+                continue;
+            }
+            by_parent
+                .entry((parent, self.upcase.hash(name)))
+                .or_default()
+                .push((record, name));
+        }
+        let synthetic: Vec<Vec<u16>> = [ORPHANS_DIR, REUSED_PARENT_DIR]
+            .iter()
+            .map(|s| s.encode_utf16().collect())
+            .collect();
+        let live_dir: Vec<u16> = LIVE_DIR.encode_utf16().collect();
+
+        let mut stack: Vec<(NodeId, u64)> = vec![(ROOT_NODE, ROOT_RECORD)];
+        if let Some(info) = dirs.dir_info(ROOT_RECORD) {
+            self.set_dir_meta(ROOT_NODE, dir_meta_from_info(ROOT_RECORD, &info));
+        }
+        while let Some((node, record)) = stack.pop() {
+            let children: Vec<NodeId> = self.children(node).filter(|&c| self.is_dir(c)).collect();
+            for child in children {
+                let name = self.names.get(self.node(child).name).to_vec();
+                if node == ROOT_NODE && synthetic.iter().any(|s| self.upcase.eq(s, &name)) {
+                    for group in self.children(child).collect::<Vec<_>>() {
+                        let n = String::from_utf16_lossy(&self.names.get(self.node(group).name));
+                        if let Ok(n) = n.parse::<u64>() {
+                            stack.push((group, n));
+                        }
                     }
-                } else {
-                    candidate = U16String::from_str(&format!("{}_{}", s, n));
-                }
-                lower = candidate.to_string_lossy().to_lowercase();
-                if !set.contains(&lower) {
-                    break;
-                }
-                n += 1;
-            }
-        }
-        set.insert(lower);
-        names.push(candidate.clone());
-        candidate
-    }
-
-    pub fn insert_file(&mut self, full_path_u16: &U16String, mut meta: EntryMeta) {
-        let full_str = normalize_candidate_path(&full_path_u16.to_string_lossy());
-        let parent_str = {
-            let pb = PathBuf::from(&full_str);
-            pb.parent()
-                .map(|x| normalize_candidate_path(&x.to_string_lossy()))
-                .unwrap_or_else(|| "\\".to_string())
-        };
-        self.ensure_dirs_from_root(&parent_str);
-        let parent_key = normalize_and_canonicalize_for_key(&parent_str);
-        let base = basename_u16str(U16Str::from_slice(
-            U16String::from_str(&full_str).as_slice(),
-        ));
-        let unique = self.unique_child_name(&parent_key, &base);
-        meta.name_u16 = unique.clone();
-        let full_shown = if parent_str == "\\" {
-            format!(r"\{}", unique.to_string_lossy())
-        } else {
-            format!(r"{}\{}", parent_str, unique.to_string_lossy())
+                    continue;
+                }
+                // \$Live 以下はルートと同じ構造
+                if node == ROOT_NODE && self.upcase.eq(&live_dir, &name) {
+                    stack.push((child, ROOT_RECORD));
+                    continue;
+                }
+                let found = by_parent
+                    .get(&(record, self.upcase.hash(&name)))
+                    .and_then(|recs| recs.iter().find(|(_, n)| self.upcase.eq(n, &name)))
+                    .map(|&(r, _)| r);
+                if let Some(r) = found {
+                    if let Some(info) = dirs.dir_info(r) {
+                        self.set_dir_meta(child, dir_meta_from_info(r, &info));
+                    }
+                    stack.push((child, r));
+                }
+            }
+        }
+    }
+
+    // 削除済みファイルごとに、\$Live 以下の同じパスにあるファイルと大きさ・更新時刻を比べる
+    pub fn mark_live_matches(&mut self) {
+        let live_dir: Vec<u16> = LIVE_DIR.encode_utf16().collect();
+        let Some(live_root) = self.find_long_child(ROOT_NODE, &live_dir) else {
+            return;
+        };
+        let modified = |m: &EntryMeta| m.times.primary().map(|t| t.modified);
+        let mut stack: Vec<(NodeId, Option<NodeId>)> = vec![(ROOT_NODE, Some(live_root))];
+        while let Some((node, live)) = stack.pop() {
+            let children: Vec<NodeId> = self.children(node).collect();
+            for child in children {
+                if child == live_root {
+                    continue;
+                }
+                let name = self.names.get(self.node(child).name).to_vec();
+                let counterpart = live.and_then(|l| self.find_long_child(l, &name));
+                if self.is_dir(child) {
+                    stack.push((child, counterpart.filter(|&c| self.is_dir(c))));
+                    continue;
+                }
+                let other = counterpart.and_then(|c| match self.entry(c).as_deref() {
+                    Some(EntryOrDir::File(m)) => Some((m.size, modified(m))),
+                    _ => None,
+                });
+                self.update_file_meta(child, |m| {
+                    m.live_match = match other {
+                        None => LiveMatch::Absent,
+                        Some((size, mt)) if size == m.size && mt == modified(m) => {
+                            LiveMatch::Identical
+                        }
+                        Some(_) => LiveMatch::Differs,
+                    };
+                });
+            }
+        }
+    }
+
+    // ファイルの情報を書き換える (一時ファイルにあるものはその場で書き直す)
+    fn update_file_meta(&mut self, id: NodeId, f: impl FnOnce(&mut EntryMeta)) {
+        let off = match self.node(id).slot {
+            IndexSlot::Resident(i) => {
+                if let EntryOrDir::File(m) = &mut self.metas[i as usize] {
+                    f(m);
+                }
+                return;
+            }
+            IndexSlot::Spilled { offset, dir: false } => offset,
+            IndexSlot::Spilled { dir: true, .. } | IndexSlot::Dir | IndexSlot::Link(_) => return,
+        };
+        let Some(spill) = self.spill.as_mut() else {
+            return;
+        };
+        let mut meta = match spill.load(off) {
+            Ok(m) => m,
+            Err(e) => {
+                tracing::warn!(error = %e, offset = off, "failed to load spilled entry");
+                return;
+            }
+        };
+        f(&mut meta);
+        match spill.store(off, &meta) {
+            Ok(offset) if offset == off => {}
+            Ok(offset) => {
+                self.update_node(id, |n| n.slot = IndexSlot::Spilled { offset, dir: false })
+            }
+            Err(e) => tracing::warn!(error = %e, "failed to spill index entry"),
+        }
+    }
+
+    // スキャン後に、同じフォルダで重なった名前を付け直す
+    // 候補が届く順 (スレッドの処理順) に依らないよう、方針の順に並べて先頭だけ元の名前のまま残す
+    // 同じ名前のディレクトリがあればディレクトリが優先
+    pub fn finalize_names(&mut self, policy: DupPolicy) {
+        let mut keys: Vec<(NodeId, u64)> = self.pending_dups.drain().collect();
+        keys.sort();
+        for key in keys {
+            let mut chain: Vec<NodeId> = Vec::new();
+            let mut cur = self.long_names.heads.get(self.long_names.of(key.0, key.1));
+            while cur != NIL {
+                let n = self.node(cur);
+                if n.parent == key.0 && self.chain_hash(Chain::Long, &n) == key.1 {
+                    chain.push(cur);
+                }
+                cur = n.hash_next;
+            }
+            // ハッシュが衝突した別の名前を分ける
+            while let Some(first) = chain.first().copied() {
+                let name = self.names.get(self.node(first).name).to_vec();
+                let (same, other): (Vec<NodeId>, Vec<NodeId>) = chain
+                    .into_iter()
+                    .partition(|&i| self.upcase.eq(&self.names.get(self.node(i).name), &name));
+                chain = other;
+                if same.len() > 1 {
+                    self.rename_duplicates(key.0, &same, policy);
+                }
+            }
+        }
+    }
+
+    fn rename_duplicates(&mut self, parent: NodeId, same: &[NodeId], policy: DupPolicy) {
+        let has_dir = same.iter().any(|&id| self.is_dir(id));
+        let mut files: Vec<(u64, u64, NodeId)> = same
+            .iter()
+            .filter(|&&id| !self.is_dir(id))
+            .map(|&id| match self.entry(id).as_deref() {
+                Some(EntryOrDir::File(m)) => (
+                    m.mft_no,
+                    m.times.primary().map(|t| t.modified).unwrap_or(0),
+                    id,
+                ),
+                _ => (u64::MAX, 0, id),
+            })
+            .collect();
+        files.sort_by_key(|&(mft_no, modified, id)| match policy.order {
+            DupOrder::MftNumber => (mft_no, modified, id),
+            DupOrder::Modified => (modified, mft_no, id),
+        });
+        let keep = usize::from(!has_dir);
+        for (i, &(mft_no, modified, id)) in files.iter().enumerate().skip(keep) {
+            let old = self.node(id).name;
+            let dup = DupInfo {
+                number: (i - keep) as u32 + 2,
+                mft_no,
+                modified,
+            };
+            let name = self.unique_child_name(parent, &self.names.get(old), policy.naming, &dup);
+            self.rename_child(id, &name);
+            self.orig_names.insert(id, old);
+        }
+    }
+
+    // 名前を変えて、名前の索引に繋ぎ直す
+    fn rename_child(&mut self, id: NodeId, new_name: &[u16]) {
+        self.unlink(Chain::Long, id);
+        let (name_id, fresh) = self.names.intern(new_name);
+        if fresh {
+            self.approx_bytes += (new_name.len() * 2 + 24) as u64;
+        }
+        self.update_node(id, |n| n.name = name_id);
+        self.link(Chain::Long, id);
+    }
+
+    // 同じ元のパスを持つファイル (保存のたびに削除/作成されたもの) を版としてまとめ、
+    // \$Versions\<元のパス>\ の下に更新時刻の古い順に v1_<日時>.ext, v2_... と並べる
+    pub fn build_versions(&mut self) {
+        let mut by_name: HashMap<(NodeId, u64), Vec<NodeId>> = HashMap::new();
+        for id in self.deleted_files() {
+            let key = (self.node(id).parent, self.upcase.hash(&self.orig_name(id)));
+            by_name.entry(key).or_default().push(id);
+        }
+        let mut groups: Vec<Vec<NodeId>> = Vec::new();
+        for (_, ids) in by_name {
+            if ids.len() < 2 {
+                continue;
+            }
+            // ハッシュが衝突した別の名前を分ける
+            let mut rest = ids;
+            while let Some(first) = rest.first().copied() {
+                let (same, other): (Vec<NodeId>, Vec<NodeId>) = rest
+                    .into_iter()
+                    .partition(|&i| self.upcase.eq(&self.orig_name(i), &self.orig_name(first)));
+                if same.len() > 1 {
+                    groups.push(same);
+                }
+                rest = other;
+            }
+        }
+        groups.sort();
+
+        let versions_dir: Vec<u16> = VERSIONS_DIR.encode_utf16().collect();
+        for group in groups {
+            let mut members: Vec<(u64, u64, NodeId)> = group
+                .iter()
+                .filter_map(|&id| match self.entry(id).as_deref() {
+                    Some(EntryOrDir::File(m)) => Some((
+                        m.times.primary().map(|t| t.modified).unwrap_or(0),
+                        m.mft_no,
+                        id,
+                    )),
+                    _ => None,
+                })
+                .collect();
+            members.sort();
+
+            let orig = self.orig_name(group[0]).to_vec();
+            let mut comps: Vec<Vec<u16>> = vec![versions_dir.clone()];
+            comps.extend(
+                self.ancestors(self.node(group[0]).parent)
+                    .map(|a| self.names.get(self.node(a).name).to_vec()),
+            );
+            comps.push(orig.clone());
+            let comp_refs: Vec<&[u16]> = comps.iter().map(|c| c.as_slice()).collect();
+            let dir = self.ensure_dir_comps(&comp_refs);
+
+            let dot = orig
+                .iter()
+                .rposition(|&c| c == b'.' as u16)
+                .filter(|&d| d > 0)
+                .unwrap_or(orig.len());
+            for (n, &(modified, _, id)) in members.iter().enumerate() {
+                let mut name: Vec<u16> = format!("v{}", n + 1).encode_utf16().collect();
+                if let Some(t) = filetime_to_civil(modified) {
+                    name.extend(
+                        format!(
+                            "_{:04}-{:02}-{:02}_{:02}{:02}{:02}",
+                            t.year, t.month, t.day, t.hour, t.minute, t.second
+                        )
+                        .encode_utf16(),
+                    );
+                }
+                name.extend_from_slice(&orig[dot..]);
+                self.add_child(dir, &name, IndexSlot::Link(id));
+            }
+        }
+    }
+
+    // \$ByType, \$ByDate, \$BySize の仮想フォルダを作る (中身は元のファイルへのリンク)
+    // 同じフォルダで名前が重なれば [mft N] を付ける。並びは種類/日付/大きさ、レコード番号、名前の順で決める
+    pub fn build_views(&mut self) {
+        let mut placed: Vec<(ViewKey, u64, NodeId)> = Vec::new();
+        for id in self.deleted_files() {
+            let (mft_no, size, times, content_type) = match self.entry(id).as_deref() {
+                Some(EntryOrDir::File(m)) => (m.mft_no, m.size, m.times.primary(), m.content_type),
+                _ => continue,
+            };
+            // 中身で判定できなかったものは拡張子で分ける
+            let family = match content_type {
+                Some(c) => c.family(),
+                None => FileFamily::from_name(&self.names.get(self.node(id).name)),
+            };
+            let t = times.unwrap_or_default();
+            placed.push((ViewKey::Type(family), mft_no, id));
+            placed.push((ViewKey::Modified(month_of(t.modified)), mft_no, id));
+            placed.push((ViewKey::Deleted(month_of(t.mft_changed)), mft_no, id));
+            placed.push((ViewKey::Size(SizeBucket::of(size)), mft_no, id));
+        }
+        placed.sort_by(|a, b| {
+            (a.0, a.1)
+                .cmp(&(b.0, b.1))
+                .then_with(|| self.name(a.2).as_slice().cmp(self.name(b.2).as_slice()))
+        });
+
+        let mut dir_of: HashMap<ViewKey, NodeId> = HashMap::new();
+        for (key, mft_no, id) in placed {
+            let dir = match dir_of.get(&key) {
+                Some(&d) => d,
+                None => {
+                    let comps: Vec<Vec<u16>> = key
+                        .folder_path()
+                        .iter()
+                        .map(|c| c.encode_utf16().collect())
+                        .collect();
+                    let comp_refs: Vec<&[u16]> = comps.iter().map(|c| c.as_slice()).collect();
+                    let d = self.ensure_dir_comps(&comp_refs);
+                    dir_of.insert(key, d);
+                    d
+                }
+            };
+            let name = self.names.get(self.node(id).name).to_vec();
+            let name = match self.find_long_child(dir, &name) {
+                None => name,
+                Some(_) => {
+                    let dup = DupInfo {
+                        number: 2,
+                        mft_no,
+                        modified: 0,
+                    };
+                    self.unique_child_name(dir, &name, DupNaming::MftNumber, &dup)
+                }
+            };
+            self.add_child(dir, &name, IndexSlot::Link(id));
+        }
+    }
+
+    // ファイルの先頭を読んで中身から種類を判定する (判定できた件数を返す)
+    // read_head はレコード番号からデータの先頭を読む。ハードリンクは1回だけ読む
+    pub fn detect_types(&mut self, mut read_head: impl FnMut(u64) -> Option<Vec<u8>>) -> usize {
+        let mut found = 0;
+        for (mft_no, ids) in self.untyped_files() {
+            if let Some(c) = read_head(mft_no).and_then(|h| sniff(&h)) {
+                self.set_content_type(&ids, c);
+                found += ids.len();
+            }
+        }
+        found
+    }
+
+    // まだ種類が分からない空でないファイルを、レコード番号ごとにまとめて返す (ハードリンクは同じ組)
+    pub fn untyped_files(&self) -> Vec<(u64, Vec<NodeId>)> {
+        let mut out: Vec<(u64, Vec<NodeId>)> = Vec::new();
+        let mut slot_of: HashMap<u64, usize> = HashMap::new();
+        for id in self.deleted_files() {
+            let (mft_no, link_count) = match self.entry(id).as_deref() {
+                Some(EntryOrDir::File(m)) if m.content_type.is_none() && m.size > 0 => {
+                    (m.mft_no, m.link_count)
+                }
+                _ => continue,
+            };
+            if link_count > 1 {
+                if let Some(&i) = slot_of.get(&mft_no) {
+                    out[i].1.push(id);
+                    continue;
+                }
+                slot_of.insert(mft_no, out.len());
+            }
+            out.push((mft_no, vec![id]));
+        }
+        out
+    }
+
+    pub fn set_content_type(&mut self, ids: &[NodeId], c: ContentType) {
+        for &id in ids {
+            self.update_file_meta(id, |m| m.content_type = Some(c));
+        }
+    }
+
+    // 仮想フォルダの元になるファイル (\$Live 以下とリンクを除く)
+    fn deleted_files(&self) -> Vec<NodeId> {
+        self.files(false)
+    }
+
+    // ファイルのノード (仮想フォルダのリンクは除く)。include_live なら \$Live 以下も含める
+    pub fn files(&self, include_live: bool) -> Vec<NodeId> {
+        let live_dir: Vec<u16> = LIVE_DIR.encode_utf16().collect();
+        let live_root = match include_live {
+            true => None,
+            false => self.find_long_child(ROOT_NODE, &live_dir),
+        };
+        (1..self.nodes.len() as NodeId)
+            .filter(|&id| {
+                !matches!(self.node(id).slot, IndexSlot::Link(_))
+                    && !self.is_dir(id)
+                    && !live_root.is_some_and(|l| self.is_under(id, l))
+            })
+            .collect()
+    }
+
+    // 連番を付ける前の名前
+    fn orig_name(&self, id: NodeId) -> Cow<'_, [u16]> {
+        let name = self
+            .orig_names
+            .get(&id)
+            .copied()
+            .unwrap_or(self.node(id).name);
+        self.names.get(name)
+    }
+
+    // ルート直下から id までのノード (ルート自身は含まない)
+    fn ancestors(&self, id: NodeId) -> impl Iterator<Item = NodeId> {
+        let mut chain = Vec::new();
+        let mut cur = id;
+        while cur != ROOT_NODE {
+            chain.push(cur);
+            cur = self.node(cur).parent;
+        }
+        chain.into_iter().rev()
+    }
+
+    pub fn is_under(&self, id: NodeId, dir: NodeId) -> bool {
+        let mut cur = id;
+        while cur != ROOT_NODE {
+            if cur == dir {
+                return true;
+            }
+            cur = self.node(cur).parent;
+        }
+        false
+    }
+
+    // ノードのパス (\a\b\c)
+    pub fn path_of(&self, id: NodeId) -> U16String {
+        let mut out: Vec<u16> = Vec::new();
+        for a in self.ancestors(id) {
+            out.push(b'\\' as u16);
+            out.extend_from_slice(&self.names.get(self.node(a).name));
+        }
+        if out.is_empty() {
+            out.push(b'\\' as u16);
+        }
+        U16String::from_vec(out)
+    }
+
+    // パスに対応する版の一覧 (古い順、\$Versions 以下のノード)
+    // 連番付きの名前や \$Versions 以下のパスを渡してもよい。版が1つだけならそのファイル自身
+    pub fn versions_of(&self, path: &[u16]) -> Vec<NodeId> {
+        let Some(id) = self.lookup(path) else {
+            return Vec::new();
         };
-        let key = normalize_and_canonicalize_for_key(&full_shown);
-        self.nodes.insert(key, EntryOrDir::File(meta));
+        let id = match self.node(id).slot {
+            IndexSlot::Link(target) => target,
+            _ if self.is_dir(id) => {
+                return self
+                    .children(id)
+                    .filter(|&c| matches!(self.node(c).slot, IndexSlot::Link(_)))
+                    .collect();
+            }
+            _ => id,
+        };
+        let mut vpath: Vec<u16> = vec![b'\\' as u16];
+        vpath.extend(VERSIONS_DIR.encode_utf16());
+        vpath.extend_from_slice(self.path_of(self.node(id).parent).as_slice());
+        if vpath.last() != Some(&(b'\\' as u16)) {
+            vpath.push(b'\\' as u16);
+        }
+        vpath.extend_from_slice(&self.orig_name(id));
+        match self.lookup(&vpath) {
+            Some(dir) if self.is_dir(dir) => self.children(dir).collect(),
+            _ => vec![id],
+        }
+    }
+
+    // Dokanから渡されるパス (\a\b\c) をノードに解決する
+    pub fn lookup(&self, path: &[u16]) -> Option<NodeId> {
+        let mut cur = ROOT_NODE;
+        for comp in path.split(|&c| c == b'\\' as u16 || c == b'/' as u16) {
+            if comp.is_empty() || comp == [b'.' as u16] {
+                continue;
+            }
+            if comp == [b'.' as u16, b'.' as u16] {
+                cur = self.node(cur).parent;
+                continue;
+            }
+            cur = self.find_child(cur, comp)?;
+        }
+        Some(cur)
+    }
+
+    pub fn is_dir(&self, id: NodeId) -> bool {
+        match self.node(id).slot {
+            IndexSlot::Dir | IndexSlot::Spilled { dir: true, .. } => true,
+            IndexSlot::Resident(i) => matches!(self.metas[i as usize], EntryOrDir::Dir(_)),
+            IndexSlot::Spilled { dir: false, .. } | IndexSlot::Link(_) => false,
+        }
     }
 
-    pub fn insert_dir(&mut self, full_dir_u16: &U16String) {
-        let dir_str = normalize_candidate_path(&full_dir_u16.to_string_lossy());
-        self.ensure_dirs_from_root(&dir_str);
+    pub fn name(&self, id: NodeId) -> Cow<'_, U16Str> {
+        match self.names.get(self.node(id).name) {
+            Cow::Borrowed(s) => Cow::Borrowed(U16Str::from_slice(s)),
+            Cow::Owned(v) => Cow::Owned(U16String::from_vec(v)),
+        }
+    }
+
+    pub fn children(&self, id: NodeId) -> Children<'_> {
+        Children {
+            idx: self,
+            cur: self.node(id).first_child,
+        }
+    }
+
+    pub fn entry(&self, id: NodeId) -> Option<Cow<'_, EntryOrDir>> {
+        if id as usize >= self.nodes.len() {
+            return None;
+        }
+        match self.node(id).slot {
+            IndexSlot::Dir => Some(Cow::Owned(EntryOrDir::Dir(None))),
+            IndexSlot::Resident(i) => Some(Cow::Borrowed(&self.metas[i as usize])),
+            IndexSlot::Link(target) => self.entry(target),
+            IndexSlot::Spilled { offset, dir } => {
+                let spill = self.spill.as_ref()?;
+                match spill.load(offset) {
+                    Ok(meta) if dir => Some(Cow::Owned(EntryOrDir::Dir(Some(Box::new(meta))))),
+                    Ok(meta) => Some(Cow::Owned(EntryOrDir::File(Box::new(meta)))),
+                    Err(e) => {
+                        tracing::warn!(error = %e, offset, "failed to load spilled entry");
+                        None
+                    }
+                }
+            }
+        }
     }
 
-    pub fn get(&self, key_lc: &str) -> Option<&EntryOrDir> {
-        self.nodes.get(key_lc)
+    // fs.rs 用: Dokanのパスでノードとエントリを引く
+    pub fn get(&self, path: &[u16]) -> Option<(NodeId, Cow<'_, EntryOrDir>)> {
+        let id = self.lookup(path)?;
+        Some((id, self.entry(id)?))
     }
 
-    pub fn list_children(&self, dir_key_lc: &str) -> Vec<U16String> {
-        self.children_names
-            .get(dir_key_lc)
-            .cloned()
-            .unwrap_or_default()
+    // fs.rs 用: ディレクトリの子を名前と一緒に並べる
+    pub fn list_children(&self, id: NodeId) -> impl Iterator<Item = (NodeId, Cow<'_, U16Str>)> {
+        self.children(id).map(move |c| (c, self.name(c)))
     }
 }
 
-fn basename_u16str(full: &U16Str) -> U16String {
-    let slice = full.as_slice();
-    if let Some(pos) = slice.iter().rposition(|c| *c == b'\\' as u16) {
-        U16String::from_vec(slice[pos + 1..].to_vec())
-    } else {
-        U16String::from_vec(slice.to_vec())
+// スナップショット用の書き出し/読み込み
+// ノードを番号順 (親が必ず先) にそのまま並べるので、読み込むと同じ木になる
+impl DeletedIndex {
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(&(self.nodes.len() as u32).to_le_bytes())?;
+        for id in 0..self.nodes.len() as NodeId {
+            let n = self.node(id);
+            w.write_all(&n.parent.to_le_bytes())?;
+            write_name(w, &self.names.get(n.name))?;
+            if let IndexSlot::Link(target) = n.slot {
+                w.write_all(&[3])?;
+                w.write_all(&target.to_le_bytes())?;
+            } else {
+                match self.entry(id).as_deref() {
+                    Some(EntryOrDir::Dir(None)) => w.write_all(&[0])?,
+                    Some(EntryOrDir::Dir(Some(m))) => {
+                        w.write_all(&[1])?;
+                        write_meta(w, m)?;
+                    }
+                    Some(EntryOrDir::File(m)) => {
+                        w.write_all(&[2])?;
+                        write_meta(w, m)?;
+                    }
+                    None => return Err(invalid_data("failed to load spilled entry")),
+                }
+            }
+            let name_of = |name: Option<NameId>| match name {
+                Some(n) if n != NIL => self.names.get(n),
+                _ => Cow::Borrowed(&[][..]),
+            };
+            write_name(w, &name_of(Some(n.short)))?;
+            write_name(w, &name_of(self.orig_names.get(&id).copied()))?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(
+        r: &mut impl Read,
+        metadata_budget: Option<SpillBudget>,
+        upcase: Arc<UpCaseTable>,
+    ) -> io::Result<Self> {
+        let mut idx = Self::new(metadata_budget, upcase);
+        let count = read_u32(r)?;
+        for id in 0..count {
+            let parent = read_u32(r)?;
+            let name = read_name(r)?;
+            let slot = match read_u8(r)? {
+                0 => IndexSlot::Dir,
+                1 => {
+                    let meta = read_meta(r)?;
+                    idx.slot_for(EntryOrDir::Dir(Some(Box::new(meta))))
+                }
+                2 => {
+                    let meta = read_meta(r)?;
+                    idx.slot_for_file(meta)
+                }
+                3 => match read_u32(r)? {
+                    t if t < id => IndexSlot::Link(t),
+                    _ => return Err(invalid_data("bad link target")),
+                },
+                _ => return Err(invalid_data("bad node kind")),
+            };
+            if id == ROOT_NODE {
+                idx.update_node(ROOT_NODE, |n| n.slot = slot);
+            } else if parent < id && idx.is_dir(parent) {
+                idx.add_child(parent, &name, slot);
+            } else {
+                return Err(invalid_data("bad parent"));
+            }
+            let short = read_name(r)?;
+            if !short.is_empty() {
+                let (name_id, _) = idx.names.intern(&short);
+                idx.update_node(id, |n| n.short = name_id);
+                idx.link(Chain::Short, id);
+            }
+            let orig = read_name(r)?;
+            if !orig.is_empty() {
+                let (name_id, _) = idx.names.intern(&orig);
+                idx.orig_names.insert(id, name_id);
+            }
+        }
+        Ok(idx)
+    }
+
+    // ファイルが指すレコード番号 (重複なし、昇順)
+    pub fn file_records(&self) -> Vec<u64> {
+        let mut out: Vec<u64> = (0..self.nodes.len() as NodeId)
+            .filter(|&id| !matches!(self.node(id).slot, IndexSlot::Link(_)))
+            .filter_map(|id| match self.entry(id).as_deref() {
+                Some(EntryOrDir::File(m)) => Some(m.mft_no),
+                _ => None,
+            })
+            .collect();
+        out.sort_unstable();
+        out.dedup();
+        out
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn write_name(w: &mut impl Write, name: &[u16]) -> io::Result<()> {
+    w.write_all(&(name.len() as u16).to_le_bytes())?;
+    for c in name {
+        w.write_all(&c.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_meta(w: &mut impl Write, m: &EntryMeta) -> io::Result<()> {
+    let buf = encode_meta(m);
+    w.write_all(&(buf.len() as u32).to_le_bytes())?;
+    w.write_all(&buf)
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_name(r: &mut impl Read) -> io::Result<Vec<u16>> {
+    let mut len = [0u8; 2];
+    r.read_exact(&mut len)?;
+    let mut buf = vec![0u8; u16::from_le_bytes(len) as usize * 2];
+    r.read_exact(&mut buf)?;
+    Ok(buf
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect())
+}
+
+fn read_meta(r: &mut impl Read) -> io::Result<EntryMeta> {
+    let mut buf = vec![0u8; read_u32(r)? as usize];
+    r.read_exact(&mut buf)?;
+    decode_meta(&buf).ok_or_else(|| invalid_data("bad entry"))
+}
+
+pub struct Children<'a> {
+    idx: &'a DeletedIndex,
+    cur: NodeId,
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = NodeId;
+    fn next(&mut self) -> Option<NodeId> {
+        if self.cur == NIL {
+            return None;
+        }
+        let id = self.cur;
+        self.cur = self.idx.node(id).next_sibling;
+        Some(id)
+    }
+}
+
+// パスをコンポーネントに分解 ("." と ".." も解決する)
+fn split_components(path: &[u16]) -> Vec<&[u16]> {
+    let mut out: Vec<&[u16]> = Vec::new();
+    for comp in path.split(|&c| c == b'\\' as u16) {
+        if comp.is_empty() || comp == [b'.' as u16] {
+            continue;
+        }
+        if comp == [b'.' as u16, b'.' as u16] {
+            out.pop();
+            continue;
+        }
+        out.push(comp);
+    }
+    out
+}
+
+fn hash_exact(name: &[u16]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for &c in name {
+        h ^= c as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+// レコードが持つ名前1つ分 (ハードリンクごと)
+#[derive(Debug, Clone)]
+pub struct CandidateName {
+    pub path: U16String,
+    pub short_name: Option<U16String>,
+}
+
+fn dir_meta_from_info(record: u64, info: &DirInfo) -> EntryMeta {
+    EntryMeta {
+        mft_no: record,
+        sequence: info.sequence,
+        is_dir: true,
+        size: 0,
+        parent_ref: info.parent_ref,
+        parent_status: ParentStatus::Resolved,
+        times: RecordTimes {
+            si: Some(info.times),
+            ..Default::default()
+        },
+        link_count: 1,
+        attributes: info.attributes,
+        live: info.in_use,
+        live_match: LiveMatch::NotChecked,
+        content_type: None,
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Candidate {
     pub mft_no: u64,
-    pub path: String,
+    pub sequence: u16,
+    // 先頭が主となる名前、残りはハードリンク
+    pub names: Vec<CandidateName>,
     pub size: u64,
     pub is_dir: bool,
-    pub created: Option<i64>,
-    pub modified: Option<i64>,
-    pub accessed: Option<i64>,
+    pub live: bool,
+    pub parent_ref: u64,
+    pub parent_status: ParentStatus,
+    pub times: RecordTimes,
+    pub attributes: u32,
 }
 
 pub fn apply_staging(
@@ -191,41 +1629,278 @@ pub fn apply_staging(
         return;
     }
     for c in staging.drain(..) {
-        let mut full_str = normalize_candidate_path(&c.path);
-        if is_basename_only(&full_str) {
-            let base = &full_str[1..];
-            if !base.is_empty() {
-                // 元々のパスが不明な場合、直下の「fakepath」というディレクトリに保存
-                full_str = format!(r"\fakepath\{}", base);
-            }
-        }
-        let full_u16 = U16String::from_str(&full_str);
+        let meta = EntryMeta {
+            mft_no: c.mft_no,
+            sequence: c.sequence,
+            is_dir: c.is_dir,
+            size: c.size,
+            parent_ref: c.parent_ref,
+            parent_status: c.parent_status,
+            times: c.times,
+            link_count: c.names.len() as u16,
+            attributes: c.attributes,
+            live: c.live,
+            live_match: LiveMatch::NotChecked,
+            content_type: None,
+        };
         if c.is_dir {
-            idx.insert_dir(&full_u16);
+            // ディレクトリはハードリンクを持てないので主の名前だけ
+            if let Some(n) = c.names.first() {
+                idx.insert_dir(&n.path, n.short_name.as_deref(), meta);
+            }
         } else {
-            let meta = EntryMeta {
-                mft_no: c.mft_no,
-                is_dir: false,
-                size: c.size,
-                created: c.created.map(unix_ts_to_system_time),
-                modified: c.modified.map(unix_ts_to_system_time),
-                accessed: c.accessed.map(unix_ts_to_system_time),
-                name_u16: basename_u16str(U16Str::from_slice(full_u16.as_slice())),
-            };
-            idx.insert_file(&full_u16, meta);
+            // ハードリンクは同じレコードを指す別のエントリとして並べる
+            for n in &c.names {
+                idx.insert_file(&n.path, n.short_name.as_deref(), meta.clone());
+            }
         }
         found_counter.fetch_add(1, Ordering::Relaxed);
     }
 }
 
-pub fn is_basename_only(norm_full: &str) -> bool {
-    if !norm_full.starts_with('\\') {
-        return false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(mft_no: u64, modified: u64) -> EntryMeta {
+        let times = RecordTimes {
+            si: Some(crate::timestamps::FileTimes {
+                modified,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        EntryMeta {
+            mft_no,
+            sequence: 1,
+            is_dir: false,
+            size: mft_no * 10,
+            parent_ref: 0,
+            parent_status: ParentStatus::Resolved,
+            times,
+            link_count: 1,
+            attributes: 0,
+            live: false,
+            live_match: LiveMatch::NotChecked,
+            content_type: None,
+        }
     }
-    !norm_full[1..].contains('\\')
-}
 
-pub fn path_key_lc_from_u16(full: &U16CStr) -> String {
-    let s = U16String::from_vec(full.as_slice().to_vec()).to_string_lossy();
-    normalize_and_canonicalize_for_key(&s)
+    fn w(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    fn add(idx: &mut DeletedIndex, path: &str, short: Option<&str>, m: EntryMeta) {
+        let short = short.map(U16String::from_str);
+        idx.insert_file(&U16String::from_str(path), short.as_deref(), m);
+    }
+
+    fn mft_at(idx: &DeletedIndex, path: &str) -> Option<u64> {
+        let id = idx.lookup(&w(path))?;
+        match idx.entry(id).as_deref() {
+            Some(EntryOrDir::File(m)) => Some(m.mft_no),
+            _ => None,
+        }
+    }
+
+    fn child_names(idx: &DeletedIndex, path: &str) -> Vec<String> {
+        let id = idx.lookup(&w(path)).unwrap();
+        idx.children(id)
+            .map(|c| idx.name(c).to_string_lossy())
+            .collect()
+    }
+
+    #[test]
+    fn child_lookup_ignores_case() {
+        let mut idx = DeletedIndex::default();
+        add(&mut idx, r"\Users\Bob\Report.docx", None, meta(1, 0));
+        add(&mut idx, r"\Users\Bob\ΣΊΣΥΦΟΣ.txt", None, meta(2, 0));
+        // 大文字小文字だけが違うフォルダは同じフォルダにまとまる
+        add(&mut idx, r"\USERS\bob\notes.txt", None, meta(3, 0));
+
+        assert_eq!(mft_at(&idx, r"\users\BOB\REPORT.DOCX"), Some(1));
+        assert_eq!(mft_at(&idx, r"\users\bob\σίσυφος.TXT"), Some(2));
+        assert_eq!(mft_at(&idx, r"\Users\Bob\Notes.txt"), Some(3));
+        assert_eq!(mft_at(&idx, r"\Users\Bob\missing.txt"), None);
+        assert_eq!(child_names(&idx, r"\"), vec!["Users"]);
+        // 表示する名前は元のまま
+        assert_eq!(
+            child_names(&idx, r"\users\bob"),
+            vec!["Report.docx", "ΣΊΣΥΦΟΣ.txt", "notes.txt"]
+        );
+    }
+
+    #[test]
+    fn duplicate_names_are_renamed_and_relinked() {
+        let mut idx = DeletedIndex::default();
+        add(&mut idx, r"\d\a.txt", None, meta(30, 100));
+        add(&mut idx, r"\d\A.TXT", None, meta(10, 300));
+        add(&mut idx, r"\d\a.txt", None, meta(20, 200));
+        idx.finalize_names(DupPolicy::default());
+
+        // レコード番号の小さい順に、先頭だけ元の名前のまま残る
+        assert_eq!(mft_at(&idx, r"\d\a.txt"), Some(10));
+        assert_eq!(mft_at(&idx, r"\d\a_2.txt"), Some(20));
+        assert_eq!(mft_at(&idx, r"\D\A_3.TXT"), Some(30));
+        let mut names = child_names(&idx, r"\d");
+        names.sort();
+        assert_eq!(names, vec!["A.TXT", "a_2.txt", "a_3.txt"]);
+        // 付け直した名前からも元の名前の版を引ける
+        let id = idx.lookup(&w(r"\d\a_3.txt")).unwrap();
+        assert_eq!(idx.orig_name(id), w("a.txt").as_slice());
+    }
+
+    #[test]
+    fn duplicate_names_follow_policy() {
+        let mut idx = DeletedIndex::default();
+        add(&mut idx, r"\d\a.txt", None, meta(30, 100));
+        add(&mut idx, r"\d\a.txt", None, meta(10, 300));
+        idx.finalize_names(DupPolicy {
+            naming: DupNaming::MftNumber,
+            order: DupOrder::Modified,
+        });
+        assert_eq!(mft_at(&idx, r"\d\a.txt"), Some(30));
+        assert_eq!(mft_at(&idx, r"\d\a [mft 10].txt"), Some(10));
+    }
+
+    #[test]
+    fn directory_keeps_name_over_file() {
+        let mut idx = DeletedIndex::default();
+        add(&mut idx, r"\d\x", None, meta(1, 0));
+        add(&mut idx, r"\d\x\inner.txt", None, meta(2, 0));
+        idx.finalize_names(DupPolicy::default());
+        assert!(idx.is_dir(idx.lookup(&w(r"\d\x")).unwrap()));
+        assert_eq!(mft_at(&idx, r"\d\X\inner.txt"), Some(2));
+        assert_eq!(mft_at(&idx, r"\d\x_2"), Some(1));
+    }
+
+    #[test]
+    fn short_names_resolve_to_long_entries() {
+        let mut idx = DeletedIndex::default();
+        add(
+            &mut idx,
+            r"\Docs\Quarterly Report.xlsx",
+            Some("QUARTE~1.XLS"),
+            meta(1, 0),
+        );
+        let short = U16String::from_str("PROGRA~1");
+        idx.insert_dir(
+            &U16String::from_str(r"\Program Files"),
+            Some(&short),
+            meta(2, 0),
+        );
+        add(&mut idx, r"\Program Files\app.exe", None, meta(3, 0));
+
+        assert_eq!(mft_at(&idx, r"\Docs\quarte~1.xls"), Some(1));
+        assert_eq!(mft_at(&idx, r"\PROGRA~1\APP.EXE"), Some(3));
+        // 一覧には短い名前は出ない
+        assert_eq!(child_names(&idx, r"\Docs"), vec!["Quarterly Report.xlsx"]);
+    }
+
+    #[test]
+    fn long_names_win_over_short_names() {
+        let mut idx = DeletedIndex::default();
+        add(&mut idx, r"\d\LONGNA~1.TXT", None, meta(1, 0));
+        add(
+            &mut idx,
+            r"\d\long one.txt",
+            Some("LONGNA~1.TXT"),
+            meta(2, 0),
+        );
+        add(
+            &mut idx,
+            r"\d\long two.txt",
+            Some("LONGNA~2.TXT"),
+            meta(3, 0),
+        );
+        add(
+            &mut idx,
+            r"\d\long 2b.txt",
+            Some("LONGNA~2.TXT"),
+            meta(4, 0),
+        );
+
+        // 同じ長い名前があれば短い名前は登録しない
+        assert_eq!(mft_at(&idx, r"\d\longna~1.txt"), Some(1));
+        // 短い名前が重なれば先に登録された方
+        assert_eq!(mft_at(&idx, r"\d\longna~2.txt"), Some(3));
+    }
+
+    #[test]
+    fn spilled_index_matches_in_memory() {
+        let budget = SpillBudget {
+            bytes: 4096,
+            dir: Some(std::env::temp_dir()),
+        };
+        let mut mem = DeletedIndex::default();
+        let mut spilled = DeletedIndex::new(Some(budget), upcase::active());
+        for idx in [&mut mem, &mut spilled] {
+            for n in 0..3000u64 {
+                let path = format!(r"\dir{}\File {}.txt", n % 30, n / 2 % 40);
+                let short = format!("FILE~{}.TXT", n);
+                add(idx, &path, Some(&short), meta(n + 100, n));
+            }
+            idx.finalize_names(DupPolicy::default());
+        }
+        assert!(spilled.spill.is_some() && spilled.nodes.is_spilled());
+        assert!(spilled.long_names.heads.is_spilled());
+        assert_eq!(mem.len(), spilled.len());
+        for d in 0..30 {
+            let dir = format!(r"\DIR{}", d);
+            assert_eq!(child_names(&mem, &dir), child_names(&spilled, &dir));
+            for name in child_names(&mem, &dir) {
+                let path = format!(r"{}\{}", dir, name.to_uppercase());
+                assert_eq!(mft_at(&mem, &path), mft_at(&spilled, &path));
+            }
+        }
+        for n in [0u64, 1, 1500, 2999] {
+            let path = format!(r"\dir{}\file~{}.txt", n % 30, n);
+            assert_eq!(mft_at(&spilled, &path), mft_at(&mem, &path));
+        }
+        // 重なった名前は付け直してある
+        assert_eq!(mft_at(&spilled, r"\dir0\File 0_2.txt"), Some(340));
+    }
+
+    #[test]
+    fn untyped_files_group_hard_links() {
+        let mut idx = DeletedIndex::default();
+        let linked = EntryMeta {
+            link_count: 2,
+            ..meta(1, 0)
+        };
+        add(&mut idx, r"\a\one.bin", None, linked.clone());
+        add(&mut idx, r"\b\one link.bin", None, linked);
+        add(&mut idx, r"\a\two.bin", None, meta(2, 0));
+        // 空のファイルは読まない
+        add(
+            &mut idx,
+            r"\a\empty.bin",
+            None,
+            EntryMeta {
+                size: 0,
+                ..meta(3, 0)
+            },
+        );
+
+        let todo = idx.untyped_files();
+        let groups: Vec<(u64, usize)> = todo.iter().map(|(n, ids)| (*n, ids.len())).collect();
+        assert_eq!(groups, vec![(1, 2), (2, 1)]);
+        // ハードリンクは別のフォルダにあっても同じ組
+        let paths: Vec<String> = todo[0]
+            .1
+            .iter()
+            .map(|&id| idx.path_of(id).to_string_lossy())
+            .collect();
+        assert_eq!(paths, vec![r"\a\one.bin", r"\b\one link.bin"]);
+        assert_eq!(idx.lookup(&w(r"\a\two.bin")), Some(todo[1].1[0]));
+
+        let jpeg = sniff(b"\xFF\xD8\xFF\xE0").unwrap();
+        idx.set_content_type(&todo[0].1, jpeg);
+        assert_eq!(idx.untyped_files().len(), 1);
+        let id = idx.lookup(&w(r"\b\one link.bin")).unwrap();
+        match idx.entry(id).as_deref() {
+            Some(EntryOrDir::File(m)) => assert_eq!(m.content_type, Some(jpeg)),
+            _ => panic!("not a file"),
+        }
+    }
 }
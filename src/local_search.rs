@@ -0,0 +1,628 @@
+use crate::filetype::FileFamily;
+use crate::indexer::{DeletedIndex, EntryMeta, EntryOrDir, NodeId};
+use crate::query::{self, QueryHit, TimeField};
+use crate::recoverability::Recoverability;
+use crate::timestamps::{days_from_civil, filetime_to_system_time, unix_to_civil};
+use crate::util::system_time_to_unix_parts;
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serde::Serialize;
+
+// 自然文での検索 (「最近開いたエクセル」「last week's photos」など) をネットワークを使わずに行う
+// 決まった言い回しから 種類・日時の範囲・どの時刻か を読み取り、残りの語は名前のキーワードにする
+// 各ファイルに 名前・種類・新しさ で点数を付けて上位を返す
+
+#[derive(Debug)]
+struct TypeRule {
+    // 読み取り結果の表示用
+    label: &'static str,
+    // この種類を指す語 (英数字の語は単語単位で照合)
+    words: &'static [&'static str],
+    exts: &'static [&'static str],
+    // 中身から判定した MIME タイプに含まれる文字列 (拡張子が無い/違うファイル用)
+    mimes: &'static [&'static str],
+    family: Option<FileFamily>,
+}
+
+const TYPE_RULES: &[TypeRule] = &[
+    TypeRule {
+        label: "excel",
+        words: &[
+            "エクセル",
+            "表計算",
+            "スプレッドシート",
+            "excel",
+            "spreadsheet",
+            "spreadsheets",
+            "xlsx",
+            "xls",
+            "csv",
+        ],
+        exts: &["xlsx", "xls", "xlsm", "xlsb", "xltx", "xltm", "csv", "ods"],
+        mimes: &["spreadsheet", "ms-excel"],
+        family: None,
+    },
+    TypeRule {
+        label: "word",
+        words: &["ワード", "word", "docx", "doc"],
+        exts: &["docx", "doc", "docm", "odt", "rtf"],
+        mimes: &["wordprocessing", "msword", "opendocument.text"],
+        family: None,
+    },
+    TypeRule {
+        label: "powerpoint",
+        words: &[
+            "パワーポイント",
+            "パワポ",
+            "スライド",
+            "プレゼン",
+            "powerpoint",
+            "slides",
+            "presentation",
+            "pptx",
+            "ppt",
+        ],
+        exts: &["pptx", "ppt", "pptm", "odp", "key"],
+        mimes: &["presentation", "powerpoint"],
+        family: None,
+    },
+    TypeRule {
+        label: "pdf",
+        words: &["pdf"],
+        exts: &["pdf"],
+        mimes: &["pdf"],
+        family: None,
+    },
+    TypeRule {
+        label: "image",
+        words: &[
+            "写真",
+            "画像",
+            "スクリーンショット",
+            "スクショ",
+            "photo",
+            "photos",
+            "picture",
+            "pictures",
+            "image",
+            "images",
+            "screenshot",
+            "screenshots",
+            "jpg",
+            "jpeg",
+            "png",
+        ],
+        exts: &[],
+        mimes: &[],
+        family: Some(FileFamily::Image),
+    },
+    TypeRule {
+        label: "video",
+        words: &[
+            "動画",
+            "ビデオ",
+            "video",
+            "videos",
+            "movie",
+            "movies",
+            "mp4",
+        ],
+        exts: &[],
+        mimes: &[],
+        family: Some(FileFamily::Video),
+    },
+    TypeRule {
+        label: "audio",
+        words: &[
+            "音楽",
+            "音声",
+            "録音",
+            "music",
+            "audio",
+            "song",
+            "songs",
+            "recording",
+            "mp3",
+        ],
+        exts: &[],
+        mimes: &[],
+        family: Some(FileFamily::Audio),
+    },
+    TypeRule {
+        label: "archive",
+        words: &["圧縮", "アーカイブ", "archive", "archives", "zip"],
+        exts: &[],
+        mimes: &[],
+        family: Some(FileFamily::Archive),
+    },
+    TypeRule {
+        label: "text",
+        words: &["テキスト", "メモ", "text", "txt", "note", "notes"],
+        exts: &[],
+        mimes: &[],
+        family: Some(FileFamily::Text),
+    },
+    TypeRule {
+        label: "program",
+        words: &[
+            "プログラム",
+            "インストーラ",
+            "アプリ",
+            "program",
+            "installer",
+            "exe",
+        ],
+        exts: &[],
+        mimes: &[],
+        family: Some(FileFamily::Executable),
+    },
+];
+
+// どの時刻で絞り込むかを表す語 (最初に見つかったもの。無ければ更新時刻)
+const FIELD_WORDS: &[(TimeField, &[&str])] = &[
+    (
+        TimeField::Accessed,
+        &[
+            "開いた",
+            "開いてた",
+            "開いていた",
+            "見た",
+            "閲覧",
+            "opened",
+            "viewed",
+            "accessed",
+        ],
+    ),
+    (TimeField::Created, &["作った", "作成", "created", "made"]),
+    (
+        TimeField::MftChanged,
+        &[
+            "消しちゃった",
+            "消した",
+            "消えた",
+            "削除",
+            "deleted",
+            "removed",
+            "lost",
+        ],
+    ),
+    (
+        TimeField::Modified,
+        &[
+            "更新", "編集", "保存", "変更", "modified", "edited", "saved", "updated", "changed",
+        ],
+    ),
+];
+
+// 名前のキーワードにしない語
+const STOP_WORDS_JA: &[&str] = &[
+    "データ",
+    "ファイル",
+    "フォルダ",
+    "どこ",
+    "どこか",
+    "場所",
+    "全部",
+];
+const STOP_WORDS_EN: &[&str] = &[
+    "the", "a", "an", "my", "me", "i", "of", "from", "in", "on", "at", "for", "to", "where", "is",
+    "are", "was", "were", "that", "this", "these", "those", "it", "its", "find", "show", "get",
+    "search", "look", "looking", "file", "files", "folder", "data", "some", "any", "all", "and",
+    "or", "with", "which", "what", "can", "you", "please", "did", "do", "does", "have", "had", "s",
+    "there", "last", "past", "ago", "recent", "week", "weeks", "day", "days", "month", "months",
+    "year", "years",
+];
+
+const DAY: i64 = 86_400;
+
+#[derive(Debug, Clone, Copy)]
+enum Span {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+// 数字付きの言い回し (「3日前」「past 2 weeks」など)
+// true は n 単位前の1単位分、false は今から n 単位前まで
+static NUMBERED: Lazy<Vec<(Regex, Span, bool)>> = Lazy::new(|| {
+    let n = r"([0-9]+|[一二三四五六七八九十]+)";
+    let months = r"(?:ヶ|か|カ|ケ|箇)月";
+    [
+        (format!(r"{n}\s*日前"), Span::Day, true),
+        (format!(r"{n}\s*days?\s+ago"), Span::Day, true),
+        (format!(r"{n}\s*日(?:以内|間)"), Span::Day, false),
+        (format!(r"(?:last|past)\s+{n}\s+days?"), Span::Day, false),
+        (format!(r"{n}\s*週間?前"), Span::Week, true),
+        (format!(r"{n}\s*weeks?\s+ago"), Span::Week, true),
+        (format!(r"{n}\s*週間(?:以内)?"), Span::Week, false),
+        (format!(r"(?:last|past)\s+{n}\s+weeks?"), Span::Week, false),
+        (format!(r"{n}\s*{months}前"), Span::Month, true),
+        (format!(r"{n}\s*months?\s+ago"), Span::Month, true),
+        (format!(r"{n}\s*{months}(?:以内|間)?"), Span::Month, false),
+        (
+            format!(r"(?:last|past)\s+{n}\s+months?"),
+            Span::Month,
+            false,
+        ),
+        (format!(r"{n}\s*年前"), Span::Year, true),
+        (format!(r"{n}\s*years?\s+ago"), Span::Year, true),
+    ]
+    .into_iter()
+    .map(|(p, span, ago)| (Regex::new(&p).expect("bad time pattern"), span, ago))
+    .collect()
+});
+
+// 決まった言い回し (長いものを先に)。数値は何単位前か
+const FIXED_TIMES: &[(&[&str], Span, i64)] = &[
+    (
+        &["一昨日", "おととい", "day before yesterday"],
+        Span::Day,
+        2,
+    ),
+    (&["昨日", "きのう", "yesterday"], Span::Day, 1),
+    (&["今日", "きょう", "today"], Span::Day, 0),
+    (&["先週", "last week"], Span::Week, 1),
+    (&["今週", "this week"], Span::Week, 0),
+    (&["先月", "last month"], Span::Month, 1),
+    (&["今月", "this month"], Span::Month, 0),
+    (&["去年", "昨年", "last year"], Span::Year, 1),
+    (&["今年", "this year"], Span::Year, 0),
+];
+
+// 「さっき」は直近1日、「最近」は範囲を決めずに新しいものを優先
+const JUST_NOW: &[&str] = &["ついさっき", "今さっき", "さっき", "just now"];
+const RECENT: &[&str] = &[
+    "最近",
+    "最新",
+    "この前",
+    "recently",
+    "recent",
+    "lately",
+    "latest",
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Interpretation {
+    pub types: Vec<&'static str>,
+    pub field: TimeField,
+    // UNIX秒で from 以上 to 未満
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<i64>,
+    pub recent: bool,
+    pub keywords: Vec<String>,
+    #[serde(skip)]
+    rules: Vec<&'static TypeRule>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RankedHit {
+    #[serde(flatten)]
+    pub hit: QueryHit,
+    pub score: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LocalSearchResult {
+    pub interpretation: Interpretation,
+    pub hits: Vec<RankedHit>,
+}
+
+// 全角英数字を半角にして小文字にする
+fn normalize(prompt: &str) -> String {
+    prompt
+        .chars()
+        .map(|c| match c {
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            '\u{3000}' => ' ',
+            _ => c,
+        })
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn parse_number(s: &str) -> Option<i64> {
+    if let Ok(n) = s.parse() {
+        return Some(n);
+    }
+    // 漢数字は九十九まで
+    let digit = |c: char| "一二三四五六七八九".find(c).map(|i| i as i64 / 3 + 1);
+    let chars: Vec<char> = s.chars().collect();
+    match chars.iter().position(|&c| c == '十') {
+        Some(p) => {
+            let tens = match p {
+                0 => 1,
+                1 => digit(chars[0])?,
+                _ => return None,
+            };
+            let ones = match &chars[p + 1..] {
+                [] => 0,
+                [c] => digit(*c)?,
+                _ => return None,
+            };
+            Some(tens * 10 + ones)
+        }
+        None if chars.len() == 1 => digit(chars[0]),
+        None => None,
+    }
+}
+
+// 英数字だけの語は前後が英数字でない場所だけ一致させる
+fn find_word(text: &str, word: &str) -> Option<usize> {
+    if !word.is_ascii() {
+        return text.find(word);
+    }
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    let mut from = 0;
+    while let Some(p) = text[from..].find(word).map(|p| p + from) {
+        let end = p + word.len();
+        if !is_word(text[..p].chars().next_back()) && !is_word(text[end..].chars().next()) {
+            return Some(p);
+        }
+        from = p + 1;
+    }
+    None
+}
+
+// 見つかった語を空白に置き換える (後でキーワードにしないように)
+fn take(text: &mut String, words: &[&str]) -> bool {
+    let mut found = false;
+    for w in words {
+        while let Some(p) = find_word(text, w) {
+            text.replace_range(p..p + w.len(), " ");
+            found = true;
+        }
+    }
+    found
+}
+
+// n 単位前の期間 [from, to) (UNIX秒)。週は月曜始まり
+fn span_ago(span: Span, n: i64, now: i64, utc_offset: i64) -> (i64, i64) {
+    let local = now + utc_offset;
+    let today = local.div_euclid(DAY);
+    let (from_day, to_day) = match span {
+        Span::Day => (today - n, today - n + 1),
+        Span::Week => {
+            // 1970-01-01 は木曜日
+            let monday = today - (today + 3).rem_euclid(7);
+            (monday - 7 * n, monday - 7 * n + 7)
+        }
+        Span::Month => {
+            let c = unix_to_civil(local);
+            let m = c.month as i64 - n;
+            (
+                days_from_civil(c.year, m, 1),
+                days_from_civil(c.year, m + 1, 1),
+            )
+        }
+        Span::Year => {
+            let y = unix_to_civil(local).year - n;
+            (days_from_civil(y, 1, 1), days_from_civil(y + 1, 1, 1))
+        }
+    };
+    (from_day * DAY - utc_offset, to_day * DAY - utc_offset)
+}
+
+fn span_secs(span: Span) -> i64 {
+    match span {
+        Span::Day => DAY,
+        Span::Week => 7 * DAY,
+        Span::Month => 30 * DAY,
+        Span::Year => 365 * DAY,
+    }
+}
+
+// 問い合わせ文を読み取る。now は UNIX秒、utc_offset は現地時刻と UTC の差 (秒、日本なら 32400)
+pub fn interpret(prompt: &str, now: i64, utc_offset: i64) -> Interpretation {
+    let mut text = normalize(prompt);
+    let mut window: Option<(i64, Option<i64>)> = None;
+    let mut recent = false;
+
+    for (re, span, ago) in NUMBERED.iter() {
+        let mut hit = None;
+        text = re
+            .replace_all(&text, |c: &Captures| {
+                hit = hit.or_else(|| parse_number(&c[1]));
+                " "
+            })
+            .into_owned();
+        if let (Some(n), None) = (hit, window) {
+            window = Some(match ago {
+                true => {
+                    let (from, to) = span_ago(*span, n, now, utc_offset);
+                    (from, Some(to))
+                }
+                false => (now - n * span_secs(*span), None),
+            });
+        }
+    }
+    for (words, span, n) in FIXED_TIMES {
+        if take(&mut text, words) && window.is_none() {
+            let (from, to) = span_ago(*span, *n, now, utc_offset);
+            window = Some((from, Some(to)));
+        }
+    }
+    if take(&mut text, JUST_NOW) {
+        recent = true;
+        window = window.or(Some((now - DAY, None)));
+    }
+    recent |= take(&mut text, RECENT);
+
+    let mut field = None;
+    for (f, words) in FIELD_WORDS {
+        if take(&mut text, words) {
+            field = field.or(Some(*f));
+        }
+    }
+    let mut rules = Vec::new();
+    for rule in TYPE_RULES {
+        if take(&mut text, rule.words) {
+            rules.push(rule);
+        }
+    }
+
+    // 残りを区切ってキーワードにする (ひらがなは助詞や送り仮名として区切りに使う)
+    let is_separator = |c: char| {
+        c.is_whitespace()
+            || c.is_ascii_punctuation()
+            || (('\u{3000}'..='\u{309F}').contains(&c) && c != '々')
+            || c == '・'
+    };
+    let mut keywords: Vec<String> = Vec::new();
+    for w in text.split(is_separator) {
+        let long_enough = if w.is_ascii() {
+            w.len() >= 2
+        } else {
+            w.chars().count() >= 2
+        };
+        if long_enough
+            && !STOP_WORDS_JA.contains(&w)
+            && !STOP_WORDS_EN.contains(&w)
+            && !keywords.iter().any(|k| k == w)
+        {
+            keywords.push(w.to_string());
+        }
+    }
+
+    Interpretation {
+        types: rules.iter().map(|r| r.label).collect(),
+        field: field.unwrap_or(TimeField::Modified),
+        from: window.map(|w| w.0),
+        to: window.and_then(|w| w.1),
+        recent,
+        keywords,
+        rules,
+    }
+}
+
+fn extension_lc(name: &str) -> Option<String> {
+    let (stem, ext) = name.rsplit_once('.')?;
+    (!stem.is_empty()).then(|| ext.to_lowercase())
+}
+
+impl Interpretation {
+    // 点数の高い順に最大 limit 件 (点数が 0 のファイルは返さない)
+    pub fn rank(&self, idx: &DeletedIndex, now: i64, limit: usize) -> Vec<(NodeId, f64)> {
+        let mut scored: Vec<(NodeId, f64, i64)> = Vec::new();
+        for id in idx.files(false) {
+            let Some(entry) = idx.entry(id) else {
+                continue;
+            };
+            let EntryOrDir::File(meta) = &*entry else {
+                continue;
+            };
+            let name = String::from_utf16_lossy(idx.name(id).as_slice()).to_lowercase();
+            let mut score = 0.0;
+
+            // 名前 (フォルダ名に含まれる場合は低め)
+            let mut path: Option<String> = None;
+            for kw in &self.keywords {
+                if name.contains(kw.as_str()) {
+                    score += 2.0;
+                } else if path
+                    .get_or_insert_with(|| {
+                        String::from_utf16_lossy(idx.path_of(id).as_slice()).to_lowercase()
+                    })
+                    .contains(kw.as_str())
+                {
+                    score += 1.0;
+                }
+            }
+
+            // 種類
+            if !self.rules.is_empty() {
+                let ext = extension_lc(&name);
+                let mime = meta.content_type.map(|c| c.mime());
+                let family = match meta.content_type {
+                    Some(c) => c.family(),
+                    None => FileFamily::from_name(idx.name(id).as_slice()),
+                };
+                let matches = self.rules.iter().any(|r| {
+                    ext.as_deref().is_some_and(|e| r.exts.contains(&e))
+                        || mime.is_some_and(|m| r.mimes.iter().any(|x| m.contains(x)))
+                        || r.family == Some(family)
+                });
+                if matches {
+                    score += 3.0;
+                } else if self.keywords.is_empty() {
+                    continue;
+                }
+            }
+
+            // キーワードも種類も無い (「昨日消したファイル」など) なら時刻だけで選ぶ
+            let time_only = self.keywords.is_empty() && self.rules.is_empty();
+            if time_only {
+                score = 1.0;
+            } else if score == 0.0 {
+                continue;
+            }
+
+            // 日時
+            let t = meta
+                .times
+                .primary()
+                .map(|t| match self.field {
+                    TimeField::Created => t.created,
+                    TimeField::Modified => t.modified,
+                    TimeField::MftChanged => t.mft_changed,
+                    TimeField::Accessed => t.accessed,
+                })
+                .and_then(filetime_to_system_time)
+                .map(|t| system_time_to_unix_parts(t).0);
+            if let Some(from) = self.from {
+                let inside = t.is_some_and(|t| t >= from && self.to.is_none_or(|to| t < to));
+                if inside {
+                    score += 2.0;
+                } else if time_only {
+                    continue;
+                } else {
+                    score *= 0.25;
+                }
+            }
+            if let Some(t) = t {
+                let age_days = (now - t).max(0) as f64 / DAY as f64;
+                score += if self.recent || self.from.is_some() {
+                    2.0 * (-age_days / 30.0).exp()
+                } else {
+                    0.5 * (-age_days / 365.0).exp()
+                };
+            }
+            scored.push((id, score, t.unwrap_or(i64::MIN)));
+        }
+        // 同じ点数なら新しい順
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.2.cmp(&a.2)).then(a.0.cmp(&b.0)));
+        scored.truncate(limit);
+        scored.into_iter().map(|(id, s, _)| (id, s)).collect()
+    }
+}
+
+// 読み取り + 点数付け + 一覧の項目化
+pub fn search(
+    idx: &DeletedIndex,
+    prompt: &str,
+    now: i64,
+    utc_offset: i64,
+    limit: usize,
+    mut assess: impl FnMut(&EntryMeta) -> Recoverability,
+) -> LocalSearchResult {
+    let interpretation = interpret(prompt, now, utc_offset);
+    let mut hits = Vec::new();
+    for (id, score) in interpretation.rank(idx, now, limit) {
+        let Some(entry) = idx.entry(id) else {
+            continue;
+        };
+        let EntryOrDir::File(meta) = &*entry else {
+            continue;
+        };
+        hits.push(RankedHit {
+            hit: query::hit(idx, id, meta, assess(meta)),
+            score,
+        });
+    }
+    LocalSearchResult {
+        interpretation,
+        hits,
+    }
+}
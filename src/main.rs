@@ -1,16 +1,35 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod dest_guard;
 mod drives;
+mod file_meta;
+mod filetype;
 mod fs;
 mod gui_bridge;
 mod indexer;
+mod local_search;
 mod logging;
+mod manifest;
+mod mft_stream;
+mod query;
+mod record;
+mod recover;
+mod recoverability;
+mod redact;
 mod scan;
+mod search_provider;
+mod snapshot;
+mod spill;
+mod timestamps;
+mod upcase;
 mod util;
 
 use gui_bridge::{
-    build_filelist_cmd, copy_to_desktop_cmd, eject_cmd, list_drives_cmd, open_path_cmd,
-    reveal_in_explorer_cmd, start_mount_cmd, AppState,
+    build_filelist_cmd, cancel_recovery_cmd, check_destination_cmd, copy_to_desktop_cmd,
+    eject_cmd, list_drives_cmd, list_versions_cmd, load_snapshot_cmd, local_search_cmd,
+    open_path_cmd, preview_remote_search_cmd, query_files_cmd, refresh_recoverability_cmd,
+    remote_search_cmd, resume_recovery_cmd, reveal_in_explorer_cmd, save_snapshot_cmd,
+    start_mount_cmd, start_recovery_cmd, AppState,
 };
 
 #[cfg(windows)]
@@ -75,8 +94,20 @@ fn main() {
             start_mount_cmd,
             eject_cmd,
             build_filelist_cmd,
+            list_versions_cmd,
+            query_files_cmd,
+            refresh_recoverability_cmd,
+            local_search_cmd,
+            preview_remote_search_cmd,
+            remote_search_cmd,
+            save_snapshot_cmd,
+            load_snapshot_cmd,
             open_path_cmd,
+            check_destination_cmd,
             copy_to_desktop_cmd,
+            start_recovery_cmd,
+            resume_recovery_cmd,
+            cancel_recovery_cmd,
             reveal_in_explorer_cmd
         ])
         .setup(|app| {
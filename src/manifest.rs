@@ -0,0 +1,330 @@
+use crate::record::{
+    file_names, ref_record, standard_info, FileNameAttr, RecordHeader, NS_DOS, NS_POSIX, NS_WIN32,
+    NS_WIN32_AND_DOS,
+};
+use crate::recoverability::Recoverability;
+use crate::timestamps::{
+    filetime_to_iso, unix_to_civil, CivilTime, FileTimes, RecordTimes, TimestompFlags,
+};
+use crate::util::escape_u16;
+use anyhow::{Context, Result};
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// まとめての復元で書き出したファイルの一覧 (何を取り出し、コピーが改変されていないかの証跡)
+// ハッシュは書き出すときに同じバイト列から計算する (書いた後に読み直さない)
+
+// 一覧の名前 (拡張子なし)。作業ごとに始めた日時を付けて、前の作業の一覧を上書きしない
+pub const MANIFEST_STEM: &str = "recovery-manifest";
+
+fn utc_now() -> Option<CivilTime> {
+    let d = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?;
+    Some(unix_to_civil(d.as_secs() as i64))
+}
+
+// recovery-manifest-YYYYMMDD-HHMMSS (dir に同じ名前の一覧があれば番号を付ける)
+pub fn new_name(dir: &Path) -> String {
+    let base = match utc_now() {
+        Some(c) => format!(
+            "{}-{:04}{:02}{:02}-{:02}{:02}{:02}",
+            MANIFEST_STEM, c.year, c.month, c.day, c.hour, c.minute, c.second
+        ),
+        None => MANIFEST_STEM.to_string(),
+    };
+    let taken = |name: &str| {
+        dir.join(format!("{}.json", name)).exists() || dir.join(format!("{}.csv", name)).exists()
+    };
+    let mut name = base.clone();
+    let mut n = 2usize;
+    while taken(&name) {
+        name = format!("{}-{}", base, n);
+        n += 1;
+    }
+    name
+}
+
+// 書いたバイト列をそのまま SHA-256 と MD5 に通す
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    sha256: Sha256,
+    md5: Md5,
+    written: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            sha256: Sha256::new(),
+            md5: Md5::new(),
+            written: 0,
+        }
+    }
+
+    // (中身, バイト数, SHA-256, MD5) 。ハッシュは小文字の16進
+    pub fn finish(self) -> (W, u64, String, String) {
+        (
+            self.inner,
+            self.written,
+            hex(&self.sha256.finalize()),
+            hex(&self.md5.finalize()),
+        )
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // inner が受け取った分だけを数える
+        let n = self.inner.write(buf)?;
+        self.sha256.update(&buf[..n]);
+        self.md5.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// ISO 8601 (UTC)。レコードに無ければ None
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ManifestTimes {
+    pub created: Option<String>,
+    pub modified: Option<String>,
+    pub mft_changed: Option<String>,
+    pub accessed: Option<String>,
+}
+
+impl ManifestTimes {
+    fn new(t: Option<FileTimes>) -> Self {
+        let Some(t) = t else {
+            return Self::default();
+        };
+        Self {
+            created: filetime_to_iso(t.created),
+            modified: filetime_to_iso(t.modified),
+            mft_changed: filetime_to_iso(t.mft_changed),
+            accessed: filetime_to_iso(t.accessed),
+        }
+    }
+}
+
+// $FILE_NAME 1つ分 (ハードリンクと8.3形式の名前はそれぞれ別)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestFileName {
+    // エスケープ済み
+    pub name: String,
+    // posix / win32 / dos / win32_and_dos (知らない値は数字のまま)
+    pub namespace: String,
+    pub parent_mft_no: u64,
+    pub times: ManifestTimes,
+}
+
+impl ManifestFileName {
+    fn new(f: &FileNameAttr) -> Self {
+        let namespace = match f.namespace {
+            NS_POSIX => "posix".to_string(),
+            NS_WIN32 => "win32".to_string(),
+            NS_DOS => "dos".to_string(),
+            NS_WIN32_AND_DOS => "win32_and_dos".to_string(),
+            n => n.to_string(),
+        };
+        Self {
+            name: escape_u16(&f.name),
+            namespace,
+            parent_mft_no: ref_record(f.parent_ref),
+            times: ManifestTimes::new(Some(FileTimes::from(f))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    // R:\... のパス (エスケープ済み)
+    pub source: String,
+    pub mft_no: u64,
+    pub sequence: u16,
+    // 一覧上の大きさと、実際に書き出したバイト数
+    pub size: u64,
+    pub written: u64,
+    // $STANDARD_INFORMATION の時刻と、レコードにある全ての $FILE_NAME
+    pub si_times: ManifestTimes,
+    #[serde(default)]
+    pub file_names: Vec<ManifestFileName>,
+    // 全ての FN と比べた時刻の改ざんの疑い
+    pub timestomp: TimestompFlags,
+    pub recoverability: Recoverability,
+    // 書き出した先 (エスケープ済み)
+    pub dest: String,
+    pub sha256: String,
+    pub md5: String,
+}
+
+impl ManifestEntry {
+    // rec は書き出しに使ったレコード (時刻とシーケンス番号をそこから取る)
+    pub fn new(source: &str, mft_no: u64, size: u64, rec: &[u8]) -> Self {
+        let si = standard_info(rec);
+        let names = file_names(rec);
+        Self {
+            source: source.to_string(),
+            mft_no,
+            sequence: RecordHeader::parse(rec).map_or(0, |h| h.sequence),
+            size,
+            written: 0,
+            si_times: ManifestTimes::new(si.as_ref().map(FileTimes::from)),
+            file_names: names.iter().map(ManifestFileName::new).collect(),
+            timestomp: RecordTimes::new(si.as_ref(), &names).stomp,
+            recoverability: Recoverability::Unknown,
+            dest: String::new(),
+            sha256: String::new(),
+            md5: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ManifestFile<'a> {
+    tool: String,
+    generated_at: Option<String>,
+    device: &'a str,
+    destination: &'a str,
+    // 取り消しや失敗が残っていれば false (続きから再開すると書き直される)
+    complete: bool,
+    files: &'a [ManifestEntry],
+}
+
+// destination 直下に name.json と name.csv を書き、JSON のパスを返す
+// name は作業ごとに new_name で決めたもの (同じ作業を再開したときだけ置き換える)
+pub fn write(
+    dir: &Path,
+    name: &str,
+    device: &str,
+    destination: &str,
+    complete: bool,
+    entries: &[ManifestEntry],
+) -> Result<PathBuf> {
+    let generated_at = utc_now().map(|c| {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            c.year, c.month, c.day, c.hour, c.minute, c.second
+        )
+    });
+    let doc = ManifestFile {
+        tool: format!("RecoveryMagic {}", env!("CARGO_PKG_VERSION")),
+        generated_at,
+        device,
+        destination,
+        complete,
+        files: entries,
+    };
+    let json = dir.join(format!("{}.json", name));
+    let mut w =
+        BufWriter::new(File::create(&json).with_context(|| format!("create {}", json.display()))?);
+    serde_json::to_writer_pretty(&mut w, &doc)?;
+    w.flush()?;
+
+    let csv = dir.join(format!("{}.csv", name));
+    let mut w =
+        BufWriter::new(File::create(&csv).with_context(|| format!("create {}", csv.display()))?);
+    write_csv(&mut w, entries)?;
+    w.flush()?;
+    Ok(json)
+}
+
+const CSV_HEADER: &[&str] = &[
+    "source",
+    "mft_no",
+    "sequence",
+    "size",
+    "written",
+    "si_created",
+    "si_modified",
+    "si_mft_changed",
+    "si_accessed",
+    "fn_names",
+    "fn_namespaces",
+    "fn_created",
+    "fn_modified",
+    "fn_mft_changed",
+    "fn_accessed",
+    "si_before_fn",
+    "si_zero_subsec",
+    "recoverability",
+    "dest",
+    "sha256",
+    "md5",
+];
+
+fn write_csv(w: &mut impl Write, entries: &[ManifestEntry]) -> Result<()> {
+    // Excel で文字化けしないように BOM を付ける
+    w.write_all("\u{FEFF}".as_bytes())?;
+    writeln!(w, "{}", CSV_HEADER.join(","))?;
+    let time = |t: &Option<String>| t.clone().unwrap_or_default();
+    for e in entries {
+        // $FILE_NAME は1つの欄に ';' 区切りで並べる (どの欄も同じ順。名前の正確な区切りは JSON で)
+        let names = |f: &dyn Fn(&ManifestFileName) -> String| {
+            e.file_names.iter().map(f).collect::<Vec<_>>().join(";")
+        };
+        let recoverability = recoverability_label(&e.recoverability);
+        let row = [
+            e.source.clone(),
+            e.mft_no.to_string(),
+            e.sequence.to_string(),
+            e.size.to_string(),
+            e.written.to_string(),
+            time(&e.si_times.created),
+            time(&e.si_times.modified),
+            time(&e.si_times.mft_changed),
+            time(&e.si_times.accessed),
+            names(&|f| f.name.clone()),
+            names(&|f| f.namespace.clone()),
+            names(&|f| time(&f.times.created)),
+            names(&|f| time(&f.times.modified)),
+            names(&|f| time(&f.times.mft_changed)),
+            names(&|f| time(&f.times.accessed)),
+            e.timestomp.si_before_fn.to_string(),
+            e.timestomp.si_zero_subsec.to_string(),
+            recoverability,
+            e.dest.clone(),
+            e.sha256.clone(),
+            e.md5.clone(),
+        ];
+        let row: Vec<String> = row.iter().map(|f| csv_field(f)).collect();
+        writeln!(w, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+// JSON の "kind" と同じ名前 (一部上書きは上書きされたクラスタ数も)
+fn recoverability_label(r: &Recoverability) -> String {
+    match r {
+        Recoverability::Partial {
+            overwritten_clusters,
+            total_clusters,
+        } => format!("partial ({}/{})", overwritten_clusters, total_clusters),
+        other => serde_json::to_value(other)
+            .ok()
+            .and_then(|v| v.get("kind")?.as_str().map(str::to_string))
+            .unwrap_or_default(),
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
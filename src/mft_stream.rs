@@ -0,0 +1,519 @@
+use crate::record::{
+    self, apply_fixup, best_file_name, decode_runs, ref_record, ref_sequence, RecordHeader,
+    ATTR_DATA, ROOT_RECORD,
+};
+use crate::timestamps::FileTimes;
+use anyhow::{bail, Context, Result};
+use ntfs_reader::mft::Mft;
+use parking_lot::Mutex;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// $MFT を全件メモリに載せずに、ウィンドウ単位でデバイスから直接読むためのリーダー
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootInfo {
+    pub bytes_per_sector: u64,
+    pub cluster_size: u64,
+    pub record_size: u64,
+    pub mft_lcn: u64,
+    // ボリュームのシリアル番号と全セクタ数 (スナップショットが同じボリュームのものか確かめる)
+    pub serial: u64,
+    pub total_sectors: u64,
+}
+
+impl BootInfo {
+    pub fn parse(b: &[u8]) -> Result<Self> {
+        if b.len() < 512 || &b[3..11] != b"NTFS    " {
+            bail!("not an NTFS boot sector");
+        }
+        let bytes_per_sector = record::read_u16(b, 0x0B) as u64;
+        let sectors_per_cluster = b[0x0D] as u64;
+        if bytes_per_sector == 0 || sectors_per_cluster == 0 {
+            bail!("invalid NTFS geometry");
+        }
+        let cluster_size = bytes_per_sector * sectors_per_cluster;
+        let raw = b[0x40] as i8;
+        let record_size = if raw > 0 {
+            raw as u64 * cluster_size
+        } else {
+            1u64 << (-(raw as i32)) as u32
+        };
+        Ok(Self {
+            bytes_per_sector,
+            cluster_size,
+            record_size,
+            mft_lcn: record::read_u64(b, 0x30),
+            serial: record::read_u64(b, 0x48),
+            total_sectors: record::read_u64(b, 0x28),
+        })
+    }
+}
+
+pub struct MftStream {
+    pub boot: BootInfo,
+    pub max_record: u64,
+    extents: Vec<(u64, u64)>,
+    reader: Mutex<File>,
+}
+
+impl MftStream {
+    pub fn open(device: &str) -> Result<Self> {
+        let mut f = File::options()
+            .read(true)
+            .open(device)
+            .with_context(|| format!("open device for MFT streaming: {}", device))?;
+        let mut sector = vec![0u8; 4096];
+        f.read_exact(&mut sector).context("read boot sector")?;
+        let boot = BootInfo::parse(&sector)?;
+
+        // $MFT 自身のレコード (0番) から $DATA のランリストを取り出す
+        let mut rec0 = read_aligned(
+            &mut f,
+            boot.mft_lcn * boot.cluster_size,
+            boot.record_size as usize,
+            boot.bytes_per_sector,
+        )?;
+        if !apply_fixup(&mut rec0) {
+            bail!("corrupted $MFT record");
+        }
+        let data = record::attributes(&rec0)
+            .find(|a| a.type_code == ATTR_DATA && a.name_len == 0)
+            .context("$MFT has no $DATA attribute")?;
+        let runs = data.run_list().context("$MFT $DATA is resident")?;
+        let mft_bytes = data.non_resident_real_size().unwrap_or(0);
+        let mut extents = Vec::new();
+        for (lcn, len) in decode_runs(runs) {
+            let lcn = match lcn {
+                Some(l) => l,
+                None => bail!("sparse run in $MFT"),
+            };
+            extents.push((lcn * boot.cluster_size, len * boot.cluster_size));
+        }
+        let covered: u64 = extents.iter().map(|e| e.1).sum();
+        let max_record = std::cmp::min(mft_bytes, covered) / boot.record_size;
+        Ok(Self {
+            boot,
+            max_record,
+            extents,
+            reader: Mutex::new(f),
+        })
+    }
+
+    pub fn mft_bytes(&self) -> u64 {
+        self.max_record * self.boot.record_size
+    }
+
+    // start から最大 count 件のレコードをまとめて読む
+    // 返り値は record_size ごとに区切られたバッファ (fixup 済み、壊れたレコードはゼロ埋め)
+    pub fn read_window(&self, start: u64, count: u64) -> Result<Vec<u8>> {
+        let rs = self.boot.record_size;
+        let end = std::cmp::min(self.max_record, start + count);
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let mut out = Vec::with_capacity(((end - start) * rs) as usize);
+        let mut rec_no = start;
+        let mut reader = self.reader.lock();
+        while rec_no < end {
+            let (disk_off, contiguous) = self.locate(rec_no)?;
+            let n = std::cmp::min(contiguous, end - rec_no);
+            let buf = read_aligned(
+                &mut reader,
+                disk_off,
+                (n * rs) as usize,
+                self.boot.bytes_per_sector,
+            )?;
+            out.extend_from_slice(&buf);
+            rec_no += n;
+        }
+        drop(reader);
+        for rec in out.chunks_mut(rs as usize) {
+            if !apply_fixup(rec) {
+                rec.iter_mut().for_each(|b| *b = 0);
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn read_record(&self, number: u64) -> Option<Vec<u8>> {
+        let buf = self.read_window(number, 1).ok()?;
+        if buf.is_empty() {
+            None
+        } else {
+            Some(buf)
+        }
+    }
+
+    // ランのバイト位置 (スパースなら None) と長さ
+    // 削除済みレコードのランは壊れていることがあるので、桁あふれやボリュームの外を指すものは断る
+    fn run_extent(&self, lcn: Option<u64>, len: u64) -> Result<(Option<u64>, u64)> {
+        let cs = self.boot.cluster_size;
+        let bytes = len
+            .checked_mul(cs)
+            .with_context(|| format!("data run too long ({} clusters)", len))?;
+        let Some(lcn) = lcn else {
+            return Ok((None, bytes));
+        };
+        let volume = self
+            .boot
+            .total_sectors
+            .saturating_mul(self.boot.bytes_per_sector);
+        match lcn.checked_mul(cs) {
+            Some(start) if start.checked_add(bytes).is_some_and(|end| end <= volume) => {
+                Ok((Some(start), bytes))
+            }
+            _ => bail!(
+                "data run outside the volume (lcn {}, {} clusters)",
+                lcn,
+                len
+            ),
+        }
+    }
+
+    // 非常駐属性のランリストに従って先頭から size バイトまで読む (スパースはゼロ)
+    pub fn read_nonresident(&self, runs: &[u8], size: u64) -> Result<Vec<u8>> {
+        let mut out: Vec<u8> = Vec::new();
+        let mut reader = self.reader.lock();
+        for (lcn, len) in decode_runs(runs) {
+            let remain = size - out.len() as u64;
+            if remain == 0 {
+                break;
+            }
+            let (start, bytes) = self.run_extent(lcn, len)?;
+            let n = std::cmp::min(bytes, remain) as usize;
+            match start {
+                Some(start) => {
+                    let buf = read_aligned(&mut reader, start, n, self.boot.bytes_per_sector)?;
+                    out.extend_from_slice(&buf);
+                }
+                None => out.resize(out.len() + n, 0),
+            }
+        }
+        Ok(out)
+    }
+
+    // レコードの無名 $DATA の先頭を最大 max バイト読む
+    pub fn read_data_head(&self, rec: &[u8], max: usize) -> Option<Vec<u8>> {
+        let data = record::attributes(rec).find(|a| a.type_code == ATTR_DATA && a.name_len == 0)?;
+        match (data.run_list(), data.resident_value()) {
+            (Some(runs), _) => {
+                let size = std::cmp::min(data.non_resident_real_size()?, max as u64);
+                self.read_nonresident(runs, size).ok()
+            }
+            (None, Some(v)) => Some(v[..std::cmp::min(v.len(), max)].to_vec()),
+            (None, None) => None,
+        }
+    }
+
+    // レコードの無名 $DATA を全部 out に書き出す (大きなファイルもメモリに載せずに少しずつ)
+    // on_chunk は書き終えた合計バイト数を受け取り、false を返すと中断する (戻り値は None)
+    pub fn copy_data(
+        &self,
+        rec: &[u8],
+        out: &mut impl Write,
+        mut on_chunk: impl FnMut(u64) -> bool,
+    ) -> Result<Option<u64>> {
+        const CHUNK: u64 = 4 << 20;
+        let data = record::attributes(rec)
+            .find(|a| a.type_code == ATTR_DATA && a.name_len == 0)
+            .context("no $DATA attribute")?;
+        let runs = match (data.run_list(), data.resident_value()) {
+            (Some(runs), _) => runs,
+            (None, Some(v)) => {
+                out.write_all(v)?;
+                return Ok(on_chunk(v.len() as u64).then_some(v.len() as u64));
+            }
+            (None, None) => bail!("unreadable $DATA attribute"),
+        };
+        let size = data.non_resident_real_size().context("bad $DATA size")?;
+        let mut written = 0u64;
+        for (lcn, len) in decode_runs(runs) {
+            if written >= size {
+                break;
+            }
+            let (start, bytes) = self.run_extent(lcn, len)?;
+            let mut run_off = 0u64;
+            while run_off < bytes && written < size {
+                let n = (bytes - run_off).min(CHUNK).min(size - written);
+                match start {
+                    Some(start) => {
+                        let buf = read_aligned(
+                            &mut self.reader.lock(),
+                            start + run_off,
+                            n as usize,
+                            self.boot.bytes_per_sector,
+                        )?;
+                        out.write_all(&buf)?;
+                    }
+                    None => out.write_all(&vec![0u8; n as usize])?,
+                }
+                run_off += n;
+                written += n;
+                if !on_chunk(written) {
+                    return Ok(None);
+                }
+            }
+        }
+        if written < size {
+            bail!("data runs end before the file size");
+        }
+        Ok(Some(written))
+    }
+
+    // レコード番号 -> (デバイス上のバイトオフセット, 同じエクステント内で連続するレコード数)
+    fn locate(&self, number: u64) -> Result<(u64, u64)> {
+        let rs = self.boot.record_size;
+        let mut vbyte = number * rs;
+        for &(off, len) in &self.extents {
+            if vbyte < len {
+                return Ok((off + vbyte, (len - vbyte) / rs));
+            }
+            vbyte -= len;
+        }
+        bail!("record {} is outside of $MFT", number)
+    }
+}
+
+// デバイスはセクタ境界でしか読めないので、前後をセクタ単位に広げて読む
+fn read_aligned(f: &mut File, offset: u64, len: usize, sector: u64) -> Result<Vec<u8>> {
+    let start = offset - offset % sector;
+    let end = offset + len as u64;
+    let end = end.div_ceil(sector) * sector;
+    let mut buf = vec![0u8; (end - start) as usize];
+    f.seek(SeekFrom::Start(start))?;
+    f.read_exact(&mut buf)
+        .with_context(|| format!("read {} bytes at {}", buf.len(), start))?;
+    let head = (offset - start) as usize;
+    Ok(buf[head..head + len].to_vec())
+}
+
+// Dokan側がレコードを取り出す元
+// 通常は従来通りの全件読み込み、ストリーミング時は必要になった時点でデバイスから読む
+pub enum RecordSource {
+    Loaded(Mft),
+    Streamed(MftStream),
+    // スナップショットに保存しておいたレコード (番号 -> レコード)
+    Saved(HashMap<u64, Vec<u8>>),
+}
+
+impl RecordSource {
+    pub fn record_data(&self, number: u64) -> Option<Cow<'_, [u8]>> {
+        match self {
+            RecordSource::Loaded(mft) => Some(Cow::Borrowed(mft.get_record_data(number))),
+            RecordSource::Streamed(s) => s.read_record(number).map(Cow::Owned),
+            RecordSource::Saved(records) => records.get(&number).map(|r| Cow::Borrowed(&r[..])),
+        }
+    }
+}
+
+// レコード番号 -> (親参照, 名前) の小さな表
+// ディレクトリのレコードだけを番号順に保持し、二分探索で引く
+// 削除済みのディレクトリも含める (シーケンス番号で親子関係が正しいかを確かめる)
+struct DirSlot {
+    record: u64,
+    parent_ref: u64,
+    name_off: u32,
+    name_len: u16,
+    sequence: u16,
+    in_use: bool,
+    // SI の時刻 (無ければ FN) と属性
+    times: FileTimes,
+    attributes: u32,
+}
+
+impl DirSlot {
+    // 子から見た親参照のシーケンス番号がこのレコードと合っているか
+    // 削除時にシーケンス番号が1つ進むので、削除済みなら +1 も許す
+    fn matches(&self, seq: u16) -> bool {
+        if seq == 0 || self.sequence == seq {
+            return true;
+        }
+        !self.in_use && self.sequence == seq.wrapping_add(1)
+    }
+}
+
+// ディレクトリ1件分の情報
+#[derive(Debug, Clone, Copy)]
+pub struct DirInfo {
+    pub parent_ref: u64,
+    pub sequence: u16,
+    pub in_use: bool,
+    pub times: FileTimes,
+    pub attributes: u32,
+}
+
+// 親参照を辿った結果
+pub enum ParentPath {
+    // ルートまで辿れた (ルート直下なら空)
+    Rooted(Vec<u16>),
+    // 途中で切れた
+    // broken は辿れなかった親のレコード番号、path はそこから下で辿れた部分
+    // reused はそのレコードが別のファイル/フォルダに再利用されていた場合 true
+    Orphan {
+        broken: u64,
+        path: Vec<u16>,
+        reused: bool,
+    },
+}
+
+#[derive(Default)]
+pub struct DirTable {
+    slots: Vec<DirSlot>,
+    names: Vec<u16>,
+    // 全レコードの (シーケンス番号, 使用中か) (読めなかったレコードは番号 0)
+    headers: Vec<(u16, bool)>,
+}
+
+impl DirTable {
+    pub fn build(stream: &MftStream, window: u64) -> Result<Self> {
+        let mut t = DirTable::default();
+        let rs = stream.boot.record_size as usize;
+        let mut start = 0u64;
+        while start < stream.max_record {
+            if crate::scan::CANCEL.load(std::sync::atomic::Ordering::Relaxed) {
+                break;
+            }
+            let buf = stream.read_window(start, window)?;
+            for (i, rec) in buf.chunks(rs).enumerate() {
+                t.push_record(start + i as u64, rec);
+            }
+            start += window;
+        }
+        t.slots.shrink_to_fit();
+        t.names.shrink_to_fit();
+        t.headers.shrink_to_fit();
+        Ok(t)
+    }
+
+    // 全件読み込み済みの Mft から作る
+    pub fn from_mft(mft: &Mft) -> Self {
+        let mut t = DirTable::default();
+        for number in 0..mft.max_record {
+            if crate::scan::CANCEL.load(std::sync::atomic::Ordering::Relaxed) {
+                break;
+            }
+            t.push_record(number, mft.get_record_data(number));
+        }
+        t.slots.shrink_to_fit();
+        t.names.shrink_to_fit();
+        t.headers.shrink_to_fit();
+        t
+    }
+
+    fn push_record(&mut self, number: u64, rec: &[u8]) {
+        let h = match RecordHeader::parse(rec) {
+            Some(h) => h,
+            None => return,
+        };
+        if self.headers.len() <= number as usize {
+            self.headers.resize(number as usize + 1, (0, false));
+        }
+        self.headers[number as usize] = (h.sequence, h.is_used());
+        if !h.is_directory() || !h.is_base() {
+            return;
+        }
+        if let Some(fname) = best_file_name(rec) {
+            let si = record::standard_info(rec);
+            self.slots.push(DirSlot {
+                record: number,
+                parent_ref: fname.parent_ref,
+                name_off: self.names.len() as u32,
+                name_len: fname.name.len() as u16,
+                sequence: h.sequence,
+                in_use: h.is_used(),
+                times: si
+                    .as_ref()
+                    .map(FileTimes::from)
+                    .unwrap_or_else(|| FileTimes::from(&fname)),
+                attributes: si.map(|s| s.file_attributes).unwrap_or(0),
+            });
+            self.names.extend_from_slice(&fname.name);
+        }
+    }
+
+    fn find(&self, record: u64) -> Option<&DirSlot> {
+        self.slots
+            .binary_search_by_key(&record, |s| s.record)
+            .ok()
+            .map(|i| &self.slots[i])
+    }
+
+    // 全ディレクトリの (レコード番号, 親のレコード番号, 名前)
+    pub fn entries(&self) -> impl Iterator<Item = (u64, u64, &[u16])> + '_ {
+        self.slots.iter().map(|s| {
+            let off = s.name_off as usize;
+            (
+                s.record,
+                ref_record(s.parent_ref),
+                &self.names[off..off + s.name_len as usize],
+            )
+        })
+    }
+
+    pub fn dir_info(&self, record: u64) -> Option<DirInfo> {
+        let s = self.find(record)?;
+        Some(DirInfo {
+            parent_ref: s.parent_ref,
+            sequence: s.sequence,
+            in_use: s.in_use,
+            times: s.times,
+            attributes: s.attributes,
+        })
+    }
+
+    // 親参照からルートまで辿ってパスを組み立てる
+    // レコードが見つからない、シーケンス番号が合わない、循環している場合はそこで打ち切る
+    // 名前はUTF-16のまま繋ぐ
+    pub fn resolve(&self, parent_ref: u64) -> ParentPath {
+        let mut comps: Vec<&[u16]> = Vec::new();
+        let mut cur = parent_ref;
+        let broken = loop {
+            let rec = ref_record(cur);
+            if rec == ROOT_RECORD {
+                break None;
+            }
+            if comps.len() > 1024 {
+                break Some((rec, false));
+            }
+            let slot = match self.find(rec) {
+                Some(s) if s.matches(ref_sequence(cur)) => s,
+                _ => break Some((rec, self.is_reused(cur))),
+            };
+            let off = slot.name_off as usize;
+            comps.push(&self.names[off..off + slot.name_len as usize]);
+            cur = slot.parent_ref;
+        };
+        let mut path: Vec<u16> = Vec::new();
+        for c in comps.iter().rev() {
+            path.push(b'\\' as u16);
+            path.extend_from_slice(c);
+        }
+        match broken {
+            None => ParentPath::Rooted(path),
+            Some((broken, reused)) => ParentPath::Orphan {
+                broken,
+                path,
+                reused,
+            },
+        }
+    }
+
+    // 参照先のレコードが今は別のシーケンス番号で使われているか
+    // (レコード自体が読めない場合は再利用とは言えないので false)
+    fn is_reused(&self, reference: u64) -> bool {
+        let want = ref_sequence(reference);
+        match self.headers.get(ref_record(reference) as usize) {
+            Some(&(seq, in_use)) if seq != 0 && want != 0 => {
+                if in_use {
+                    seq != want
+                } else {
+                    seq != want && seq != want.wrapping_add(1)
+                }
+            }
+            _ => false,
+        }
+    }
+}
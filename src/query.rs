@@ -0,0 +1,746 @@
+use crate::filetype::FileFamily;
+use crate::indexer::{DeletedIndex, EntryMeta, EntryOrDir, LiveMatch, NodeId};
+use crate::recoverability::{Recoverability, RecoverabilityKind};
+use crate::timestamps::{filetime_to_system_time, FileTimes, TimestompFlags};
+use crate::util::{escape_u16, system_time_to_unix_parts, unescape_u16};
+use anyhow::{Context, Result};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+// 削除済みファイルの検索
+// 名前・拡張子・サイズ・日時・フォルダ・復元可否・種類で絞り込み、並べ替えてページ単位で返す
+// 条件は全て AND、同じ条件の中の複数の値 (拡張子や種類) は OR
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Query {
+    // ワイルドカード (* と ?)。大文字小文字は区別しない
+    pub name_glob: Option<String>,
+    // 正規表現。大文字小文字は区別しない
+    pub name_regex: Option<String>,
+    // "xlsx" でも ".xlsx" でもよい
+    pub extensions: Vec<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub time_ranges: Vec<TimeRange>,
+    // このフォルダ以下だけ (R:\ 以下のパス、エスケープ済みでもよい)
+    pub path_prefix: Option<String>,
+    pub recoverability: Vec<RecoverabilityKind>,
+    pub families: Vec<FileFamily>,
+    // 中身から判定した MIME タイプ (判定できていないファイルは一致しない)
+    pub mimes: Vec<String>,
+    // 時刻の改ざんの疑い (どれかに当てはまるもの)
+    pub timestomp: Vec<TimestompRule>,
+    // \$Live 以下の削除されていないファイルも含める
+    pub include_live: bool,
+    pub sort: SortKey,
+    pub descending: bool,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeField {
+    Created,
+    Modified,
+    // レコードの更新時刻 (削除された時刻の目安)
+    #[serde(alias = "deleted")]
+    MftChanged,
+    Accessed,
+}
+
+// UNIX秒で from 以上 to 未満。時刻が無いファイルは一致しない
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct TimeRange {
+    pub field: TimeField,
+    #[serde(default)]
+    pub from: Option<i64>,
+    #[serde(default)]
+    pub to: Option<i64>,
+}
+
+// TimestompFlags の項目
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestompRule {
+    // 改ざんの疑いがどれか1つでもある
+    Any,
+    SiBeforeFn,
+    SiZeroSubsec,
+}
+
+impl TimestompRule {
+    fn matches(self, f: &TimestompFlags) -> bool {
+        match self {
+            TimestompRule::Any => f.si_before_fn || f.si_zero_subsec,
+            TimestompRule::SiBeforeFn => f.si_before_fn,
+            TimestompRule::SiZeroSubsec => f.si_zero_subsec,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    Path,
+    // 並べ替えない (インデックスの順、descending は無視)。全件を溜めずに返せる
+    None,
+    Name,
+    Size,
+    Created,
+    Modified,
+    MftChanged,
+    Accessed,
+    MftNo,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryHit {
+    #[serde(skip)]
+    pub id: NodeId,
+    pub name: String,
+    // R:\... のパス (エスケープ済み)
+    pub path: String,
+    pub ext: String,
+    pub mft_no: u64,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_ts: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_nsec: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_ts: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_nsec: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mft_changed_ts: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mft_changed_nsec: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accessed_ts: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accessed_nsec: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime: Option<&'static str>,
+    pub family: FileFamily,
+    pub live: bool,
+    // 同じパスの削除されていないファイルとの比較 (\$Live を取り込んだ場合)
+    pub live_match: LiveMatch,
+    pub recoverability: Recoverability,
+    // SI と FN の時刻から見た改ざんの疑い
+    pub timestomp: TimestompFlags,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryResult {
+    // ページ分けする前の件数
+    pub total: usize,
+    pub hits: Vec<QueryHit>,
+}
+
+type CompareFn<'a> = dyn Fn(&Candidate, &Candidate) -> Ordering + 'a;
+
+struct Candidate {
+    id: NodeId,
+    meta: EntryMeta,
+    recoverability: Option<Recoverability>,
+}
+
+impl TimeField {
+    fn of(self, t: &FileTimes) -> u64 {
+        match self {
+            TimeField::Created => t.created,
+            TimeField::Modified => t.modified,
+            TimeField::MftChanged => t.mft_changed,
+            TimeField::Accessed => t.accessed,
+        }
+    }
+}
+
+fn unix_parts(ft: u64) -> Option<(i64, u32)> {
+    filetime_to_system_time(ft).map(system_time_to_unix_parts)
+}
+
+// 最後の「.」以降 (先頭の「.」だけの名前は拡張子なし)
+fn extension_of(name: &[u16]) -> &[u16] {
+    match name.iter().rposition(|&c| c == b'.' as u16) {
+        Some(p) if p > 0 => &name[p + 1..],
+        _ => &[],
+    }
+}
+
+fn family_of(meta: &EntryMeta, name: &[u16]) -> FileFamily {
+    match meta.content_type {
+        Some(c) => c.family(),
+        None => FileFamily::from_name(name),
+    }
+}
+
+// 名前やフォルダの条件を先に組み立てておいて、ファイルごとに当てはめる
+struct Matcher<'a> {
+    query: &'a Query,
+    idx: &'a DeletedIndex,
+    glob: Option<Vec<u16>>,
+    regex: Option<Regex>,
+    extensions: Vec<Vec<u16>>,
+    prefix: Option<NodeId>,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(query: &'a Query, idx: &'a DeletedIndex) -> Result<Self> {
+        let regex = match &query.name_regex {
+            Some(r) => Some(
+                RegexBuilder::new(r)
+                    .case_insensitive(true)
+                    .build()
+                    .with_context(|| format!("invalid regex: {}", r))?,
+            ),
+            None => None,
+        };
+        let prefix = match &query.path_prefix {
+            Some(p) => {
+                let rel = match p.get(..2) {
+                    Some(d) if d.eq_ignore_ascii_case("R:") => &p[2..],
+                    _ => p.as_str(),
+                };
+                Some(
+                    idx.lookup(&unescape_u16(rel))
+                        .with_context(|| format!("folder not found: {}", p))?,
+                )
+            }
+            None => None,
+        };
+        Ok(Self {
+            query,
+            idx,
+            glob: query.name_glob.as_deref().map(unescape_u16),
+            regex,
+            extensions: query
+                .extensions
+                .iter()
+                .map(|e| unescape_u16(e.trim_start_matches('.')))
+                .collect(),
+            prefix,
+        })
+    }
+
+    // 条件に合わなければ None
+    fn candidate(
+        &self,
+        id: NodeId,
+        assess: &mut impl FnMut(&EntryMeta) -> Recoverability,
+    ) -> Option<Candidate> {
+        let (q, idx) = (self.query, self.idx);
+        let upcase = idx.upcase();
+        if self.prefix.is_some_and(|p| !idx.is_under(id, p)) {
+            return None;
+        }
+        let name = idx.name(id);
+        let name = name.as_slice();
+        if self
+            .glob
+            .as_ref()
+            .is_some_and(|g| !upcase.glob_match(name, g))
+        {
+            return None;
+        }
+        if self
+            .regex
+            .as_ref()
+            .is_some_and(|r| !r.is_match(&String::from_utf16_lossy(name)))
+        {
+            return None;
+        }
+        if !self.extensions.is_empty() {
+            let ext = extension_of(name);
+            if !self.extensions.iter().any(|e| upcase.eq(ext, e)) {
+                return None;
+            }
+        }
+        let meta = match idx.entry(id).as_deref() {
+            Some(EntryOrDir::File(m)) => (**m).clone(),
+            _ => return None,
+        };
+        if q.min_size.is_some_and(|s| meta.size < s) || q.max_size.is_some_and(|s| meta.size > s) {
+            return None;
+        }
+        if !q.time_ranges.is_empty() {
+            let times = meta.times.primary().unwrap_or_default();
+            let in_range = |r: &TimeRange| match unix_parts(r.field.of(&times)) {
+                Some((secs, _)) => {
+                    r.from.is_none_or(|f| secs >= f) && r.to.is_none_or(|t| secs < t)
+                }
+                None => false,
+            };
+            if !q.time_ranges.iter().all(in_range) {
+                return None;
+            }
+        }
+        if !q.families.is_empty() && !q.families.contains(&family_of(&meta, name)) {
+            return None;
+        }
+        if !q.timestomp.is_empty() && !q.timestomp.iter().any(|r| r.matches(&meta.times.stomp)) {
+            return None;
+        }
+        if !q.mimes.is_empty() {
+            let mime = meta.content_type.map(|c| c.mime());
+            if !q
+                .mimes
+                .iter()
+                .any(|m| mime.is_some_and(|x| x.eq_ignore_ascii_case(m)))
+            {
+                return None;
+            }
+        }
+        let recoverability = if q.recoverability.is_empty() {
+            None
+        } else {
+            let r = assess(&meta);
+            if !q.recoverability.contains(&r.kind()) {
+                return None;
+            }
+            Some(r)
+        };
+        Some(Candidate {
+            id,
+            meta,
+            recoverability,
+        })
+    }
+}
+
+impl Query {
+    // assess は復元可否の判定 (絞り込みに使うときは候補全部、それ以外は返すページの分だけ呼ぶ)
+    pub fn run(
+        &self,
+        idx: &DeletedIndex,
+        assess: impl FnMut(&EntryMeta) -> Recoverability,
+    ) -> Result<QueryResult> {
+        let mut hits = Vec::new();
+        let total = self.run_each(idx, assess, |_, h| hits.push(h))?;
+        Ok(QueryResult { total, hits })
+    }
+
+    // 結果を1件ずつ渡す。少しずつ画面に送る場合など
+    // 並べ替えるときは全件を集めてから渡す (on_hit にはページ分けする前の件数も渡す)
+    // SortKey::None なら集めずに見つけた順に渡す (件数は最後まで分からないので None)
+    // 戻り値はページ分けする前の件数
+    pub fn run_each(
+        &self,
+        idx: &DeletedIndex,
+        mut assess: impl FnMut(&EntryMeta) -> Recoverability,
+        mut on_hit: impl FnMut(Option<usize>, QueryHit),
+    ) -> Result<usize> {
+        let matcher = Matcher::new(self, idx)?;
+        let files = idx.files(self.include_live);
+        let end = self.offset.saturating_add(self.limit.unwrap_or(usize::MAX));
+
+        if self.sort == SortKey::None {
+            let mut total = 0usize;
+            for id in files {
+                let Some(c) = matcher.candidate(id, &mut assess) else {
+                    continue;
+                };
+                if (self.offset..end).contains(&total) {
+                    let recoverability = c.recoverability.unwrap_or_else(|| assess(&c.meta));
+                    on_hit(None, hit(idx, c.id, &c.meta, recoverability));
+                }
+                total += 1;
+            }
+            return Ok(total);
+        }
+
+        let mut found: Vec<Candidate> = files
+            .into_iter()
+            .filter_map(|id| matcher.candidate(id, &mut assess))
+            .collect();
+        self.sort(idx, &mut found);
+        let total = found.len();
+        for c in found.into_iter().take(end).skip(self.offset) {
+            let recoverability = c.recoverability.unwrap_or_else(|| assess(&c.meta));
+            on_hit(Some(total), hit(idx, c.id, &c.meta, recoverability));
+        }
+        Ok(total)
+    }
+
+    fn sort(&self, idx: &DeletedIndex, found: &mut [Candidate]) {
+        let upcase = idx.upcase();
+        let folded = |s: &[u16]| s.iter().map(|&c| upcase.fold(c)).collect::<Vec<u16>>();
+        let time = |c: &Candidate, f: TimeField| c.meta.times.primary().map_or(0, |t| f.of(&t));
+        let by_time =
+            |f: TimeField| move |a: &Candidate, b: &Candidate| time(a, f).cmp(&time(b, f));
+        // パスと名前は比較のたびに作ると重いので、先に並べて順位にしておく
+        let rank_by = |key: &dyn Fn(NodeId) -> Vec<u16>| {
+            let mut keys: Vec<(Vec<u16>, NodeId)> =
+                found.iter().map(|c| (folded(&key(c.id)), c.id)).collect();
+            keys.sort_unstable();
+            keys.into_iter()
+                .enumerate()
+                .map(|(i, (_, id))| (id, i))
+                .collect::<HashMap<NodeId, usize>>()
+        };
+        let cmp: Box<CompareFn> = match self.sort {
+            SortKey::Path => {
+                let rank = rank_by(&|id| idx.path_of(id).into_vec());
+                Box::new(move |a, b| rank[&a.id].cmp(&rank[&b.id]))
+            }
+            SortKey::Name => {
+                let rank = rank_by(&|id| idx.name(id).to_ustring().into_vec());
+                Box::new(move |a, b| rank[&a.id].cmp(&rank[&b.id]))
+            }
+            SortKey::Size => Box::new(|a, b| a.meta.size.cmp(&b.meta.size)),
+            SortKey::Created => Box::new(by_time(TimeField::Created)),
+            SortKey::Modified => Box::new(by_time(TimeField::Modified)),
+            SortKey::MftChanged => Box::new(by_time(TimeField::MftChanged)),
+            SortKey::Accessed => Box::new(by_time(TimeField::Accessed)),
+            SortKey::MftNo => Box::new(|a, b| a.meta.mft_no.cmp(&b.meta.mft_no)),
+            SortKey::None => Box::new(|_, _| Ordering::Equal),
+        };
+        // 同じ値どうしはノード順にして、ページをまたいでも順番が変わらないようにする
+        found.sort_by(|a, b| {
+            let o = cmp(a, b);
+            let o = if self.descending { o.reverse() } else { o };
+            o.then(a.id.cmp(&b.id))
+        });
+    }
+}
+
+pub fn hit(
+    idx: &DeletedIndex,
+    id: NodeId,
+    meta: &EntryMeta,
+    recoverability: Recoverability,
+) -> QueryHit {
+    let name = idx.name(id);
+    let name = name.as_slice();
+    let ext = extension_of(name);
+    let t = meta.times.primary().unwrap_or_default();
+    let (created, modified, mft_changed, accessed) = (
+        unix_parts(t.created),
+        unix_parts(t.modified),
+        unix_parts(t.mft_changed),
+        unix_parts(t.accessed),
+    );
+    QueryHit {
+        id,
+        name: escape_u16(name),
+        path: format!("R:{}", escape_u16(idx.path_of(id).as_slice())),
+        ext: if ext.is_empty() {
+            String::new()
+        } else {
+            format!(".{}", escape_u16(ext))
+        },
+        mft_no: meta.mft_no,
+        size: meta.size,
+        created_ts: created.map(|t| t.0),
+        created_nsec: created.map(|t| t.1),
+        modified_ts: modified.map(|t| t.0),
+        modified_nsec: modified.map(|t| t.1),
+        mft_changed_ts: mft_changed.map(|t| t.0),
+        mft_changed_nsec: mft_changed.map(|t| t.1),
+        accessed_ts: accessed.map(|t| t.0),
+        accessed_nsec: accessed.map(|t| t.1),
+        mime: meta.content_type.map(|c| c.mime()),
+        family: family_of(meta, name),
+        live: meta.live,
+        live_match: meta.live_match,
+        recoverability,
+        timestomp: meta.times.stomp,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::ParentStatus;
+    use crate::timestamps::RecordTimes;
+    use widestring::U16String;
+
+    const UNIX_TO_FILETIME_SECS: u64 = 11_644_473_600;
+
+    fn ft(secs: u64) -> u64 {
+        (secs + UNIX_TO_FILETIME_SECS) * 10_000_000
+    }
+
+    // mft_no ごとに 作成 = n*1000, 更新 +100, レコード更新 +200, アクセス +300 (UNIX秒)
+    fn meta(mft_no: u64, size: u64, with_times: bool) -> EntryMeta {
+        let base = mft_no * 1000;
+        let si = with_times.then(|| FileTimes {
+            created: ft(base),
+            modified: ft(base + 100),
+            mft_changed: ft(base + 200),
+            accessed: ft(base + 300),
+        });
+        EntryMeta {
+            mft_no,
+            sequence: 1,
+            is_dir: false,
+            size,
+            parent_ref: 0,
+            parent_status: ParentStatus::Resolved,
+            times: RecordTimes {
+                si,
+                ..Default::default()
+            },
+            link_count: 1,
+            attributes: 0,
+            live: false,
+            live_match: LiveMatch::NotChecked,
+            content_type: None,
+        }
+    }
+
+    fn index() -> DeletedIndex {
+        let mut idx = DeletedIndex::default();
+        let files = [
+            (1, r"\Docs\Report.docx", 1000, true),
+            (2, r"\Docs\report_final.DOCX", 5000, true),
+            (3, r"\Docs\Sub\notes.txt", 10, true),
+            (4, r"\Pics\IMG_0001.jpg", 2_000_000, true),
+            (5, r"\Pics\img_0002.JPG", 3_000_000, true),
+            (6, r"\$Live\Docs\live.txt", 20, true),
+            (7, r"\Docs\blank.bin", 0, false),
+        ];
+        for (mft_no, path, size, with_times) in files {
+            let mut m = meta(mft_no, size, with_times);
+            m.live = mft_no == 6;
+            m.times.stomp = TimestompFlags {
+                si_before_fn: mft_no == 2 || mft_no == 4,
+                si_zero_subsec: mft_no == 4 || mft_no == 5,
+            };
+            idx.insert_file(&U16String::from_str(path), None, m);
+        }
+        idx
+    }
+
+    fn run(q: &Query) -> (usize, Vec<u64>) {
+        let r = q.run(&index(), |_| Recoverability::Unknown).unwrap();
+        (r.total, r.hits.iter().map(|h| h.mft_no).collect())
+    }
+
+    fn sorted(q: Query) -> Vec<u64> {
+        let mut v = run(&q).1;
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn glob_and_regex_ignore_case() {
+        let glob = |g: &str| Query {
+            name_glob: Some(g.into()),
+            ..Default::default()
+        };
+        assert_eq!(sorted(glob("REPORT*")), vec![1, 2]);
+        assert_eq!(sorted(glob("img_000?.jpg")), vec![4, 5]);
+        assert_eq!(sorted(glob("*.txt")), vec![3]);
+
+        let regex = |r: &str| Query {
+            name_regex: Some(r.into()),
+            ..Default::default()
+        };
+        assert_eq!(sorted(regex(r"^img_\d+\.jpg$")), vec![4, 5]);
+        assert_eq!(sorted(regex("final")), vec![2]);
+        assert!(regex("(")
+            .run(&index(), |_| Recoverability::Unknown)
+            .is_err());
+    }
+
+    #[test]
+    fn extensions_and_size_range() {
+        let q = Query {
+            extensions: vec!["docx".into()],
+            ..Default::default()
+        };
+        assert_eq!(sorted(q), vec![1, 2]);
+        let q = Query {
+            extensions: vec![".TXT".into(), "jpg".into()],
+            ..Default::default()
+        };
+        assert_eq!(sorted(q), vec![3, 4, 5]);
+
+        // 両端を含む
+        let q = Query {
+            min_size: Some(1000),
+            max_size: Some(2_000_000),
+            ..Default::default()
+        };
+        assert_eq!(sorted(q), vec![1, 2, 4]);
+        let q = Query {
+            max_size: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(sorted(q), vec![3, 7]);
+    }
+
+    #[test]
+    fn each_time_range() {
+        let fields = [
+            (TimeField::Created, 0),
+            (TimeField::Modified, 100),
+            (TimeField::MftChanged, 200),
+            (TimeField::Accessed, 300),
+        ];
+        for (field, offset) in fields {
+            // from 以上 to 未満。時刻の無いファイル (7) は一致しない
+            let q = Query {
+                time_ranges: vec![TimeRange {
+                    field,
+                    from: Some(2000 + offset),
+                    to: Some(4000 + offset),
+                }],
+                ..Default::default()
+            };
+            assert_eq!(sorted(q), vec![2, 3], "{:?}", field);
+            let q = Query {
+                time_ranges: vec![TimeRange {
+                    field,
+                    from: Some(4001 + offset),
+                    to: None,
+                }],
+                ..Default::default()
+            };
+            assert_eq!(sorted(q), vec![5], "{:?}", field);
+        }
+        // 複数の範囲は AND
+        let q = Query {
+            time_ranges: vec![
+                TimeRange {
+                    field: TimeField::Created,
+                    from: Some(2000),
+                    to: None,
+                },
+                TimeRange {
+                    field: TimeField::Accessed,
+                    from: None,
+                    to: Some(3300),
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(sorted(q), vec![2]);
+    }
+
+    #[test]
+    fn path_prefix_and_live() {
+        let prefix = |p: &str| Query {
+            path_prefix: Some(p.into()),
+            ..Default::default()
+        };
+        assert_eq!(sorted(prefix(r"R:\Docs")), vec![1, 2, 3, 7]);
+        assert_eq!(sorted(prefix(r"\docs\SUB")), vec![3]);
+        assert!(prefix(r"R:\Missing")
+            .run(&index(), |_| Recoverability::Unknown)
+            .is_err());
+
+        assert_eq!(run(&Query::default()).0, 6);
+        let q = Query {
+            include_live: true,
+            ..Default::default()
+        };
+        assert_eq!(sorted(q), vec![1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn recoverability_filter_uses_assess() {
+        let q = Query {
+            recoverability: vec![RecoverabilityKind::Overwritten],
+            ..Default::default()
+        };
+        let r = q
+            .run(&index(), |m| match m.size {
+                0..=1000 => Recoverability::Resident,
+                _ => Recoverability::Overwritten,
+            })
+            .unwrap();
+        let mut got: Vec<u64> = r.hits.iter().map(|h| h.mft_no).collect();
+        got.sort();
+        assert_eq!(got, vec![2, 4, 5]);
+    }
+
+    #[test]
+    fn timestomp_filter() {
+        let q = |rules: Vec<TimestompRule>| Query {
+            timestomp: rules,
+            ..Default::default()
+        };
+        assert_eq!(sorted(q(vec![TimestompRule::SiBeforeFn])), vec![2, 4]);
+        assert_eq!(sorted(q(vec![TimestompRule::SiZeroSubsec])), vec![4, 5]);
+        assert_eq!(sorted(q(vec![TimestompRule::Any])), vec![2, 4, 5]);
+        let r = q(vec![TimestompRule::SiBeforeFn])
+            .run(&index(), |_| Recoverability::Unknown)
+            .unwrap();
+        assert!(r.hits.iter().all(|h| h.timestomp.si_before_fn));
+    }
+
+    #[test]
+    fn sort_keys() {
+        let by = |sort: SortKey, descending: bool| {
+            run(&Query {
+                sort,
+                descending,
+                ..Default::default()
+            })
+            .1
+        };
+        // パスと名前は大文字小文字を無視して並べる
+        assert_eq!(by(SortKey::Path, false), vec![7, 1, 2, 3, 4, 5]);
+        assert_eq!(by(SortKey::Name, false), vec![7, 4, 5, 3, 1, 2]);
+        assert_eq!(by(SortKey::Size, true), vec![5, 4, 2, 1, 3, 7]);
+        assert_eq!(by(SortKey::MftNo, true), vec![7, 5, 4, 3, 2, 1]);
+        // 時刻の無いファイルは先頭
+        for key in [
+            SortKey::Created,
+            SortKey::Modified,
+            SortKey::MftChanged,
+            SortKey::Accessed,
+        ] {
+            assert_eq!(by(key, false), vec![7, 1, 2, 3, 4, 5], "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn offset_and_limit() {
+        let page = |offset: usize, limit: Option<usize>| {
+            run(&Query {
+                sort: SortKey::MftNo,
+                offset,
+                limit,
+                ..Default::default()
+            })
+        };
+        assert_eq!(page(0, Some(2)), (6, vec![1, 2]));
+        assert_eq!(page(2, Some(2)), (6, vec![3, 4]));
+        assert_eq!(page(5, Some(2)), (6, vec![7]));
+        assert_eq!(page(6, None), (6, vec![]));
+        assert_eq!(page(4, None), (6, vec![5, 7]));
+    }
+
+    #[test]
+    fn unsorted_results_are_streamed() {
+        let q = Query {
+            sort: SortKey::None,
+            offset: 1,
+            limit: Some(3),
+            ..Default::default()
+        };
+        let mut got = Vec::new();
+        let total = q
+            .run_each(
+                &index(),
+                |_| Recoverability::Unknown,
+                |total, h| {
+                    // 件数は最後まで分からない
+                    assert_eq!(total, None);
+                    got.push(h.mft_no);
+                },
+            )
+            .unwrap();
+        assert_eq!(total, 6);
+        // インデックスに入れた順
+        assert_eq!(got, vec![2, 3, 4]);
+    }
+}
@@ -0,0 +1,526 @@
+// MFTレコードの生バイト列を直接解釈するための最小限のパーサ
+// ntfs-reader の Mft を経由せずにウィンドウ単位で読んだレコードを扱うために使う
+
+pub const ROOT_RECORD: u64 = 5;
+
+pub const ATTR_STANDARD_INFORMATION: u32 = 0x10;
+pub const ATTR_FILE_NAME: u32 = 0x30;
+pub const ATTR_DATA: u32 = 0x80;
+const ATTR_END: u32 = 0xFFFF_FFFF;
+
+const FLAG_IN_USE: u16 = 0x0001;
+const FLAG_DIRECTORY: u16 = 0x0002;
+
+pub const NS_POSIX: u8 = 0;
+pub const NS_WIN32: u8 = 1;
+pub const NS_DOS: u8 = 2;
+pub const NS_WIN32_AND_DOS: u8 = 3;
+
+pub fn read_u16(b: &[u8], off: usize) -> u16 {
+    b.get(off..off + 2)
+        .map(|s| u16::from_le_bytes([s[0], s[1]]))
+        .unwrap_or(0)
+}
+
+pub fn read_u32(b: &[u8], off: usize) -> u32 {
+    b.get(off..off + 4)
+        .map(|s| u32::from_le_bytes([s[0], s[1], s[2], s[3]]))
+        .unwrap_or(0)
+}
+
+pub fn read_u64(b: &[u8], off: usize) -> u64 {
+    b.get(off..off + 8)
+        .map(|s| {
+            let mut a = [0u8; 8];
+            a.copy_from_slice(s);
+            u64::from_le_bytes(a)
+        })
+        .unwrap_or(0)
+}
+
+// MFT参照 (下位48bitがレコード番号、上位16bitがシーケンス番号)
+pub fn ref_record(r: u64) -> u64 {
+    r & 0x0000_FFFF_FFFF_FFFF
+}
+
+pub fn ref_sequence(r: u64) -> u16 {
+    (r >> 48) as u16
+}
+
+// Update Sequence Array によるセクタ末尾の書き戻し
+// 破損していた場合は false を返す
+pub fn apply_fixup(rec: &mut [u8]) -> bool {
+    if rec.len() < 0x30 || &rec[0..4] != b"FILE" {
+        return false;
+    }
+    let usa_off = read_u16(rec, 0x04) as usize;
+    let usa_count = read_u16(rec, 0x06) as usize;
+    if usa_count < 2 || usa_off + usa_count * 2 > rec.len() {
+        return false;
+    }
+    let stride = rec.len() / (usa_count - 1);
+    if stride < 2 {
+        return false;
+    }
+    let usn = [rec[usa_off], rec[usa_off + 1]];
+    for i in 1..usa_count {
+        let end = i * stride;
+        if end > rec.len() {
+            return false;
+        }
+        if rec[end - 2..end] != usn {
+            return false;
+        }
+        rec[end - 2] = rec[usa_off + i * 2];
+        rec[end - 1] = rec[usa_off + i * 2 + 1];
+    }
+    true
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RecordHeader {
+    pub sequence: u16,
+    pub flags: u16,
+    pub attrs_offset: u16,
+    pub used_size: u32,
+    pub base_record: u64,
+}
+
+impl RecordHeader {
+    pub fn parse(rec: &[u8]) -> Option<Self> {
+        if rec.len() < 0x30 || &rec[0..4] != b"FILE" {
+            return None;
+        }
+        Some(Self {
+            sequence: read_u16(rec, 0x10),
+            flags: read_u16(rec, 0x16),
+            attrs_offset: read_u16(rec, 0x14),
+            used_size: read_u32(rec, 0x18),
+            base_record: read_u64(rec, 0x20),
+        })
+    }
+    pub fn is_used(&self) -> bool {
+        self.flags & FLAG_IN_USE != 0
+    }
+    pub fn is_directory(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+    pub fn is_base(&self) -> bool {
+        ref_record(self.base_record) == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Attribute<'a> {
+    pub type_code: u32,
+    pub non_resident: bool,
+    pub name_len: u8,
+    raw: &'a [u8],
+}
+
+impl<'a> Attribute<'a> {
+    // 常駐属性の値
+    pub fn resident_value(&self) -> Option<&'a [u8]> {
+        if self.non_resident {
+            return None;
+        }
+        let len = read_u32(self.raw, 0x10) as usize;
+        let off = read_u16(self.raw, 0x14) as usize;
+        self.raw.get(off..off + len)
+    }
+    // 非常駐属性のランリスト (mapping pairs)
+    pub fn run_list(&self) -> Option<&'a [u8]> {
+        if !self.non_resident {
+            return None;
+        }
+        let off = read_u16(self.raw, 0x20) as usize;
+        self.raw.get(off..)
+    }
+    pub fn non_resident_real_size(&self) -> Option<u64> {
+        if self.non_resident {
+            Some(read_u64(self.raw, 0x30))
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Attributes<'a> {
+    rec: &'a [u8],
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = Attribute<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos + 0x10 > self.end {
+            return None;
+        }
+        let type_code = read_u32(self.rec, self.pos);
+        if type_code == ATTR_END {
+            return None;
+        }
+        let len = read_u32(self.rec, self.pos + 4) as usize;
+        if len < 0x18 || self.pos + len > self.end {
+            return None;
+        }
+        let raw = &self.rec[self.pos..self.pos + len];
+        self.pos += len;
+        Some(Attribute {
+            type_code,
+            non_resident: raw[8] != 0,
+            name_len: raw[9],
+            raw,
+        })
+    }
+}
+
+pub fn attributes(rec: &[u8]) -> Attributes<'_> {
+    let (pos, end) = match RecordHeader::parse(rec) {
+        Some(h) => (
+            h.attrs_offset as usize,
+            std::cmp::min(h.used_size as usize, rec.len()),
+        ),
+        None => (0, 0),
+    };
+    Attributes { rec, pos, end }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdInfo {
+    pub created: u64,
+    pub modified: u64,
+    pub mft_changed: u64,
+    pub accessed: u64,
+    pub file_attributes: u32,
+}
+
+pub fn standard_info(rec: &[u8]) -> Option<StdInfo> {
+    let v = attributes(rec)
+        .find(|a| a.type_code == ATTR_STANDARD_INFORMATION)?
+        .resident_value()?;
+    if v.len() < 0x24 {
+        return None;
+    }
+    Some(StdInfo {
+        created: read_u64(v, 0x00),
+        modified: read_u64(v, 0x08),
+        mft_changed: read_u64(v, 0x10),
+        accessed: read_u64(v, 0x18),
+        file_attributes: read_u32(v, 0x20),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct FileNameAttr {
+    pub parent_ref: u64,
+    pub created: u64,
+    pub modified: u64,
+    pub mft_changed: u64,
+    pub accessed: u64,
+    pub real_size: u64,
+    pub namespace: u8,
+    pub name: Vec<u16>,
+}
+
+impl FileNameAttr {
+    fn parse(v: &[u8]) -> Option<Self> {
+        if v.len() < 0x42 {
+            return None;
+        }
+        let name_len = v[0x40] as usize;
+        let bytes = v.get(0x42..0x42 + name_len * 2)?;
+        let name = bytes
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        Some(Self {
+            parent_ref: read_u64(v, 0x00),
+            created: read_u64(v, 0x08),
+            modified: read_u64(v, 0x10),
+            mft_changed: read_u64(v, 0x18),
+            accessed: read_u64(v, 0x20),
+            real_size: read_u64(v, 0x30),
+            namespace: v[0x41],
+            name,
+        })
+    }
+}
+
+pub fn file_names(rec: &[u8]) -> Vec<FileNameAttr> {
+    attributes(rec)
+        .filter(|a| a.type_code == ATTR_FILE_NAME)
+        .filter_map(|a| a.resident_value())
+        .filter_map(FileNameAttr::parse)
+        .collect()
+}
+
+// ハードリンク1つ分の名前 (長い名前と、あれば対になる8.3形式の名前)
+#[derive(Debug, Clone)]
+pub struct LinkName {
+    pub long: FileNameAttr,
+    pub short: Option<Vec<u16>>,
+}
+
+// レコードの全ての名前をハードリンクごとにまとめる
+// 並びは Win32 > POSIX > DOSのみ、同じ順位なら親参照、名前の順 (先頭が主の名前)
+pub fn link_names(rec: &[u8]) -> Vec<LinkName> {
+    let (dos, long): (Vec<FileNameAttr>, Vec<FileNameAttr>) = file_names(rec)
+        .into_iter()
+        .partition(|f| f.namespace == NS_DOS);
+    let mut dos_used = vec![false; dos.len()];
+    let mut links: Vec<LinkName> = Vec::new();
+    for f in long {
+        if links
+            .iter()
+            .any(|l| l.long.parent_ref == f.parent_ref && l.long.name == f.name)
+        {
+            continue;
+        }
+        // Win32 の名前には同じフォルダにある DOS の名前が対になる
+        let mut short = None;
+        if f.namespace == NS_WIN32 {
+            if let Some(i) = (0..dos.len()).find(|&i| {
+                !dos_used[i] && ref_record(dos[i].parent_ref) == ref_record(f.parent_ref)
+            }) {
+                dos_used[i] = true;
+                short = Some(dos[i].name.clone());
+            }
+        }
+        links.push(LinkName { long: f, short });
+    }
+    // DOS の名前しか残っていない場合はそれを使う
+    if links.is_empty() {
+        links = dos
+            .into_iter()
+            .map(|f| LinkName {
+                long: f,
+                short: None,
+            })
+            .collect();
+    }
+    let rank = |ns: u8| match ns {
+        NS_WIN32 | NS_WIN32_AND_DOS => 0,
+        NS_POSIX => 1,
+        _ => 2,
+    };
+    links.sort_by(|a, b| {
+        rank(a.long.namespace)
+            .cmp(&rank(b.long.namespace))
+            .then(a.long.parent_ref.cmp(&b.long.parent_ref))
+            .then_with(|| a.long.name.cmp(&b.long.name))
+    });
+    links
+}
+
+// 表示に使う名前を1つ選ぶ (link_names の先頭)
+pub fn best_file_name(rec: &[u8]) -> Option<FileNameAttr> {
+    link_names(rec).into_iter().next().map(|l| l.long)
+}
+
+// 無名の$DATA属性のサイズ
+pub fn data_size(rec: &[u8]) -> Option<u64> {
+    let a = attributes(rec).find(|a| a.type_code == ATTR_DATA && a.name_len == 0)?;
+    match a.non_resident_real_size() {
+        Some(sz) => Some(sz),
+        None => a.resident_value().map(|v| v.len() as u64),
+    }
+}
+
+// ランリストを (LCN, クラスタ数) の列に展開
+// スパース領域は LCN が None になる
+pub fn decode_runs(runs: &[u8]) -> Vec<(Option<u64>, u64)> {
+    let mut out = Vec::new();
+    let mut pos = 0usize;
+    let mut lcn: i64 = 0;
+    while pos < runs.len() {
+        let head = runs[pos];
+        if head == 0 {
+            break;
+        }
+        let len_size = (head & 0x0F) as usize;
+        let off_size = (head >> 4) as usize;
+        pos += 1;
+        if len_size == 0 || len_size > 8 || off_size > 8 || pos + len_size + off_size > runs.len() {
+            break;
+        }
+        let mut len: u64 = 0;
+        for i in 0..len_size {
+            len |= (runs[pos + i] as u64) << (8 * i);
+        }
+        pos += len_size;
+        if off_size == 0 {
+            out.push((None, len));
+            continue;
+        }
+        let mut delta: i64 = 0;
+        for i in 0..off_size {
+            delta |= (runs[pos + i] as i64) << (8 * i);
+        }
+        if runs[pos + off_size - 1] & 0x80 != 0 && off_size < 8 {
+            delta |= -1i64 << (8 * off_size);
+        }
+        pos += off_size;
+        // 壊れたランで桁あふれしたり負になったりしたらそこまで
+        lcn = match lcn.checked_add(delta) {
+            Some(l) if l >= 0 => l,
+            _ => break,
+        };
+        out.push((Some(lcn as u64), len));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USN: [u8; 2] = [0x07, 0x00];
+
+    // 1024バイト、セクタ 512 のレコード (attrs は (種別, 中身) を常駐属性として並べる)
+    // セクタ末尾は USN に置き換えて元の値を USA に入れる (apply_fixup 前の状態)
+    fn raw_record(attrs: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut r = vec![0u8; 1024];
+        r[0..4].copy_from_slice(b"FILE");
+        r[0x04..0x06].copy_from_slice(&0x30u16.to_le_bytes());
+        r[0x06..0x08].copy_from_slice(&3u16.to_le_bytes());
+        r[0x10..0x12].copy_from_slice(&1u16.to_le_bytes());
+        r[0x14..0x16].copy_from_slice(&0x38u16.to_le_bytes());
+        r[0x16..0x18].copy_from_slice(&FLAG_IN_USE.to_le_bytes());
+        let mut pos = 0x38;
+        for (type_code, value) in attrs {
+            let len = (0x18 + value.len() + 7) & !7;
+            r[pos..pos + 4].copy_from_slice(&type_code.to_le_bytes());
+            r[pos + 4..pos + 8].copy_from_slice(&(len as u32).to_le_bytes());
+            r[pos + 0x10..pos + 0x14].copy_from_slice(&(value.len() as u32).to_le_bytes());
+            r[pos + 0x14..pos + 0x16].copy_from_slice(&0x18u16.to_le_bytes());
+            r[pos + 0x18..pos + 0x18 + value.len()].copy_from_slice(value);
+            pos += len;
+        }
+        r[pos..pos + 4].copy_from_slice(&ATTR_END.to_le_bytes());
+        r[0x18..0x1C].copy_from_slice(&((pos + 8) as u32).to_le_bytes());
+        r[0x30..0x32].copy_from_slice(&USN);
+        for i in 1..3 {
+            let end = i * 512;
+            r.copy_within(end - 2..end, 0x30 + i * 2);
+            r[end - 2..end].copy_from_slice(&USN);
+        }
+        r
+    }
+
+    fn file_name(name: &str) -> Vec<u8> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let mut v = vec![0u8; 0x42];
+        v[0x00..0x08].copy_from_slice(&ROOT_RECORD.to_le_bytes());
+        v[0x40] = units.len() as u8;
+        v[0x41] = NS_WIN32;
+        v.extend(units.iter().flat_map(|u| u.to_le_bytes()));
+        v
+    }
+
+    #[test]
+    fn fixup_restores_sector_tails() {
+        let mut r = raw_record(&[(ATTR_DATA, vec![0xAB; 600])]);
+        // 1つ目のセクタの末尾は中身の途中にある
+        assert_eq!(r[0x1FE..0x200], USN);
+        assert!(apply_fixup(&mut r));
+        assert_eq!(r[0x1FE..0x200], [0xAB, 0xAB]);
+        assert_eq!(data_size(&r), Some(600));
+    }
+
+    #[test]
+    fn fixup_mismatch_is_rejected() {
+        let mut r = raw_record(&[]);
+        r[0x3FF] ^= 0xFF;
+        assert!(!apply_fixup(&mut r));
+
+        // USA がレコードの外まで続く
+        let mut r = raw_record(&[]);
+        r[0x06..0x08].copy_from_slice(&600u16.to_le_bytes());
+        assert!(!apply_fixup(&mut r));
+
+        let mut r = raw_record(&[]);
+        r[0..4].copy_from_slice(b"BAAD");
+        assert!(!apply_fixup(&mut r));
+        assert!(RecordHeader::parse(&r).is_none());
+        assert_eq!(attributes(&r).count(), 0);
+    }
+
+    #[test]
+    fn truncated_attributes_stop_the_walk() {
+        let mut r = raw_record(&[
+            (ATTR_FILE_NAME, file_name("a.txt")),
+            (ATTR_DATA, vec![1; 8]),
+        ]);
+        assert!(apply_fixup(&mut r));
+        assert_eq!(attributes(&r).count(), 2);
+
+        // 長さが使用中の範囲を越える属性
+        let mut cut = r.clone();
+        cut[0x18..0x1C].copy_from_slice(&0x80u32.to_le_bytes());
+        assert_eq!(attributes(&cut).count(), 0);
+        // 長さが短すぎる属性
+        let mut short = r.clone();
+        short[0x3C..0x40].copy_from_slice(&0x10u32.to_le_bytes());
+        assert_eq!(attributes(&short).count(), 0);
+        // レコードそのものが途中で切れている
+        assert_eq!(attributes(&r[..0x60]).count(), 0);
+
+        // 値が属性の外を指す
+        let mut value = r.clone();
+        value[0x38 + 0x10..0x38 + 0x14].copy_from_slice(&0x400u32.to_le_bytes());
+        assert!(file_names(&value).is_empty());
+        // 名前の長さが値に収まらない
+        let mut name = r.clone();
+        name[0x38 + 0x18 + 0x40] = 0x7F;
+        assert!(file_names(&name).is_empty());
+        assert_eq!(
+            file_names(&r)[0].name,
+            "a.txt".encode_utf16().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn runs_with_negative_deltas() {
+        // 256 から 16、そこから -16 で 240 から 8
+        let runs = [0x21, 0x10, 0x00, 0x01, 0x21, 0x08, 0xF0, 0xFF, 0x00];
+        assert_eq!(decode_runs(&runs), [(Some(256), 16), (Some(240), 8)]);
+        // 0 より前に戻るランで止める
+        let runs = [0x11, 0x04, 0x10, 0x11, 0x04, 0xE0, 0x00];
+        assert_eq!(decode_runs(&runs), [(Some(16), 4)]);
+    }
+
+    #[test]
+    fn sparse_runs_do_not_move_the_lcn() {
+        let runs = [0x11, 0x04, 0x10, 0x01, 0x08, 0x11, 0x04, 0x04, 0x00];
+        assert_eq!(
+            decode_runs(&runs),
+            [(Some(16), 4), (None, 8), (Some(20), 4)]
+        );
+    }
+
+    #[test]
+    fn eight_byte_lengths_and_offsets() {
+        let mut runs = vec![0x18];
+        runs.extend(u64::MAX.to_le_bytes());
+        runs.extend([0x01, 0x00]);
+        assert_eq!(decode_runs(&runs), [(Some(1), u64::MAX)]);
+
+        // 8バイトの負の差分
+        let mut runs = vec![0x11, 0x01, 0x05, 0x81, 0x01];
+        runs.extend((-1i64).to_le_bytes());
+        assert_eq!(decode_runs(&runs), [(Some(5), 1), (Some(4), 1)]);
+
+        // 桁あふれする差分で止める
+        let mut runs = vec![0x81, 0x01];
+        runs.extend(i64::MAX.to_le_bytes());
+        runs.extend([0x11, 0x01, 0x01]);
+        assert_eq!(decode_runs(&runs), [(Some(i64::MAX as u64), 1)]);
+
+        // 9バイト以上の長さ、長さ0、途中で切れたラン
+        assert!(decode_runs(&[0x19, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]).is_empty());
+        assert!(decode_runs(&[0x10, 0x01]).is_empty());
+        assert!(decode_runs(&[0x21, 0x01, 0x00]).is_empty());
+    }
+}
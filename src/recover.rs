@@ -0,0 +1,621 @@
+use crate::file_meta::{self, OriginalMeta};
+use crate::indexer::{
+    DeletedIndex, EntryOrDir, NodeId, BY_DATE_DIR, BY_SIZE_DIR, BY_TYPE_DIR, ROOT_NODE,
+    VERSIONS_DIR,
+};
+use crate::manifest::{self, HashingWriter, ManifestEntry};
+use crate::mft_stream::MftStream;
+use crate::query::Query;
+use crate::record::RecordHeader;
+use crate::recoverability::{ClusterBitmap, Recoverability};
+use crate::scan::LIVE_DIR;
+use crate::util::{escape_u16, unescape_u16};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+// 削除済みファイルをまとめて別の場所へ書き出す
+// 選んだファイル/フォルダ/検索結果を R:\ からの相対パスのまま destination 以下に置く
+// 途中経過は destination\.recoverymagic に残し、取り消したり失敗したりした後に続きからやり直せる
+
+pub static RECOVER_CANCEL: AtomicBool = AtomicBool::new(false);
+
+const JOB_DIR: &str = ".recoverymagic";
+const JOB_FILE: &str = "job.json";
+const DONE_FILE: &str = "done.log";
+const PARTIAL_SUFFIX: &str = ".rmpartial";
+
+// 何を書き出すか。paths のフォルダは中身ごと、query は検索に一致したファイル
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Selection {
+    // R:\... のパス (エスケープ済み、一覧の path と同じ形)
+    pub paths: Vec<String>,
+    pub query: Option<Query>,
+}
+
+// 書き出し先に同じ名前があったとき
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    // name (2).ext のように連番を付ける
+    #[default]
+    Rename,
+    Skip,
+    Overwrite,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanItem {
+    // R:\... のパス (エスケープ済み)
+    pub source: String,
+    // フォルダは None
+    pub mft_no: Option<u64>,
+    // スキャンしたときのシーケンス番号 (以前のバージョンの作業ファイルには無い)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u16>,
+    pub size: u64,
+    // \$Live 以下 (使用中のレコード)
+    #[serde(default)]
+    pub live: bool,
+    // 書き出した後に戻す日時と属性
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<OriginalMeta>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Job {
+    device: String,
+    conflict: ConflictPolicy,
+    #[serde(default = "file_meta::enabled_by_default")]
+    restore_meta: bool,
+    // 書き出したファイルの一覧の名前 (拡張子なし)
+    #[serde(default = "legacy_manifest_name")]
+    manifest_name: String,
+    items: Vec<PlanItem>,
+}
+
+// 名前を持たない前の版の作業は、これまでの名前に書く
+fn legacy_manifest_name() -> String {
+    manifest::MANIFEST_STEM.to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DoneEntry {
+    index: usize,
+    // 書き出した先 (エスケープ済み)。飛ばした場合は None
+    dest: Option<String>,
+    // 書き出したファイルの記録 (フォルダには無い)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    manifest: Option<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RecoverEvent {
+    Started {
+        total: usize,
+        bytes: u64,
+        // 前回までに終わっていた件数
+        already_done: usize,
+    },
+    File {
+        index: usize,
+        total: usize,
+        source: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dest: Option<String>,
+        skipped: bool,
+        done_bytes: u64,
+        total_bytes: u64,
+    },
+    Failed {
+        index: usize,
+        source: String,
+        error: String,
+    },
+    Finished(RecoverSummary),
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RecoverSummary {
+    pub copied: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub cancelled: bool,
+    pub destination: String,
+    // 書き出したファイルの一覧 (JSON、エスケープ済み)。1件も無ければ None
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<String>,
+}
+
+// 選択を書き出す一覧にする (フォルダはその後に中身が続く)
+pub fn plan(idx: &DeletedIndex, selection: &Selection) -> Result<Vec<PlanItem>> {
+    let mut seen: HashSet<NodeId> = HashSet::new();
+    let mut items = Vec::new();
+    for path in &selection.paths {
+        let id = resolve(idx, path).with_context(|| format!("not found: {}", path))?;
+        walk(idx, id, &mut seen, &mut items);
+    }
+    if let Some(query) = &selection.query {
+        let mut query = query.clone();
+        query.offset = 0;
+        query.limit = None;
+        for hit in query.run(idx, |_| Recoverability::Unknown)?.hits {
+            walk(idx, hit.id, &mut seen, &mut items);
+        }
+    }
+    if items.is_empty() {
+        bail!("nothing to recover");
+    }
+    Ok(items)
+}
+
+// R:\... のパスからノードを引く
+pub fn resolve(idx: &DeletedIndex, path: &str) -> Option<NodeId> {
+    let rest = match path.get(..2) {
+        Some(drive) if drive.eq_ignore_ascii_case("R:") => &path[2..],
+        _ => path,
+    };
+    idx.lookup(&unescape_u16(rest))
+}
+
+fn walk(idx: &DeletedIndex, id: NodeId, seen: &mut HashSet<NodeId>, items: &mut Vec<PlanItem>) {
+    let live_root = idx.lookup(&LIVE_DIR.encode_utf16().collect::<Vec<_>>());
+    // ルートごと選んだときは仮想フォルダと \$Live、\$Versions は除く (同じファイルが何度も出てくるので)
+    let skip_at_root: Vec<Vec<u16>> = [
+        LIVE_DIR,
+        VERSIONS_DIR,
+        BY_TYPE_DIR,
+        BY_DATE_DIR,
+        BY_SIZE_DIR,
+    ]
+    .iter()
+    .map(|d| d.encode_utf16().collect())
+    .collect();
+    let mut stack = vec![id];
+    while let Some(id) = stack.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let live = live_root.is_some_and(|l| idx.is_under(id, l));
+        let entry = idx.entry(id);
+        let meta = entry.as_deref().and_then(OriginalMeta::from_entry);
+        if idx.is_dir(id) {
+            if id != ROOT_NODE {
+                items.push(PlanItem {
+                    source: source_path(idx, id),
+                    mft_no: None,
+                    sequence: None,
+                    size: 0,
+                    live,
+                    meta,
+                });
+            }
+            let mut children: Vec<NodeId> = idx
+                .children(id)
+                .filter(|&c| {
+                    id != ROOT_NODE
+                        || !skip_at_root
+                            .iter()
+                            .any(|d| idx.name(c).as_slice() == &d[..])
+                })
+                .collect();
+            // 名前順に出すため逆順に積む
+            children.reverse();
+            stack.extend(children);
+            continue;
+        }
+        if let Some(EntryOrDir::File(m)) = entry.as_deref() {
+            items.push(PlanItem {
+                source: source_path(idx, id),
+                mft_no: Some(m.mft_no),
+                sequence: Some(m.sequence),
+                size: m.size,
+                live,
+                meta,
+            });
+        }
+    }
+}
+
+fn source_path(idx: &DeletedIndex, id: NodeId) -> String {
+    format!("R:{}", escape_u16(idx.path_of(id).as_slice()))
+}
+
+// R:\a\b を destination\a\b にする
+fn target_path(dest: &Path, source: &str) -> PathBuf {
+    let rel = source.get(3..).unwrap_or_default();
+    let mut out = dest.to_path_buf();
+    for comp in unescape_u16(rel).split(|&c| c == b'\\' as u16) {
+        if comp.is_empty() {
+            continue;
+        }
+        out.push(os_name(comp));
+    }
+    out
+}
+
+#[cfg(windows)]
+fn os_name(name: &[u16]) -> std::ffi::OsString {
+    use std::os::windows::ffi::OsStringExt;
+    std::ffi::OsString::from_wide(name)
+}
+
+#[cfg(not(windows))]
+fn os_name(name: &[u16]) -> std::ffi::OsString {
+    String::from_utf16_lossy(name).into()
+}
+
+fn escape_path(p: &Path) -> String {
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        escape_u16(&p.as_os_str().encode_wide().collect::<Vec<_>>())
+    }
+    #[cfg(not(windows))]
+    {
+        p.to_string_lossy().into_owned()
+    }
+}
+
+// name.ext -> name (2).ext (空いている番号まで進める)
+fn numbered(path: &Path) -> PathBuf {
+    let mut n = 2usize;
+    loop {
+        // 名前は OsStr のまま扱う (UTF-16 として不正な名前も崩さない)
+        let mut name = path.file_stem().unwrap_or_default().to_os_string();
+        name.push(format!(" ({})", n));
+        if let Some(ext) = path.extension() {
+            name.push(".");
+            name.push(ext);
+        }
+        let cand = path.with_file_name(name);
+        if !cand.exists() {
+            return cand;
+        }
+        n += 1;
+    }
+}
+
+enum Outcome {
+    // フォルダは記録なし
+    Written(PathBuf, Option<Box<ManifestEntry>>),
+    // ConflictPolicy::Skip で飛ばした
+    Skipped,
+    // 書いている途中で取り消された
+    Cancelled,
+}
+
+pub struct Recovery {
+    dest: PathBuf,
+    job: Job,
+    // 読ませないレコード (スナップショットの後にクラスタが使われたもの)
+    withheld: HashSet<u64>,
+    done: HashSet<usize>,
+    // 前回までの分も含めた書き出し済みのファイル (items の順)
+    manifest: BTreeMap<usize, ManifestEntry>,
+}
+
+impl Recovery {
+    // 新しく始める (書き出し先に終わっていない作業があれば断る)
+    // restore_meta を立てると元の日時と属性を戻す
+    pub fn create(
+        dest: &Path,
+        device: &str,
+        conflict: ConflictPolicy,
+        restore_meta: bool,
+        items: Vec<PlanItem>,
+    ) -> Result<Self> {
+        let dir = dest.join(JOB_DIR);
+        if dir.join(JOB_FILE).exists() {
+            bail!(
+                "an unfinished recovery exists in {}; resume it or remove {}",
+                dest.display(),
+                JOB_DIR
+            );
+        }
+        std::fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+        let job = Job {
+            device: device.to_string(),
+            conflict,
+            restore_meta,
+            manifest_name: manifest::new_name(dest),
+            items,
+        };
+        let tmp = dir.join("job.tmp");
+        serde_json::to_writer(BufWriter::new(File::create(&tmp)?), &job)?;
+        std::fs::rename(&tmp, dir.join(JOB_FILE))?;
+        File::create(dir.join(DONE_FILE))?;
+        Ok(Self {
+            dest: dest.to_path_buf(),
+            job,
+            withheld: HashSet::new(),
+            done: HashSet::new(),
+            manifest: BTreeMap::new(),
+        })
+    }
+
+    // 前回の続きから
+    pub fn open(dest: &Path) -> Result<Self> {
+        let dir = dest.join(JOB_DIR);
+        let f = File::open(dir.join(JOB_FILE))
+            .with_context(|| format!("no unfinished recovery in {}", dest.display()))?;
+        let job: Job = serde_json::from_reader(BufReader::new(f)).context("read job file")?;
+        let mut done = HashSet::new();
+        let mut manifest = BTreeMap::new();
+        if let Ok(f) = File::open(dir.join(DONE_FILE)) {
+            for line in BufReader::new(f).lines() {
+                // 書きかけの最後の行は読み飛ばす
+                if let Ok(e) = serde_json::from_str::<DoneEntry>(&line?) {
+                    done.insert(e.index);
+                    if let Some(m) = e.manifest {
+                        manifest.insert(e.index, m);
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            dest: dest.to_path_buf(),
+            job,
+            withheld: HashSet::new(),
+            done,
+            manifest,
+        })
+    }
+
+    pub fn device(&self) -> &str {
+        &self.job.device
+    }
+
+    // これらのレコードのファイルは書き出さずに失敗にする
+    pub fn withhold(&mut self, records: HashSet<u64>) {
+        self.withheld = records;
+    }
+
+    pub fn len(&self) -> usize {
+        self.job.items.len()
+    }
+
+    pub fn run(
+        &mut self,
+        stream: &MftStream,
+        mut emit: impl FnMut(RecoverEvent),
+    ) -> Result<RecoverSummary> {
+        let total = self.job.items.len();
+        let total_bytes: u64 = self.job.items.iter().map(|i| i.size).sum();
+        let mut done_bytes: u64 = self
+            .job
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.done.contains(i))
+            .map(|(_, it)| it.size)
+            .sum();
+        emit(RecoverEvent::Started {
+            total,
+            bytes: total_bytes,
+            already_done: self.done.len(),
+        });
+        let mut log = File::options()
+            .append(true)
+            .create(true)
+            .open(self.dest.join(JOB_DIR).join(DONE_FILE))?;
+        let mut summary = RecoverSummary {
+            destination: escape_path(&self.dest),
+            ..Default::default()
+        };
+        // 一覧に残りぐあいを書くため ($Bitmap が読めなければ unknown)
+        let bitmap = match ClusterBitmap::load(stream) {
+            Ok(b) => Some(b),
+            Err(e) => {
+                warn!(error = %format!("{:#}", e), "failed to load $Bitmap");
+                None
+            }
+        };
+
+        for index in 0..total {
+            if self.done.contains(&index) {
+                continue;
+            }
+            if RECOVER_CANCEL.load(Ordering::Relaxed) {
+                summary.cancelled = true;
+                break;
+            }
+            let item = self.job.items[index].clone();
+            let (dest, manifest) = match self.recover_one(stream, bitmap.as_ref(), &item) {
+                Ok(Outcome::Written(p, m)) => (Some(escape_path(&p)), m.map(|m| *m)),
+                Ok(Outcome::Skipped) => (None, None),
+                Ok(Outcome::Cancelled) => {
+                    summary.cancelled = true;
+                    break;
+                }
+                Err(e) => {
+                    let error = format!("{:#}", e);
+                    warn!(source = %item.source, error = %error, "failed to recover");
+                    summary.failed += 1;
+                    emit(RecoverEvent::Failed {
+                        index,
+                        source: item.source,
+                        error,
+                    });
+                    continue;
+                }
+            };
+            let skipped = dest.is_none();
+            if skipped {
+                summary.skipped += 1;
+            } else if item.mft_no.is_some() {
+                summary.copied += 1;
+            }
+            done_bytes += item.size;
+            let entry = DoneEntry {
+                index,
+                dest: dest.clone(),
+                manifest,
+            };
+            writeln!(log, "{}", serde_json::to_string(&entry)?)?;
+            self.done.insert(index);
+            if let Some(m) = entry.manifest {
+                self.manifest.insert(index, m);
+            }
+            emit(RecoverEvent::File {
+                index,
+                total,
+                source: item.source,
+                dest,
+                skipped,
+                done_bytes,
+                total_bytes,
+            });
+        }
+        log.flush()?;
+        drop(log);
+
+        // フォルダの日時は中身を書き終えてから (中に書くと更新日時が変わる)
+        if self.job.restore_meta && !summary.cancelled {
+            for (index, item) in self.job.items.iter().enumerate().rev() {
+                if item.mft_no.is_some() || !self.done.contains(&index) {
+                    continue;
+                }
+                if let Some(meta) = &item.meta {
+                    let target = target_path(&self.dest, &item.source);
+                    if let Err(e) = file_meta::apply(&target, meta) {
+                        warn!(error = %format!("{:#}", e), "failed to restore folder times");
+                    }
+                }
+            }
+        }
+
+        // 一覧は途中で止めた場合も書く (続きから再開すると書き直す)
+        let complete = !summary.cancelled && summary.failed == 0;
+        if !self.manifest.is_empty() {
+            let entries: Vec<ManifestEntry> = self.manifest.values().cloned().collect();
+            let json = manifest::write(
+                &self.dest,
+                &self.job.manifest_name,
+                &self.job.device,
+                &summary.destination,
+                complete,
+                &entries,
+            )
+            .context("failed to write the recovery manifest")?;
+            summary.manifest = Some(escape_path(&json));
+        }
+
+        // 全部終わったら途中経過は要らない (失敗があれば残してやり直せるようにする)
+        if complete {
+            let _ = std::fs::remove_dir_all(self.dest.join(JOB_DIR));
+        }
+        info!(
+            copied = summary.copied,
+            skipped = summary.skipped,
+            failed = summary.failed,
+            cancelled = summary.cancelled,
+            "recovery finished"
+        );
+        emit(RecoverEvent::Finished(summary.clone()));
+        Ok(summary)
+    }
+
+    fn recover_one(
+        &self,
+        stream: &MftStream,
+        bitmap: Option<&ClusterBitmap>,
+        item: &PlanItem,
+    ) -> Result<Outcome> {
+        let target = target_path(&self.dest, &item.source);
+        let Some(mft_no) = item.mft_no else {
+            std::fs::create_dir_all(&target)
+                .with_context(|| format!("create {}", target.display()))?;
+            return Ok(Outcome::Written(target, None));
+        };
+        if self.withheld.contains(&mft_no) {
+            bail!("the file's clusters have been reused since the snapshot was taken");
+        }
+        let rec = stream
+            .read_record(mft_no)
+            .context("failed to read the record")?;
+        let header = RecordHeader::parse(&rec).context("broken record")?;
+        // 削除後に別のファイルに使われたレコードは中身が違う
+        // 使用中になっていなくても、一度使われて消されていればシーケンス番号が進んでいる
+        if header.is_used() && !item.live {
+            bail!("the record has been reused by another file");
+        }
+        if let Some(sequence) = item.sequence {
+            if header.sequence != sequence {
+                bail!(
+                    "the record has been reused by another file (sequence {} is now {})",
+                    sequence,
+                    header.sequence
+                );
+            }
+        }
+        let mut entry = ManifestEntry::new(&item.source, mft_no, item.size, &rec);
+        entry.recoverability = match (item.live, bitmap) {
+            (true, _) => Recoverability::Live,
+            (false, Some(b)) => b.assess(&rec),
+            (false, None) => Recoverability::Unknown,
+        };
+
+        let target = if target.exists() {
+            match self.job.conflict {
+                ConflictPolicy::Skip => return Ok(Outcome::Skipped),
+                ConflictPolicy::Overwrite => target,
+                ConflictPolicy::Rename => numbered(&target),
+            }
+        } else {
+            target
+        };
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("create {}", parent.display()))?;
+        }
+        let mut partial = target.clone().into_os_string();
+        partial.push(PARTIAL_SUFFIX);
+        let partial = PathBuf::from(partial);
+        let file =
+            File::create(&partial).with_context(|| format!("create {}", partial.display()))?;
+        let mut out = HashingWriter::new(BufWriter::with_capacity(1 << 20, file));
+        let res = stream
+            .copy_data(&rec, &mut out, |_| !RECOVER_CANCEL.load(Ordering::Relaxed))
+            .and_then(|c| {
+                out.flush()?;
+                Ok(c)
+            });
+        let (out, written, sha256, md5) = out.finish();
+        drop(out);
+        match res {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                let _ = std::fs::remove_file(&partial);
+                return Ok(Outcome::Cancelled);
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                return Err(e);
+            }
+        }
+        if target.exists() {
+            file_meta::clear_readonly(&target)?;
+        }
+        std::fs::rename(&partial, &target)
+            .with_context(|| format!("rename to {}", target.display()))?;
+        // データは書けているので、日時や属性を戻せなくても失敗にはしない
+        if let (true, Some(meta)) = (self.job.restore_meta, &item.meta) {
+            if let Err(e) = file_meta::apply(&target, meta) {
+                warn!(error = %format!("{:#}", e), "failed to restore file times");
+            }
+        }
+        entry.written = written;
+        entry.sha256 = sha256;
+        entry.md5 = md5;
+        entry.dest = escape_path(&target);
+        Ok(Outcome::Written(target, Some(Box::new(entry))))
+    }
+}
@@ -0,0 +1,192 @@
+use crate::mft_stream::MftStream;
+use crate::record::{self, decode_runs, ATTR_DATA};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+// 削除済みファイルのデータがまだ残っていそうかを、ボリュームの $Bitmap から判定する
+// 削除されたファイルのクラスタは空きになるので、今また使用中ならほかのファイルに上書きされている
+
+const BITMAP_RECORD: u64 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Recoverability {
+    // データがMFTレコードの中にある (クラスタを使わない)
+    Resident,
+    // 全てのクラスタがまだ空き
+    Intact,
+    // 一部のクラスタが別のファイルに使われている
+    Partial {
+        overwritten_clusters: u64,
+        total_clusters: u64,
+    },
+    // 全てのクラスタが別のファイルに使われている
+    Overwritten,
+    // 削除されていないファイル (そのまま読める)
+    Live,
+    // $DATA が無い、または空
+    NoData,
+    // レコードが読めない等で判定できない
+    Unknown,
+}
+
+// 絞り込み用の種別 (Recoverability の "kind" と同じ名前)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoverabilityKind {
+    Resident,
+    Intact,
+    Partial,
+    Overwritten,
+    Live,
+    NoData,
+    Unknown,
+}
+
+impl Recoverability {
+    pub fn kind(&self) -> RecoverabilityKind {
+        match self {
+            Recoverability::Resident => RecoverabilityKind::Resident,
+            Recoverability::Intact => RecoverabilityKind::Intact,
+            Recoverability::Partial { .. } => RecoverabilityKind::Partial,
+            Recoverability::Overwritten => RecoverabilityKind::Overwritten,
+            Recoverability::Live => RecoverabilityKind::Live,
+            Recoverability::NoData => RecoverabilityKind::NoData,
+            Recoverability::Unknown => RecoverabilityKind::Unknown,
+        }
+    }
+}
+
+pub struct ClusterBitmap {
+    bits: Vec<u8>,
+}
+
+impl ClusterBitmap {
+    pub fn load(stream: &MftStream) -> Result<Self> {
+        let rec = stream
+            .read_record(BITMAP_RECORD)
+            .context("read $Bitmap record")?;
+        let data = record::attributes(&rec)
+            .find(|a| a.type_code == ATTR_DATA && a.name_len == 0)
+            .context("$Bitmap has no $DATA attribute")?;
+        let bits = match (data.run_list(), data.resident_value()) {
+            (Some(runs), _) => {
+                let size = data.non_resident_real_size().unwrap_or(0);
+                stream.read_nonresident(runs, size)?
+            }
+            (None, Some(v)) => v.to_vec(),
+            (None, None) => bail!("$Bitmap $DATA is unreadable"),
+        };
+        Ok(Self { bits })
+    }
+
+    fn clusters(&self) -> u64 {
+        self.bits.len() as u64 * 8
+    }
+
+    // [start, end) のビットを1バイトずつ渡す (範囲の外のビットは 0)。ビットマップより先は渡さない
+    fn bytes_in(&self, start: u64, end: u64, mut f: impl FnMut(u8)) {
+        let end = end.min(self.clusters());
+        let mut c = start;
+        while c < end {
+            let base = c - c % 8;
+            let lo = c - base;
+            let hi = (end - base).min(8);
+            let mask = ((0xFFu16 << lo) & (0xFFu16 >> (8 - hi))) as u8;
+            f(self.bits[(base / 8) as usize] & mask);
+            c = base + 8;
+        }
+    }
+
+    // [start, end) の使用中のクラスタ数
+    // ビットマップより先は使用中として数える (読んでも意味がないので)
+    fn count_allocated(&self, start: u64, end: u64) -> u64 {
+        let mut n = end.saturating_sub(start.max(self.clusters()));
+        self.bytes_in(start, end, |b| n += u64::from(b.count_ones()));
+        n
+    }
+
+    // レコードの無名 $DATA が指すクラスタの割り当て状況のハッシュ (FNV-1a)
+    // スナップショットの後にそのファイルのクラスタが使われたかの判定に使う (常駐なら定数)
+    // ビットマップより先を指すランは、その長さだけを混ぜる
+    pub fn runs_digest(&self, rec: &[u8]) -> u64 {
+        let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut mix = |v: u64| {
+            h ^= v;
+            h = h.wrapping_mul(0x0000_0100_0000_01b3);
+        };
+        let runs = record::attributes(rec)
+            .find(|a| a.type_code == ATTR_DATA && a.name_len == 0)
+            .and_then(|a| a.run_list().map(decode_runs));
+        for (lcn, len) in runs.into_iter().flatten() {
+            let Some(lcn) = lcn else { continue };
+            let end = lcn.saturating_add(len);
+            self.bytes_in(lcn, end, |b| mix(u64::from(b)));
+            mix(end.saturating_sub(lcn.max(self.clusters())));
+        }
+        h
+    }
+
+    // 削除済みレコードの無名 $DATA がどれだけ残っているか
+    pub fn assess(&self, rec: &[u8]) -> Recoverability {
+        let Some(data) =
+            record::attributes(rec).find(|a| a.type_code == ATTR_DATA && a.name_len == 0)
+        else {
+            return Recoverability::NoData;
+        };
+        let Some(runs) = data.run_list() else {
+            return match data.resident_value() {
+                Some(v) if !v.is_empty() => Recoverability::Resident,
+                _ => Recoverability::NoData,
+            };
+        };
+        let (mut total, mut overwritten) = (0u64, 0u64);
+        for (lcn, len) in decode_runs(runs) {
+            // スパース領域は元からデータが無い
+            let Some(lcn) = lcn else { continue };
+            total = total.saturating_add(len);
+            overwritten += self.count_allocated(lcn, lcn.saturating_add(len));
+        }
+        match (total, overwritten) {
+            (0, _) => Recoverability::NoData,
+            (_, 0) => Recoverability::Intact,
+            (t, o) if o == t => Recoverability::Overwritten,
+            (t, o) => Recoverability::Partial {
+                overwritten_clusters: o,
+                total_clusters: t,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitmap(bits: &[u8]) -> ClusterBitmap {
+        ClusterBitmap {
+            bits: bits.to_vec(),
+        }
+    }
+
+    #[test]
+    fn counts_partial_bytes() {
+        let b = bitmap(&[0b1111_0000, 0xFF, 0b0000_0001]);
+        assert_eq!(b.count_allocated(0, 4), 0);
+        assert_eq!(b.count_allocated(2, 6), 2);
+        assert_eq!(b.count_allocated(7, 17), 10);
+        assert_eq!(b.count_allocated(17, 24), 0);
+        assert_eq!(b.count_allocated(5, 5), 0);
+    }
+
+    #[test]
+    fn runs_past_the_bitmap_count_as_allocated() {
+        let b = bitmap(&[0, 0]);
+        assert_eq!(b.count_allocated(10, 20), 4);
+        assert_eq!(b.count_allocated(100, 110), 10);
+        // 巨大なランやオーバーフローする位置でも止まる
+        assert_eq!(b.count_allocated(0, u64::MAX), u64::MAX - 16);
+        let lcn = u64::MAX - 3;
+        assert_eq!(b.count_allocated(lcn, lcn.saturating_add(10)), 3);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::search_provider::{SearchAnswer, SearchFile};
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+// 外部の検索サービスに送る前にパスの一部を伏せ字にする
+// 伏せる対象: ユーザーのプロファイル名 (Users\<名前>)、指定したフォルダ名、正規表現に一致する部分
+// 伏せ字と元の文字列の対応はこのPCの中だけに持ち、返答のパスを元に戻すのに使う
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactMode {
+    Off,
+    // USER-3fa29c01 のようなハッシュ (同じ起動中は同じ文字列なら同じ値)
+    Hash,
+    // USER-1, FOLDER-2 のような連番
+    #[default]
+    Pseudonym,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedactionConfig {
+    pub mode: RedactMode,
+    // Users / Documents and Settings の直下と、今のユーザー名
+    pub user_profiles: bool,
+    // 名前で指定するフォルダ (大文字小文字は区別しない)
+    pub folders: Vec<String>,
+    // 一致した部分だけを伏せる (顧客名や案件コードなど。複数あるときは | でつなぐ)
+    pub pattern: Option<String>,
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        Self {
+            mode: RedactMode::Pseudonym,
+            user_profiles: true,
+            folders: Vec::new(),
+            pattern: None,
+        }
+    }
+}
+
+impl RedactionConfig {
+    pub fn from_env() -> Self {
+        let mut c = Self::default();
+        match std::env::var("UNUNLINK_REDACT").unwrap_or_default().trim() {
+            "off" | "0" | "false" => c.mode = RedactMode::Off,
+            "hash" => c.mode = RedactMode::Hash,
+            _ => {}
+        }
+        if let Ok(s) = std::env::var("UNUNLINK_REDACT_USERS") {
+            c.user_profiles = !matches!(s.trim(), "0" | "false" | "off");
+        }
+        c.folders = std::env::var("UNUNLINK_REDACT_FOLDERS")
+            .unwrap_or_default()
+            .split(';')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+        c.pattern = std::env::var("UNUNLINK_REDACT_PATTERN")
+            .ok()
+            .filter(|s| !s.trim().is_empty());
+        c
+    }
+}
+
+// ハッシュの鍵は起動ごとに変える (サービス側で元の名前を総当たりで調べられないように)
+static HASH_KEY: Lazy<RandomState> = Lazy::new(RandomState::new);
+
+const PROFILE_PARENTS: &[&str] = &["users", "documents and settings"];
+
+#[derive(Clone, Copy)]
+enum Kind {
+    User,
+    Folder,
+    Text,
+}
+
+impl Kind {
+    fn prefix(self) -> &'static str {
+        match self {
+            Kind::User => "USER",
+            Kind::Folder => "FOLDER",
+            Kind::Text => "TEXT",
+        }
+    }
+}
+
+pub struct Redactor {
+    mode: RedactMode,
+    user_profiles: bool,
+    // 小文字にしたもの
+    folders: Vec<String>,
+    current_user: Option<String>,
+    pattern: Option<Regex>,
+    // 元の文字列 -> 伏せ字
+    tokens: HashMap<String, String>,
+    counters: [usize; 3],
+    // 送ったパス -> 元のパス
+    paths: HashMap<String, String>,
+    redacted: usize,
+}
+
+impl Redactor {
+    pub fn new(config: &RedactionConfig) -> Result<Self> {
+        let pattern = match config.pattern.as_deref() {
+            Some(p) => Some(Regex::new(p).context("invalid redaction pattern")?),
+            None => None,
+        };
+        Ok(Self {
+            mode: config.mode,
+            user_profiles: config.user_profiles,
+            folders: config.folders.iter().map(|f| f.to_lowercase()).collect(),
+            current_user: std::env::var("USERNAME")
+                .ok()
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_lowercase()),
+            pattern,
+            tokens: HashMap::new(),
+            counters: [0; 3],
+            paths: HashMap::new(),
+            redacted: 0,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.mode != RedactMode::Off
+    }
+
+    // 伏せ字にした箇所の数 (同じ文字列が何度出てきても数える)
+    pub fn redacted(&self) -> usize {
+        self.redacted
+    }
+
+    fn token(&mut self, kind: Kind, original: &str) -> String {
+        self.redacted += 1;
+        if let Some(t) = self.tokens.get(original) {
+            return t.clone();
+        }
+        let t = match self.mode {
+            RedactMode::Hash => {
+                format!(
+                    "{}-{:08x}",
+                    kind.prefix(),
+                    HASH_KEY.hash_one(original) as u32
+                )
+            }
+            _ => {
+                let n = &mut self.counters[kind as usize];
+                *n += 1;
+                format!("{}-{}", kind.prefix(), n)
+            }
+        };
+        self.tokens.insert(original.to_string(), t.clone());
+        t
+    }
+
+    // prev は一つ上のフォルダ名、is_last はファイル名そのものか
+    fn segment(&mut self, seg: &str, prev: Option<&str>, is_last: bool) -> String {
+        let lower = seg.to_lowercase();
+        if self.user_profiles
+            && !is_last
+            && (prev.is_some_and(|p| PROFILE_PARENTS.contains(&p.to_lowercase().as_str()))
+                || self.current_user.as_deref() == Some(lower.as_str()))
+        {
+            return self.token(Kind::User, seg);
+        }
+        if !is_last && self.folders.contains(&lower) {
+            return self.token(Kind::Folder, seg);
+        }
+        let Some(re) = self.pattern.clone() else {
+            return seg.to_string();
+        };
+        let mut out = String::with_capacity(seg.len());
+        let mut last = 0;
+        for m in re.find_iter(seg) {
+            if m.as_str().is_empty() {
+                continue;
+            }
+            out.push_str(&seg[last..m.start()]);
+            out.push_str(&self.token(Kind::Text, m.as_str()));
+            last = m.end();
+        }
+        out.push_str(&seg[last..]);
+        out
+    }
+
+    fn path(&mut self, path: &str) -> String {
+        let segs: Vec<&str> = path.split('\\').collect();
+        let mut out = Vec::with_capacity(segs.len());
+        for (i, seg) in segs.iter().enumerate() {
+            // 先頭はドライブ文字 (R:)
+            if i == 0 {
+                out.push(seg.to_string());
+                continue;
+            }
+            out.push(self.segment(seg, Some(segs[i - 1]), i == segs.len() - 1));
+        }
+        out.join("\\")
+    }
+
+    pub fn redact(&mut self, mut file: SearchFile) -> SearchFile {
+        if !self.enabled() {
+            return file;
+        }
+        let real = std::mem::take(&mut file.path);
+        file.path = self.path(&real);
+        let parent = real.rsplit('\\').nth(1);
+        file.name = self.segment(&file.name, parent, true);
+        self.paths.insert(file.path.clone(), real);
+        file
+    }
+
+    // 返答の伏せ字を元に戻す (文章はこのPCでの表示用)
+    pub fn restore(&self, mut answer: SearchAnswer) -> SearchAnswer {
+        if !self.enabled() {
+            return answer;
+        }
+        answer.paths = answer
+            .paths
+            .into_iter()
+            .map(|p| match self.paths.get(&p) {
+                Some(real) => real.clone(),
+                None => self
+                    .paths
+                    .iter()
+                    .find(|(sent, _)| sent.eq_ignore_ascii_case(&p))
+                    .map(|(_, real)| real.clone())
+                    .unwrap_or_else(|| self.restore_text(&p)),
+            })
+            .collect();
+        answer.text = self.restore_text(&answer.text);
+        answer
+    }
+
+    fn restore_text(&self, text: &str) -> String {
+        // USER-1 が USER-12 の一部を置き換えないよう長いものから
+        let mut pairs: Vec<(&String, &String)> =
+            self.tokens.iter().map(|(orig, t)| (t, orig)).collect();
+        pairs.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(b.0)));
+        let mut out = text.to_string();
+        for (t, orig) in pairs {
+            if out.contains(t.as_str()) {
+                out = out.replace(t.as_str(), orig);
+            }
+        }
+        out
+    }
+}
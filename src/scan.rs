@@ -1,24 +1,117 @@
-use crate::indexer::{apply_staging, Candidate, DeletedIndex};
+use crate::indexer::{
+    apply_staging, Candidate, CandidateName, DeletedIndex, DupNaming, DupOrder, DupPolicy,
+    ParentStatus,
+};
+use crate::mft_stream::{DirTable, MftStream, ParentPath};
+use crate::record::{self, RecordHeader};
+use crate::spill::SpillBudget;
+use crate::timestamps::RecordTimes;
+use crate::upcase::UpCaseTable;
 use crossbeam_channel::{Receiver, Sender};
 use ntfs_reader::api::FIRST_NORMAL_RECORD;
-use ntfs_reader::file_info::{FileInfo, VecCache};
 use ntfs_reader::mft::Mft;
 use parking_lot::RwLock;
+use std::path::PathBuf;
 use std::sync::{
     atomic::{AtomicBool, AtomicU64, Ordering},
     Arc,
 };
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 use tauri::Manager;
+use widestring::U16String;
 
 pub static CANCEL: AtomicBool = AtomicBool::new(false);
 
-pub fn start_scanner_pool(
-    shared_mft: Arc<RwLock<Mft>>,
-    tx: Sender<Candidate>,
-    processed: Arc<AtomicU64>,
-    max_record: u64,
-) -> Vec<std::thread::JoinHandle<()>> {
+// 元の場所まで辿れなかった削除済みファイルを置くディレクトリ
+pub const ORPHANS_DIR: &str = "$Orphans";
+// 親フォルダのレコードが別のものに再利用されていたファイルを置くディレクトリ
+pub const REUSED_PARENT_DIR: &str = "$ReusedParent";
+// 比較用に取り込んだ削除されていないファイルを置くディレクトリ
+pub const LIVE_DIR: &str = "$Live";
+
+// スキャン方法の設定 (環境変数から読む)
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    // None の場合は $MFT の大きさと metadata_budget から自動で決める
+    pub streaming: Option<bool>,
+    // インデックスの見積もりがこれを超えたら、以降に足すノード・名前・子の索引・ファイル情報を
+    // 一時ファイルへ書き出す (一時ファイルのページのキャッシュと、重なった名前の記録はメモリに置く)
+    pub metadata_budget: Option<u64>,
+    // 予算を超えた分の書き出し先 (None なら一時フォルダ)。スキャンするボリューム上には置けない
+    pub spill_dir: Option<PathBuf>,
+    pub window_records: u64,
+    // 削除されていないファイルも \$Live 以下に取り込む
+    pub include_live: bool,
+    // 同じ名前が重なったときの付け直し方
+    pub dup_policy: DupPolicy,
+    // スキャン後にファイルの先頭を読んで中身から種類を判定する
+    pub detect_types: bool,
+}
+
+impl ScanOptions {
+    pub fn from_env() -> Self {
+        let streaming = std::env::var("UNUNLINK_MFT_STREAMING")
+            .ok()
+            .map(|s| matches!(s.trim(), "1" | "true" | "on"));
+        // 大きすぎてバイトにできない値は指定なしとして扱う
+        let metadata_budget = std::env::var("UNUNLINK_METADATA_BUDGET_MB")
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .and_then(|mb| mb.checked_mul(1024 * 1024));
+        let spill_dir = std::env::var("UNUNLINK_SPILL_DIR")
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from);
+        let window_records = std::env::var("UNUNLINK_MFT_WINDOW")
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .unwrap_or(4096)
+            .max(1);
+        let include_live = std::env::var("UNUNLINK_INCLUDE_LIVE")
+            .map(|s| matches!(s.trim(), "1" | "true" | "on"))
+            .unwrap_or(false);
+        let naming = match std::env::var("UNUNLINK_DUP_NAMING")
+            .unwrap_or_default()
+            .trim()
+        {
+            "mft" => DupNaming::MftNumber,
+            "timestamp" | "time" => DupNaming::Timestamp,
+            _ => DupNaming::Numeric,
+        };
+        let order = match std::env::var("UNUNLINK_DUP_ORDER")
+            .unwrap_or_default()
+            .trim()
+        {
+            "modified" | "time" => DupOrder::Modified,
+            _ => DupOrder::MftNumber,
+        };
+        let detect_types = std::env::var("UNUNLINK_DETECT_TYPES")
+            .map(|s| !matches!(s.trim(), "0" | "false" | "off"))
+            .unwrap_or(true);
+        Self {
+            streaming,
+            metadata_budget,
+            spill_dir,
+            window_records,
+            include_live,
+            dup_policy: DupPolicy { naming, order },
+            detect_types,
+        }
+    }
+
+    // $MFT を丸ごと読み込むと予算の半分を超える場合はストリーミングにする
+    pub fn use_streaming(&self, mft_bytes: u64) -> bool {
+        match (self.streaming, self.metadata_budget) {
+            (Some(v), _) => v,
+            (None, Some(budget)) => mft_bytes > budget / 2,
+            (None, None) => false,
+        }
+    }
+}
+
+fn scan_thread_count() -> usize {
     let mut threads = ((num_cpus::get() as f64) * 0.7).round() as usize;
     if threads < 2 {
         threads = 2;
@@ -28,9 +121,23 @@ pub fn start_scanner_pool(
             threads = v.max(1);
         }
     }
+    threads
+}
+
+// 全件読み込み済みの Mft を走査する版
+// パスは FileInfo を経由せず、レコードの名前と DirTable から UTF-16 のまま組み立てる
+pub fn start_scanner_pool(
+    shared_mft: Arc<RwLock<Mft>>,
+    dirs: Arc<DirTable>,
+    tx: Sender<Candidate>,
+    processed: Arc<AtomicU64>,
+    max_record: u64,
+    include_live: bool,
+) -> Vec<std::thread::JoinHandle<()>> {
+    let threads = scan_thread_count();
     let start = FIRST_NORMAL_RECORD as u64;
     let end = max_record;
-    let span = (end - start + threads as u64 - 1) / threads as u64;
+    let span = (end - start).div_ceil(threads as u64);
     let mut handles = Vec::with_capacity(threads);
     for i in 0..threads {
         let range_start = start + span * i as u64;
@@ -39,38 +146,24 @@ pub fn start_scanner_pool(
             break;
         }
         let mft_arc = shared_mft.clone();
+        let dirs = dirs.clone();
         let tx_cloned = tx.clone();
         let processed_cloned = processed.clone();
         let h = std::thread::spawn(move || {
-            let mut cache = VecCache::default();
             for number in range_start..range_end {
                 if CANCEL.load(Ordering::Relaxed) {
                     break;
                 }
                 let cand_opt = {
                     let mft_read = mft_arc.read();
-                    if let Some(file) = mft_read.get_record(number) {
-                        if file.is_used() {
-                            processed_cloned.fetch_add(1, Ordering::Relaxed);
-                            None
-                        } else {
-                            let infox = FileInfo::with_cache(&mft_read, &file, &mut cache);
-                            processed_cloned.fetch_add(1, Ordering::Relaxed);
-                            Some(Candidate {
-                                mft_no: number,
-                                path: infox.path.display().to_string(),
-                                size: infox.size,
-                                is_dir: file.is_directory(),
-                                created: infox.created.map(|t| t.unix_timestamp()),
-                                modified: infox.modified.map(|t| t.unix_timestamp()),
-                                accessed: infox.accessed.map(|t| t.unix_timestamp()),
-                            })
-                        }
-                    } else {
-                        processed_cloned.fetch_add(1, Ordering::Relaxed);
-                        None
-                    }
+                    candidate_from_record(
+                        number,
+                        mft_read.get_record_data(number),
+                        &dirs,
+                        include_live,
+                    )
                 };
+                processed_cloned.fetch_add(1, Ordering::Relaxed);
                 if let Some(cand) = cand_opt {
                     if tx_cloned.send(cand).is_err() {
                         break;
@@ -83,13 +176,161 @@ pub fn start_scanner_pool(
     handles
 }
 
+// ストリーミング版のスキャナ
+// 各スレッドが担当範囲をウィンドウ単位で読み、親パスは DirTable から引く
+pub fn start_streaming_scanner_pool(
+    stream: Arc<MftStream>,
+    dirs: Arc<DirTable>,
+    tx: Sender<Candidate>,
+    processed: Arc<AtomicU64>,
+    window: u64,
+    include_live: bool,
+) -> Vec<std::thread::JoinHandle<()>> {
+    let threads = scan_thread_count();
+    let start = FIRST_NORMAL_RECORD as u64;
+    let end = stream.max_record;
+    if end <= start {
+        return Vec::new();
+    }
+    let span = (end - start).div_ceil(threads as u64);
+    let mut handles = Vec::with_capacity(threads);
+    for i in 0..threads {
+        let range_start = start + span * i as u64;
+        let range_end = std::cmp::min(end, range_start + span);
+        if range_start >= range_end {
+            break;
+        }
+        let stream = stream.clone();
+        let dirs = dirs.clone();
+        let tx_cloned = tx.clone();
+        let processed_cloned = processed.clone();
+        let h = std::thread::spawn(move || {
+            let rs = stream.boot.record_size as usize;
+            let mut win_start = range_start;
+            'outer: while win_start < range_end {
+                let count = std::cmp::min(window, range_end - win_start);
+                let buf = match stream.read_window(win_start, count) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        tracing::warn!(start = win_start, error = %e, "failed to read MFT window");
+                        processed_cloned.fetch_add(count, Ordering::Relaxed);
+                        win_start += count;
+                        continue;
+                    }
+                };
+                for (i, rec) in buf.chunks(rs).enumerate() {
+                    if CANCEL.load(Ordering::Relaxed) {
+                        break 'outer;
+                    }
+                    processed_cloned.fetch_add(1, Ordering::Relaxed);
+                    if let Some(cand) =
+                        candidate_from_record(win_start + i as u64, rec, &dirs, include_live)
+                    {
+                        if tx_cloned.send(cand).is_err() {
+                            break 'outer;
+                        }
+                    }
+                }
+                win_start += count;
+            }
+        });
+        handles.push(h);
+    }
+    handles
+}
+
+fn candidate_from_record(
+    number: u64,
+    rec: &[u8],
+    dirs: &DirTable,
+    include_live: bool,
+) -> Option<Candidate> {
+    let h = RecordHeader::parse(rec)?;
+    if !h.is_base() {
+        return None;
+    }
+    // 削除されていないものは比較用にファイルだけ取り込む (フォルダは途中のパスとして作られる)
+    let live = h.is_used();
+    if live && (!include_live || h.is_directory()) {
+        return None;
+    }
+    let links = record::link_names(rec);
+    let primary = links.first()?;
+    let si = record::standard_info(rec);
+    let is_dir = h.is_directory();
+    let size = if is_dir {
+        0
+    } else {
+        record::data_size(rec).unwrap_or(primary.long.real_size)
+    };
+    let parent_ref = primary.long.parent_ref;
+    let (_, parent_status) = place_under_parent(dirs, parent_ref);
+    let names = links
+        .iter()
+        .map(|l| {
+            let (parent, _) = place_under_parent(dirs, l.long.parent_ref);
+            let mut path: Vec<u16> = Vec::new();
+            if live {
+                path.push(b'\\' as u16);
+                path.extend(LIVE_DIR.encode_utf16());
+            }
+            path.extend_from_slice(&parent);
+            path.push(b'\\' as u16);
+            path.extend_from_slice(&l.long.name);
+            CandidateName {
+                path: U16String::from_vec(path),
+                short_name: l.short.clone().map(U16String::from_vec),
+            }
+        })
+        .collect();
+    Some(Candidate {
+        mft_no: number,
+        sequence: h.sequence,
+        names,
+        size,
+        is_dir,
+        live,
+        parent_ref,
+        parent_status,
+        times: RecordTimes::new(si.as_ref(), &record::file_names(rec)),
+        attributes: si.map(|s| s.file_attributes).unwrap_or(0),
+    })
+}
+
+// 親フォルダのパスを決める
+// 親を辿りきれなかったものは \$Orphans\<切れた親のレコード番号>\ の下に、
+// 辿れた分の階層を残したまま置く (同じ親を持っていたもの同士がまとまるように)
+// 親のレコードが今は別のフォルダ等に使われている場合は、そのまま辿ると
+// 無関係な場所に見えてしまうので \$ReusedParent\<元の親のレコード番号>\ に分ける
+fn place_under_parent(dirs: &DirTable, parent_ref: u64) -> (Vec<u16>, ParentStatus) {
+    match dirs.resolve(parent_ref) {
+        ParentPath::Rooted(parent) => (parent, ParentStatus::Resolved),
+        ParentPath::Orphan {
+            broken,
+            path,
+            reused,
+        } => {
+            let (top, status) = if reused {
+                (REUSED_PARENT_DIR, ParentStatus::Reused)
+            } else {
+                (ORPHANS_DIR, ParentStatus::Orphan)
+            };
+            let mut p: Vec<u16> = format!("\\{}\\{}", top, broken).encode_utf16().collect();
+            p.extend_from_slice(&path);
+            (p, status)
+        }
+    }
+}
+
 pub fn indexer_worker(
     rx: Receiver<Candidate>,
     found_counter: Arc<AtomicU64>,
     flush_every: usize,
+    metadata_budget: Option<SpillBudget>,
+    upcase: Arc<UpCaseTable>,
+    dup_policy: DupPolicy,
 ) -> DeletedIndex {
-    let mut idx = DeletedIndex::default();
-    idx.ensure_dirs_from_root("\\");
+    let mut idx = DeletedIndex::new(metadata_budget, upcase);
     let mut staging: Vec<Candidate> = Vec::with_capacity(flush_every * 2);
     loop {
         if CANCEL.load(Ordering::Relaxed) {
@@ -114,6 +355,7 @@ pub fn indexer_worker(
         }
     }
     apply_staging(&mut idx, &mut staging, &found_counter);
+    idx.finalize_names(dup_policy);
     idx
 }
 
@@ -127,6 +369,32 @@ pub struct ProgressPayload {
     pub msg: String,
 }
 
+// 進捗を送るスレッド。stop で止めて終わるのを待つ
+// スキャンの途中で失敗して抜けたときも送り続けないよう、drop でも止める
+pub struct ProgressThread {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ProgressThread {
+    pub fn stop(mut self) {
+        self.stop_and_join();
+    }
+
+    fn stop_and_join(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
+impl Drop for ProgressThread {
+    fn drop(&mut self) {
+        self.stop_and_join();
+    }
+}
+
 pub fn progress_loop_emit(
     app: tauri::AppHandle,
     processed: Arc<AtomicU64>,
@@ -134,8 +402,9 @@ pub fn progress_loop_emit(
     total: u64,
     running: Arc<AtomicBool>,
     start: Instant,
-) -> std::thread::JoinHandle<()> {
-    std::thread::spawn(move || {
+) -> ProgressThread {
+    let flag = running.clone();
+    let handle = std::thread::spawn(move || {
         while running.load(Ordering::Relaxed) && !CANCEL.load(Ordering::Relaxed) {
             let p = processed.load(Ordering::Relaxed);
             let f = found.load(Ordering::Relaxed);
@@ -192,5 +461,9 @@ pub fn progress_loop_emit(
                 },
             },
         );
-    })
+    });
+    ProgressThread {
+        running: flag,
+        handle: Some(handle),
+    }
 }
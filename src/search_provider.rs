@@ -0,0 +1,458 @@
+use crate::filetype::FileFamily;
+use crate::query::QueryHit;
+use crate::timestamps::unix_to_civil;
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::blocking::{multipart, Client};
+use serde::Serialize;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// 自然文でのファイル検索を外部のサービスに頼む
+// 送るのはファイル一覧 (名前・パス・種類・日時) と問い合わせ文だけ
+
+pub const DEFAULT_SEARCH_URL: &str = "https://internal-searchfile-sendpoint.end2end.tech";
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_MAX_UPLOAD_MB: u64 = 20;
+
+// サービスに渡す1ファイル分 (/search-file の filelist.json の形)
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchFile {
+    pub name: String,
+    pub path: String,
+    pub ext: String,
+    pub family: FileFamily,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime: Option<&'static str>,
+    // 現地時刻の ISO 8601 (タイムゾーン無し)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_opened: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl SearchFile {
+    // utc_offset は現地時刻と UTC の差 (秒)
+    pub fn from_hit(h: &QueryHit, utc_offset: i64) -> Self {
+        let local = |ts: Option<i64>, nsec: Option<u32>| {
+            ts.map(|ts| local_iso(ts + utc_offset, nsec.unwrap_or(0)))
+        };
+        Self {
+            name: h.name.clone(),
+            path: h.path.clone(),
+            ext: h.ext.clone(),
+            family: h.family,
+            mime: h.mime,
+            last_opened: local(h.accessed_ts, h.accessed_nsec),
+            last_modified: local(h.modified_ts, h.modified_nsec),
+        }
+    }
+}
+
+fn local_iso(secs: i64, nsec: u32) -> String {
+    let c = unix_to_civil(secs);
+    let mut s = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        c.year, c.month, c.day, c.hour, c.minute, c.second
+    );
+    if nsec > 0 {
+        s.push_str(&format!(".{:09}", nsec));
+    }
+    s
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchAnswer {
+    // サービスの返答そのまま
+    pub text: String,
+    // 返答に含まれていたパス (重複なし、出てきた順)
+    pub paths: Vec<String>,
+    pub upload: UploadSummary,
+}
+
+// 送る内容の要約 (送信前の確認と記録用)
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadSummary {
+    pub endpoint: String,
+    // 実際に送る件数 (大きさの上限で削った場合は渡した件数より少ない)
+    pub files: usize,
+    pub bytes: usize,
+    // 1件でも値があった項目
+    pub fields: Vec<&'static str>,
+}
+
+pub trait SearchProvider {
+    // 送信はせずに、送る内容を確かめる
+    fn summarize(&self, files: &[SearchFile]) -> Result<UploadSummary>;
+    fn search(&self, prompt: &str, files: &[SearchFile]) -> Result<SearchAnswer>;
+}
+
+// POST {url}/search-file に multipart で prompt と json_file (filelist.json) を送る
+pub struct HttpSearchProvider {
+    pub url: String,
+    // 指定すると Authorization: Bearer を付ける
+    pub token: Option<String>,
+    pub timeout: Duration,
+    // filelist.json の上限 (バイト)
+    pub max_upload: usize,
+}
+
+impl HttpSearchProvider {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            token: None,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            max_upload: (DEFAULT_MAX_UPLOAD_MB * 1024 * 1024) as usize,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let url = std::env::var("UNUNLINK_SEARCH_URL")
+            .ok()
+            .filter(|s| !s.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_SEARCH_URL.to_string());
+        let mut p = Self::new(url.trim());
+        p.token = std::env::var("UNUNLINK_SEARCH_TOKEN")
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+        if let Some(secs) = std::env::var("UNUNLINK_SEARCH_TIMEOUT_SECS")
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+        {
+            p.timeout = Duration::from_secs(secs.max(1));
+        }
+        if let Some(mb) = std::env::var("UNUNLINK_SEARCH_MAX_MB")
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+        {
+            p.max_upload = (mb.max(1) * 1024 * 1024) as usize;
+        }
+        p
+    }
+
+    fn prepare(&self, files: &[SearchFile]) -> Result<(Vec<u8>, UploadSummary)> {
+        let (json, sent) = file_list_json(files, self.max_upload)?;
+        let summary = UploadSummary {
+            endpoint: format!("{}/search-file", self.url),
+            files: sent,
+            bytes: json.len(),
+            fields: sent_fields(&files[..sent]),
+        };
+        Ok((json, summary))
+    }
+}
+
+impl SearchProvider for HttpSearchProvider {
+    fn summarize(&self, files: &[SearchFile]) -> Result<UploadSummary> {
+        Ok(self.prepare(files)?.1)
+    }
+
+    fn search(&self, prompt: &str, files: &[SearchFile]) -> Result<SearchAnswer> {
+        let (json, upload) = self.prepare(files)?;
+        if upload.files < files.len() {
+            warn!(
+                sent = upload.files,
+                total = files.len(),
+                limit = self.max_upload,
+                "file list truncated to fit the upload limit"
+            );
+        }
+        let endpoint = upload.endpoint.clone();
+        info!(
+            url = %endpoint,
+            files = upload.files,
+            bytes = upload.bytes,
+            fields = %upload.fields.join(","),
+            prompt_chars = prompt.chars().count(),
+            "sending search request"
+        );
+
+        let client = Client::builder()
+            .timeout(self.timeout)
+            .build()
+            .context("build http client")?;
+        let part = multipart::Part::bytes(json)
+            .file_name("filelist.json")
+            .mime_str("application/json")?;
+        let form = multipart::Form::new()
+            .text("prompt", prompt.to_string())
+            .part("json_file", part);
+        let mut req = client.post(&endpoint).multipart(form);
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        let resp = req
+            .send()
+            .with_context(|| format!("request to {}", endpoint))?;
+        let status = resp.status();
+        let text = resp.text().context("read response")?;
+        if !status.is_success() {
+            let excerpt: String = text.chars().take(200).collect();
+            bail!("search service returned {}: {}", status, excerpt);
+        }
+        Ok(SearchAnswer {
+            paths: extract_paths(&text),
+            text,
+            upload,
+        })
+    }
+}
+
+fn sent_fields(files: &[SearchFile]) -> Vec<&'static str> {
+    let mut fields = vec!["name", "path", "ext", "family"];
+    if files.iter().any(|f| f.mime.is_some()) {
+        fields.push("mime");
+    }
+    if files.iter().any(|f| f.last_opened.is_some()) {
+        fields.push("last_opened");
+    }
+    if files.iter().any(|f| f.last_modified.is_some()) {
+        fields.push("last_modified");
+    }
+    if files.is_empty() {
+        fields.clear();
+    }
+    fields
+}
+
+// {"files": [...]} を max_bytes に収まる所まで作る (先頭から詰める)
+fn file_list_json(files: &[SearchFile], max_bytes: usize) -> Result<(Vec<u8>, usize)> {
+    const HEAD: &[u8] = b"{\"files\":[";
+    const TAIL: &[u8] = b"]}";
+    let mut out = HEAD.to_vec();
+    let mut sent = 0;
+    for f in files {
+        let item = serde_json::to_vec(f)?;
+        let sep = usize::from(sent > 0);
+        if out.len() + sep + item.len() + TAIL.len() > max_bytes {
+            break;
+        }
+        if sep == 1 {
+            out.push(b',');
+        }
+        out.extend_from_slice(&item);
+        sent += 1;
+    }
+    if sent == 0 && !files.is_empty() {
+        bail!("the file list does not fit in the upload limit");
+    }
+    out.extend_from_slice(TAIL);
+    Ok((out, sent))
+}
+
+static PATH_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"[A-Za-z]:[\\/][^\s"']+"#).unwrap());
+
+// 返答の文章からドライブ文字で始まるパスを拾う (末尾の句読点は外す)
+pub fn extract_paths(text: &str) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    for m in PATH_RE.find_iter(text) {
+        let p = m.as_str().trim_end_matches([')', ',', '.']);
+        if !out.iter().any(|x| x == p) {
+            out.push(p.to_string());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    // 1回だけ受け付ける HTTP サーバーの代わり。受け取ったリクエスト (ヘッダ, 本文) を返す
+    fn serve_once(
+        status: &'static str,
+        body: &'static str,
+        delay: Duration,
+    ) -> (String, JoinHandle<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let h = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" || line.is_empty() {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let len = head
+                .lines()
+                .find_map(|l| {
+                    let (k, v) = l.split_once(':')?;
+                    k.eq_ignore_ascii_case("content-length")
+                        .then(|| v.trim().parse::<usize>().ok())?
+                })
+                .unwrap_or(0);
+            let mut buf = vec![0u8; len];
+            reader.read_exact(&mut buf).unwrap();
+            std::thread::sleep(delay);
+            let resp = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            // 時間切れのテストでは相手が先に切っている
+            let _ = reader.get_mut().write_all(resp.as_bytes());
+            (head, String::from_utf8_lossy(&buf).into_owned())
+        });
+        (url, h)
+    }
+
+    fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+        head.lines().find_map(|l| {
+            let (k, v) = l.split_once(':')?;
+            k.eq_ignore_ascii_case(name).then(|| v.trim())
+        })
+    }
+
+    fn file(n: usize) -> SearchFile {
+        SearchFile {
+            name: format!("Book{}.xlsx", n),
+            path: format!(r"R:\Users\a\Book{}.xlsx", n),
+            ext: ".xlsx".into(),
+            family: FileFamily::Document,
+            mime: None,
+            last_opened: Some("2024-09-04T12:00:00".into()),
+            last_modified: None,
+        }
+    }
+
+    // 本文の filelist.json の部分
+    fn sent_json(body: &str) -> serde_json::Value {
+        let start = body.find("{\"files\"").unwrap();
+        let end = start + body[start..].find("]}").unwrap() + 2;
+        serde_json::from_str(&body[start..end]).unwrap()
+    }
+
+    #[test]
+    fn sends_prompt_and_file_list_as_multipart() {
+        let (url, h) = serve_once(
+            "200 OK",
+            r"見つかりました: R:\Users\a\Book1.xlsx",
+            Duration::ZERO,
+        );
+        let mut p = HttpSearchProvider::new(&url);
+        p.token = Some("secret".into());
+        let files: Vec<SearchFile> = (0..3).map(file).collect();
+        let a = p.search("最近のエクセル", &files).unwrap();
+        assert_eq!(a.paths, vec![r"R:\Users\a\Book1.xlsx"]);
+        assert_eq!(a.upload.files, 3);
+        assert_eq!(
+            a.upload.fields,
+            vec!["name", "path", "ext", "family", "last_opened"]
+        );
+
+        let (head, body) = h.join().unwrap();
+        assert!(head.starts_with("POST /search-file "), "{}", head);
+        assert_eq!(header(&head, "authorization"), Some("Bearer secret"));
+        assert!(header(&head, "content-type")
+            .unwrap()
+            .starts_with("multipart/form-data"));
+        assert!(body.contains("name=\"prompt\"\r\n\r\n最近のエクセル\r\n"));
+        assert!(body.contains("name=\"json_file\"; filename=\"filelist.json\""));
+        assert!(body.contains("Content-Type: application/json"));
+        assert_eq!(sent_json(&body)["files"].as_array().unwrap().len(), 3);
+        assert_eq!(sent_json(&body)["files"][0]["name"], "Book0.xlsx");
+    }
+
+    #[test]
+    fn no_token_sends_no_authorization() {
+        let (url, h) = serve_once("200 OK", "none", Duration::ZERO);
+        let a = HttpSearchProvider::new(&url)
+            .search("x", &[file(0)])
+            .unwrap();
+        assert!(a.paths.is_empty());
+        let (head, _) = h.join().unwrap();
+        assert_eq!(header(&head, "authorization"), None);
+    }
+
+    #[test]
+    fn file_list_is_truncated_to_max_upload() {
+        let (url, h) = serve_once("200 OK", "ok", Duration::ZERO);
+        let mut p = HttpSearchProvider::new(&url);
+        p.max_upload = 1000;
+        let files: Vec<SearchFile> = (0..50).map(file).collect();
+        let a = p.search("x", &files).unwrap();
+        assert!(a.upload.files > 0 && a.upload.files < 50);
+        assert!(a.upload.bytes <= 1000);
+
+        let (_, body) = h.join().unwrap();
+        let json = sent_json(&body);
+        assert_eq!(json["files"].as_array().unwrap().len(), a.upload.files);
+        assert_eq!(json.to_string().len(), a.upload.bytes);
+    }
+
+    #[test]
+    fn non_success_status_is_an_error() {
+        let (url, h) = serve_once("429 Too Many Requests", "rate limited", Duration::ZERO);
+        let e = HttpSearchProvider::new(&url)
+            .search("x", &[file(0)])
+            .unwrap_err();
+        let msg = format!("{:#}", e);
+        assert!(
+            msg.contains("429") && msg.contains("rate limited"),
+            "{}",
+            msg
+        );
+        h.join().unwrap();
+    }
+
+    #[test]
+    fn slow_service_times_out() {
+        let (url, h) = serve_once("200 OK", "late", Duration::from_secs(2));
+        let mut p = HttpSearchProvider::new(&url);
+        p.timeout = Duration::from_millis(200);
+        assert!(p.search("x", &[file(0)]).is_err());
+        h.join().unwrap();
+    }
+
+    #[test]
+    fn file_list_json_fills_from_the_front() {
+        let files: Vec<SearchFile> = (0..3).map(file).collect();
+        let (json, sent) = file_list_json(&[], 100).unwrap();
+        assert_eq!((json.as_slice(), sent), (&b"{\"files\":[]}"[..], 0));
+
+        let (all, sent) = file_list_json(&files, usize::MAX).unwrap();
+        assert_eq!(sent, 3);
+        let v: serde_json::Value = serde_json::from_slice(&all).unwrap();
+        assert_eq!(v["files"][2]["path"], r"R:\Users\a\Book2.xlsx");
+        // 時刻の無い項目は書かない
+        assert!(v["files"][0].get("last_modified").is_none());
+
+        // ちょうど収まる大きさなら全部、1バイト足りなければ最後の1件を落とす
+        let (json, sent) = file_list_json(&files, all.len()).unwrap();
+        assert_eq!((json.len(), sent), (all.len(), 3));
+        let (json, sent) = file_list_json(&files, all.len() - 1).unwrap();
+        assert_eq!(sent, 2);
+        assert!(json.len() < all.len());
+        serde_json::from_slice::<serde_json::Value>(&json).unwrap();
+
+        assert!(file_list_json(&files, 20).is_err());
+    }
+
+    #[test]
+    fn extract_paths_dedups_and_trims() {
+        let text = "候補は R:\\Users\\a\\Book1.xlsx, R:\\Users\\a\\Book1.xlsx と (C:/tmp/x.txt). \
+                    \"R:\\q\\報告.docx\" です。d:\\last.";
+        assert_eq!(
+            extract_paths(text),
+            vec![
+                r"R:\Users\a\Book1.xlsx",
+                "C:/tmp/x.txt",
+                r"R:\q\報告.docx",
+                r"d:\last"
+            ]
+        );
+        assert!(extract_paths("no paths here: R: or C:").is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+use crate::dest_guard;
+use crate::indexer::{DeletedIndex, EntryOrDir};
+use crate::mft_stream::MftStream;
+use crate::record::RecordHeader;
+use crate::recoverability::ClusterBitmap;
+use crate::scan::ScanOptions;
+use crate::upcase::{self, UpCaseTable};
+use crate::util::escape_u16;
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+// スキャン結果をファイルに保存して、次回は再スキャンせずにマウントする
+// 中身は ボリュームの識別情報 + インデックス + ファイルが指すMFTレコード (データランを含む)
+// 使用中のボリューム (C: など) は常にどこかが書き換わるので、ボリューム全体の変化では断らない
+// レコードごとにデータのクラスタの割り当てを記録しておき、変わったファイルは読み込み時に知らせて
+// そのレコードは渡さない (別のファイルのものになったかもしれないクラスタを読ませない)
+//
+// 形式 (すべてリトルエンディアン):
+//   MAGIC, VERSION(u32), デバイス名, VolumeIdentity, インデックス,
+//   レコード数(u64), {番号(u64), 使用部分の長さ(u32), 本体, クラスタの割り当てのハッシュ(u64)}...
+
+const MAGIC: &[u8; 8] = b"RMSNAP\0\0";
+const VERSION: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeIdentity {
+    pub serial: u64,
+    pub total_sectors: u64,
+    pub cluster_size: u64,
+    pub record_size: u64,
+}
+
+impl VolumeIdentity {
+    pub fn read(stream: &MftStream) -> Self {
+        Self {
+            serial: stream.boot.serial,
+            total_sectors: stream.boot.total_sectors,
+            cluster_size: stream.boot.cluster_size,
+            record_size: stream.boot.record_size,
+        }
+    }
+
+    // 保存時と同じボリューム (シリアル番号と大きさが同じ) であること
+    pub fn check(&self, current: &VolumeIdentity) -> Result<()> {
+        if self != current {
+            bail!("the snapshot was taken from a different volume");
+        }
+        Ok(())
+    }
+
+    fn write_to(&self, w: &mut impl Write) -> Result<()> {
+        for v in [
+            self.serial,
+            self.total_sectors,
+            self.cluster_size,
+            self.record_size,
+        ] {
+            w.write_all(&v.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn read_from(r: &mut impl Read) -> Result<Self> {
+        Ok(Self {
+            serial: read_u64(r)?,
+            total_sectors: read_u64(r)?,
+            cluster_size: read_u64(r)?,
+            record_size: read_u64(r)?,
+        })
+    }
+}
+
+pub struct Snapshot {
+    pub device: String,
+    pub index: DeletedIndex,
+    // レコード番号 -> レコード (fixup 済み、record_size の長さ)。withheld のものは含まない
+    pub records: HashMap<u64, Vec<u8>>,
+    // 保存した後にデータのクラスタの割り当てが変わったレコード (読み出しも復元もさせない)
+    pub withheld: HashSet<u64>,
+    // withheld のファイル (R:\... のパス、エスケープ済み)
+    pub stale: Vec<String>,
+}
+
+// 一時ファイルに書いてから置き換える (途中で失敗しても前のスナップショットは壊さない)
+// レコードとダイジェストは今のボリュームから読む。スナップショットから読み込んだインデックスは渡さないこと
+pub fn save(path: &Path, device: &str, index: &DeletedIndex) -> Result<()> {
+    let stream = MftStream::open(device).context("failed to read $MFT layout")?;
+    let identity = VolumeIdentity::read(&stream);
+    let bitmap = ClusterBitmap::load(&stream).context("load $Bitmap")?;
+    let tmp = path.with_extension("tmp");
+    let file = File::create(&tmp).with_context(|| format!("create {}", tmp.display()))?;
+    let mut w = BufWriter::with_capacity(1 << 20, file);
+
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    let dev = device.as_bytes();
+    w.write_all(&(dev.len() as u16).to_le_bytes())?;
+    w.write_all(dev)?;
+    identity.write_to(&mut w)?;
+    index.write_to(&mut w).context("write index")?;
+
+    let numbers = index.file_records();
+    w.write_all(&(numbers.len() as u64).to_le_bytes())?;
+    let mut missing = 0usize;
+    for no in &numbers {
+        // 読めなかったレコードは長さ0で残す (その後の読み出しではデータ無し扱い)
+        let rec = stream.read_record(*no).unwrap_or_default();
+        let used = match RecordHeader::parse(&rec) {
+            Some(h) => std::cmp::min(h.used_size as usize, rec.len()),
+            None => {
+                missing += 1;
+                0
+            }
+        };
+        w.write_all(&no.to_le_bytes())?;
+        w.write_all(&(used as u32).to_le_bytes())?;
+        w.write_all(&rec[..used])?;
+        w.write_all(&bitmap.runs_digest(&rec[..used]).to_le_bytes())?;
+    }
+    w.flush()?;
+    drop(w);
+    std::fs::rename(&tmp, path).with_context(|| format!("rename to {}", path.display()))?;
+    if missing > 0 {
+        warn!(
+            missing,
+            "some records could not be read while saving snapshot"
+        );
+    }
+    info!(path = %path.display(), records = numbers.len(), "snapshot saved");
+    Ok(())
+}
+
+// device を指定すると保存時とは別のパス (ドライブ文字が変わった等) で開く
+pub fn load(path: &Path, device: Option<&str>, opts: &ScanOptions) -> Result<Snapshot> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut r = BufReader::with_capacity(1 << 20, file);
+
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic).context("read snapshot header")?;
+    if &magic != MAGIC {
+        bail!("not a snapshot file");
+    }
+    let version = read_u32(&mut r)?;
+    if version != VERSION {
+        bail!("unsupported snapshot version {}", version);
+    }
+    let mut dev = vec![0u8; read_u16(&mut r)? as usize];
+    r.read_exact(&mut dev)?;
+    let saved_device = String::from_utf8(dev).context("bad device name")?;
+    let device = device.map_or(saved_device, str::to_string);
+    let saved = VolumeIdentity::read_from(&mut r)?;
+
+    let stream = MftStream::open(&device).context("failed to read $MFT layout")?;
+    saved.check(&VolumeIdentity::read(&stream))?;
+    // 読めなければ変化は確かめない (マウントはする)
+    let bitmap = match ClusterBitmap::load(&stream) {
+        Ok(b) => Some(b),
+        Err(e) => {
+            warn!(error = %e, "failed to load $Bitmap, not checking for changed files");
+            None
+        }
+    };
+
+    // 名前の比較はボリューム自身の $UpCase に合わせる (スキャン時と同じ)
+    let upcase = match UpCaseTable::load_from_volume(&stream) {
+        Ok(t) => Arc::new(t),
+        Err(e) => {
+            warn!(error = %e, "failed to load $UpCase, using built-in table");
+            Arc::new(UpCaseTable::builtin())
+        }
+    };
+    upcase::set_active(upcase.clone());
+    let budget = dest_guard::spill_budget(&device, opts);
+    let index = DeletedIndex::read_from(&mut r, budget, upcase).context("read index")?;
+
+    let count = read_u64(&mut r)?;
+    let record_size = saved.record_size as usize;
+    let mut records = HashMap::new();
+    let mut changed: HashSet<u64> = HashSet::new();
+    for _ in 0..count {
+        let no = read_u64(&mut r)?;
+        let len = read_u32(&mut r)? as usize;
+        if len > record_size {
+            bail!("bad record length in snapshot");
+        }
+        // 使用部分だけ保存してあるので元の長さに戻す
+        let mut rec = vec![0u8; record_size];
+        r.read_exact(&mut rec[..len])?;
+        let digest = read_u64(&mut r)?;
+        if len == 0 {
+            continue;
+        }
+        if bitmap
+            .as_ref()
+            .is_some_and(|b| b.runs_digest(&rec[..len]) != digest)
+        {
+            changed.insert(no);
+            continue;
+        }
+        records.insert(no, rec);
+    }
+    let stale = stale_paths(&index, &changed);
+    if !stale.is_empty() {
+        warn!(
+            files = stale.len(),
+            "clusters of some files changed since the snapshot; they will not be served"
+        );
+    }
+    info!(path = %path.display(), device = %device, records = records.len(), "snapshot loaded");
+    Ok(Snapshot {
+        device,
+        index,
+        records,
+        withheld: changed,
+        stale,
+    })
+}
+
+fn stale_paths(index: &DeletedIndex, changed: &HashSet<u64>) -> Vec<String> {
+    if changed.is_empty() {
+        return Vec::new();
+    }
+    index
+        .files(true)
+        .into_iter()
+        .filter(|&id| match index.entry(id).as_deref() {
+            Some(EntryOrDir::File(m)) => changed.contains(&m.mft_no),
+            _ => false,
+        })
+        .map(|id| format!("R:{}", escape_u16(index.path_of(id).as_slice())))
+        .collect()
+}
+
+fn read_u16(r: &mut impl Read) -> Result<u16> {
+    let mut b = [0u8; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_le_bytes(b))
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
@@ -0,0 +1,606 @@
+use crate::filetype::ContentType;
+use crate::indexer::{EntryMeta, LiveMatch, ParentStatus};
+use crate::timestamps::{FileTimes, RecordTimes, TimestompFlags};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 予算を超えた分のインデックス (ノード、名前、子の索引、エントリ情報) を一時ファイルへ逃がす
+// 一時ファイルはページ単位で読み書きし、最近使ったページだけをメモリに置く
+
+// インデックスの予算 (バイト、インデックスの見積もりと比べる) と、超えた分を書き出すフォルダ
+// dir が None なら書き出さない (予算を超えてもメモリに置いたまま)
+#[derive(Debug, Clone)]
+pub struct SpillBudget {
+    pub bytes: u64,
+    pub dir: Option<PathBuf>,
+}
+
+const PAGE_SIZE: usize = 4096;
+// 1ファイルあたりメモリに置くページ数 (4 MiB)
+const CACHED_PAGES: usize = 1024;
+
+struct Frame {
+    page: u64,
+    data: Box<[u8]>,
+    dirty: bool,
+    referenced: bool,
+}
+
+// 置ききれなくなったら、しばらく使われていないページを書き戻して入れ替える (クロック方式)
+struct Pages {
+    file: File,
+    frames: Vec<Frame>,
+    slot_of: HashMap<u64, usize>,
+    hand: usize,
+}
+
+impl Pages {
+    fn frame(&mut self, page: u64) -> io::Result<&mut Frame> {
+        let i = match self.slot_of.get(&page) {
+            Some(&i) => i,
+            None => self.load(page)?,
+        };
+        let f = &mut self.frames[i];
+        f.referenced = true;
+        Ok(f)
+    }
+
+    fn load(&mut self, page: u64) -> io::Result<usize> {
+        let i = if self.frames.len() < CACHED_PAGES {
+            self.frames.push(Frame {
+                page,
+                data: vec![0u8; PAGE_SIZE].into_boxed_slice(),
+                dirty: false,
+                referenced: false,
+            });
+            self.frames.len() - 1
+        } else {
+            let i = self.victim();
+            let f = &mut self.frames[i];
+            if f.dirty {
+                write_page(&mut self.file, f.page, &f.data)?;
+                f.dirty = false;
+            }
+            self.slot_of.remove(&f.page);
+            f.page = page;
+            i
+        };
+        read_page(&mut self.file, page, &mut self.frames[i].data)?;
+        self.slot_of.insert(page, i);
+        Ok(i)
+    }
+
+    fn victim(&mut self) -> usize {
+        loop {
+            let i = self.hand;
+            self.hand = (self.hand + 1) % self.frames.len();
+            let f = &mut self.frames[i];
+            if !f.referenced {
+                return i;
+            }
+            f.referenced = false;
+        }
+    }
+}
+
+// まだ書いていない所はゼロとして読む
+fn read_page(file: &mut File, page: u64, out: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+    let mut done = 0;
+    while done < out.len() {
+        match file.read(&mut out[done..])? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    out[done..].fill(0);
+    Ok(())
+}
+
+fn write_page(file: &mut File, page: u64, data: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+    file.write_all(data)
+}
+
+// 閉じたら消える一時ファイル
+pub struct PagedFile {
+    path: PathBuf,
+    pages: Mutex<Pages>,
+}
+
+impl PagedFile {
+    pub fn create(dir: &Path, kind: &str) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "recmagic-spill-{}-{}-{}.bin",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0),
+            kind
+        ));
+        let file = create_temp(&path)?;
+        Ok(Self {
+            path,
+            pages: Mutex::new(Pages {
+                file,
+                frames: Vec::new(),
+                slot_of: HashMap::new(),
+                hand: 0,
+            }),
+        })
+    }
+
+    pub fn read(&self, mut offset: u64, out: &mut [u8]) -> io::Result<()> {
+        let mut pages = self.pages.lock();
+        let mut done = 0;
+        while done < out.len() {
+            let at = (offset % PAGE_SIZE as u64) as usize;
+            let n = (PAGE_SIZE - at).min(out.len() - done);
+            let f = pages.frame(offset / PAGE_SIZE as u64)?;
+            out[done..done + n].copy_from_slice(&f.data[at..at + n]);
+            done += n;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, mut offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut pages = self.pages.lock();
+        let mut done = 0;
+        while done < data.len() {
+            let at = (offset % PAGE_SIZE as u64) as usize;
+            let n = (PAGE_SIZE - at).min(data.len() - done);
+            let f = pages.frame(offset / PAGE_SIZE as u64)?;
+            f.data[at..at + n].copy_from_slice(&data[done..done + n]);
+            f.dirty = true;
+            done += n;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+}
+
+// 異常終了しても残らないよう、閉じたときに消すフラグを付けて作る
+#[cfg(windows)]
+fn create_temp(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    use winapi::um::winbase::FILE_FLAG_DELETE_ON_CLOSE;
+    File::options()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .custom_flags(FILE_FLAG_DELETE_ON_CLOSE)
+        .open(path)
+}
+
+#[cfg(not(windows))]
+fn create_temp(path: &Path) -> io::Result<File> {
+    File::options()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(path)
+}
+
+impl Drop for PagedFile {
+    fn drop(&mut self) {
+        #[cfg(not(windows))]
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// 一時ファイルが読み書きできなくなるとインデックスを保てないので止める
+pub fn disk_io<T>(r: io::Result<T>) -> T {
+    r.unwrap_or_else(|e| panic!("spill file I/O failed: {}", e))
+}
+
+// SpillVec に置ける固定長のレコード
+pub trait FixedRecord: Copy {
+    const SIZE: usize;
+    fn put(&self, out: &mut [u8]);
+    fn get(b: &[u8]) -> Self;
+}
+
+const MAX_RECORD: usize = 64;
+
+// 番号で引く配列。spill_to の前に足した分はメモリ、後に足した分は一時ファイルに置く
+pub struct SpillVec<T> {
+    mem: Vec<T>,
+    disk: Option<PagedFile>,
+    len: usize,
+}
+
+impl<T> Default for SpillVec<T> {
+    fn default() -> Self {
+        Self {
+            mem: Vec::new(),
+            disk: None,
+            len: 0,
+        }
+    }
+}
+
+impl<T: FixedRecord> SpillVec<T> {
+    // n 個の value で埋める (disk を渡せば全部を一時ファイルに置く)
+    pub fn filled(n: usize, value: T, disk: Option<PagedFile>) -> Self {
+        let mut v = Self::default();
+        match disk {
+            None => v.mem = vec![value; n],
+            Some(d) => {
+                v.disk = Some(d);
+                for _ in 0..n {
+                    v.push(value);
+                }
+            }
+        }
+        v.len = n;
+        v
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        self.disk.is_some()
+    }
+
+    pub fn spill_to(&mut self, disk: PagedFile) {
+        if self.disk.is_none() {
+            self.mem.shrink_to_fit();
+            self.disk = Some(disk);
+        }
+    }
+
+    fn offset(&self, i: usize) -> u64 {
+        ((i - self.mem.len()) * T::SIZE) as u64
+    }
+
+    pub fn push(&mut self, value: T) {
+        match &self.disk {
+            None => self.mem.push(value),
+            Some(d) => {
+                let mut buf = [0u8; MAX_RECORD];
+                value.put(&mut buf[..T::SIZE]);
+                disk_io(d.write(self.offset(self.len), &buf[..T::SIZE]));
+            }
+        }
+        self.len += 1;
+    }
+
+    pub fn get(&self, i: usize) -> T {
+        if i < self.mem.len() {
+            return self.mem[i];
+        }
+        assert!(i < self.len, "index {} out of range ({})", i, self.len);
+        let mut buf = [0u8; MAX_RECORD];
+        let d = self.disk.as_ref().expect("spilled record without a file");
+        disk_io(d.read(self.offset(i), &mut buf[..T::SIZE]));
+        T::get(&buf[..T::SIZE])
+    }
+
+    pub fn set(&mut self, i: usize, value: T) {
+        if i < self.mem.len() {
+            self.mem[i] = value;
+            return;
+        }
+        assert!(i < self.len, "index {} out of range ({})", i, self.len);
+        let mut buf = [0u8; MAX_RECORD];
+        value.put(&mut buf[..T::SIZE]);
+        let d = self.disk.as_ref().expect("spilled record without a file");
+        disk_io(d.write(self.offset(i), &buf[..T::SIZE]));
+    }
+}
+
+impl FixedRecord for u32 {
+    const SIZE: usize = 4;
+    fn put(&self, out: &mut [u8]) {
+        out.copy_from_slice(&self.to_le_bytes());
+    }
+    fn get(b: &[u8]) -> Self {
+        u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+    }
+}
+
+// エントリ情報 (EntryMeta) は長さが変わるので、長さ + 中身 を追記していく
+pub struct SpillStore {
+    dir: PathBuf,
+    file: PagedFile,
+    end: u64,
+}
+
+impl SpillStore {
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            file: PagedFile::create(dir, "meta")?,
+            end: 0,
+        })
+    }
+
+    // 同じフォルダに別の一時ファイルを作る (ノードや名前用)
+    pub fn sibling(&self, kind: &str) -> io::Result<PagedFile> {
+        PagedFile::create(&self.dir, kind)
+    }
+
+    pub fn append(&mut self, meta: &EntryMeta) -> io::Result<u64> {
+        let buf = encode_meta(meta);
+        let off = self.end;
+        self.file.write(off, &(buf.len() as u32).to_le_bytes())?;
+        self.file.write(off + 4, &buf)?;
+        self.end += 4 + buf.len() as u64;
+        Ok(off)
+    }
+
+    // offset の内容を書き換える。長さが同じならその場で上書きし、変わるなら末尾に足す
+    // (種類や比較結果の書き換えでは長さは変わらない)。書いた位置を返す
+    pub fn store(&mut self, offset: u64, meta: &EntryMeta) -> io::Result<u64> {
+        let buf = encode_meta(meta);
+        let mut len = [0u8; 4];
+        self.file.read(offset, &mut len)?;
+        if offset + 4 + buf.len() as u64 <= self.end
+            && u32::from_le_bytes(len) as usize == buf.len()
+        {
+            self.file.write(offset + 4, &buf)?;
+            return Ok(offset);
+        }
+        self.append(meta)
+    }
+
+    pub fn load(&self, offset: u64) -> io::Result<EntryMeta> {
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, "bad spill entry");
+        if offset + 4 > self.end {
+            return Err(bad());
+        }
+        let mut len = [0u8; 4];
+        self.file.read(offset, &mut len)?;
+        let len = u32::from_le_bytes(len) as u64;
+        if offset + 4 + len > self.end {
+            return Err(bad());
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.file.read(offset + 4, &mut buf)?;
+        decode_meta(&buf).ok_or_else(bad)
+    }
+}
+
+fn put_filetimes(out: &mut Vec<u8>, t: &FileTimes) {
+    for v in [t.created, t.modified, t.mft_changed, t.accessed] {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+fn put_record_times(out: &mut Vec<u8>, t: &RecordTimes) {
+    match &t.si {
+        Some(si) => {
+            out.push(1);
+            put_filetimes(out, si);
+        }
+        None => out.push(0),
+    }
+    out.push(t.fn_times.len() as u8);
+    for (ns, ft) in &t.fn_times {
+        out.push(*ns);
+        put_filetimes(out, ft);
+    }
+    out.push(t.stomp.si_before_fn as u8 | (t.stomp.si_zero_subsec as u8) << 1);
+}
+
+pub fn encode_meta(m: &EntryMeta) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64);
+    out.extend_from_slice(&m.mft_no.to_le_bytes());
+    out.extend_from_slice(&m.sequence.to_le_bytes());
+    out.push(m.is_dir as u8);
+    out.extend_from_slice(&m.size.to_le_bytes());
+    out.extend_from_slice(&m.parent_ref.to_le_bytes());
+    out.push(match m.parent_status {
+        ParentStatus::Resolved => 0,
+        ParentStatus::Orphan => 1,
+        ParentStatus::Reused => 2,
+    });
+    put_record_times(&mut out, &m.times);
+    out.extend_from_slice(&m.link_count.to_le_bytes());
+    out.extend_from_slice(&m.attributes.to_le_bytes());
+    out.push(m.live as u8);
+    out.push(match m.live_match {
+        LiveMatch::NotChecked => 0,
+        LiveMatch::Absent => 1,
+        LiveMatch::Differs => 2,
+        LiveMatch::Identical => 3,
+    });
+    // 0 は未判定、それ以外は種類の番号 + 1
+    out.push(m.content_type.map_or(0, |c| c.id() + 1));
+    out
+}
+
+struct Cursor<'a> {
+    b: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let s = self.b.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(s)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|s| s[0])
+    }
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|s| u16::from_le_bytes([s[0], s[1]]))
+    }
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|s| u32::from_le_bytes([s[0], s[1], s[2], s[3]]))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        let s = self.take(8)?;
+        let mut a = [0u8; 8];
+        a.copy_from_slice(s);
+        Some(u64::from_le_bytes(a))
+    }
+    fn filetimes(&mut self) -> Option<FileTimes> {
+        Some(FileTimes {
+            created: self.u64()?,
+            modified: self.u64()?,
+            mft_changed: self.u64()?,
+            accessed: self.u64()?,
+        })
+    }
+    fn record_times(&mut self) -> Option<RecordTimes> {
+        let si = match self.u8()? {
+            0 => None,
+            _ => Some(self.filetimes()?),
+        };
+        let n = self.u8()? as usize;
+        let mut fn_times = Vec::with_capacity(n);
+        for _ in 0..n {
+            let ns = self.u8()?;
+            fn_times.push((ns, self.filetimes()?));
+        }
+        let flags = self.u8()?;
+        Some(RecordTimes {
+            si,
+            fn_times,
+            stomp: TimestompFlags {
+                si_before_fn: flags & 1 != 0,
+                si_zero_subsec: flags & 2 != 0,
+            },
+        })
+    }
+}
+
+pub fn decode_meta(b: &[u8]) -> Option<EntryMeta> {
+    let mut c = Cursor { b, pos: 0 };
+    let mft_no = c.u64()?;
+    let sequence = c.u16()?;
+    let is_dir = c.u8()? != 0;
+    let size = c.u64()?;
+    let parent_ref = c.u64()?;
+    let parent_status = match c.u8()? {
+        0 => ParentStatus::Resolved,
+        1 => ParentStatus::Orphan,
+        2 => ParentStatus::Reused,
+        _ => return None,
+    };
+    let times = c.record_times()?;
+    let link_count = c.u16()?;
+    let attributes = c.u32()?;
+    let live = c.u8()? != 0;
+    let live_match = match c.u8()? {
+        0 => LiveMatch::NotChecked,
+        1 => LiveMatch::Absent,
+        2 => LiveMatch::Differs,
+        3 => LiveMatch::Identical,
+        _ => return None,
+    };
+    let content_type = match c.u8()? {
+        0 => None,
+        n => Some(ContentType::from_id(n - 1)?),
+    };
+    Some(EntryMeta {
+        mft_no,
+        sequence,
+        is_dir,
+        size,
+        parent_ref,
+        parent_status,
+        times,
+        link_count,
+        attributes,
+        live,
+        live_match,
+        content_type,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file() -> PagedFile {
+        PagedFile::create(&std::env::temp_dir(), "test").unwrap()
+    }
+
+    #[test]
+    fn paged_file_survives_eviction() {
+        let file = temp_file();
+        // キャッシュより多いページに、ページの境目をまたいで書く
+        let pages = CACHED_PAGES as u64 + 100;
+        let chunk: Vec<u8> = (0..100u8).collect();
+        for p in 0..pages {
+            file.write(p * PAGE_SIZE as u64 + PAGE_SIZE as u64 - 50, &chunk)
+                .unwrap();
+        }
+        for p in (0..pages).rev() {
+            let mut back = vec![0u8; 100];
+            file.read(p * PAGE_SIZE as u64 + PAGE_SIZE as u64 - 50, &mut back)
+                .unwrap();
+            assert_eq!(back, chunk);
+        }
+        // 書いていない所はゼロ
+        let mut tail = [1u8; 8];
+        file.read((pages + 5) * PAGE_SIZE as u64, &mut tail)
+            .unwrap();
+        assert_eq!(tail, [0u8; 8]);
+    }
+
+    #[test]
+    fn spill_vec_keeps_order_across_memory_and_file() {
+        let mut v: SpillVec<u32> = SpillVec::default();
+        for i in 0..10 {
+            v.push(i);
+        }
+        v.spill_to(temp_file());
+        for i in 10..5000 {
+            v.push(i);
+        }
+        v.set(3, 300);
+        v.set(4000, 40);
+        assert_eq!(v.len(), 5000);
+        assert_eq!((v.get(2), v.get(3), v.get(9), v.get(10)), (2, 300, 9, 10));
+        assert_eq!((v.get(3999), v.get(4000), v.get(4999)), (3999, 40, 4999));
+    }
+
+    #[test]
+    fn store_overwrites_entries_in_place() {
+        let mut store = SpillStore::create(&std::env::temp_dir()).unwrap();
+        let mut meta = EntryMeta {
+            mft_no: 42,
+            sequence: 3,
+            is_dir: false,
+            size: 10,
+            parent_ref: 5,
+            parent_status: ParentStatus::Resolved,
+            times: RecordTimes::default(),
+            link_count: 1,
+            attributes: 0,
+            live: false,
+            live_match: LiveMatch::NotChecked,
+            content_type: None,
+        };
+        let first = store.append(&meta).unwrap();
+        let second = store.append(&meta).unwrap();
+        let end = store.end;
+        meta.live_match = LiveMatch::Identical;
+        assert_eq!(store.store(first, &meta).unwrap(), first);
+        assert_eq!(store.end, end);
+        assert_eq!(store.load(first).unwrap().live_match, LiveMatch::Identical);
+        assert_eq!(
+            store.load(second).unwrap().live_match,
+            LiveMatch::NotChecked
+        );
+        // 長さが変わるときは末尾に足す
+        meta.times.si = Some(FileTimes::default());
+        let moved = store.store(first, &meta).unwrap();
+        assert_eq!(moved, end);
+        assert!(store.load(moved).unwrap().times.si.is_some());
+    }
+}
@@ -0,0 +1,259 @@
+use crate::record::{FileNameAttr, StdInfo};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// $STANDARD_INFORMATION と $FILE_NAME の時刻をそのまま (FILETIME, 100ns単位) 持つ
+// 改ざん (timestomping) の痕跡もここで判定する
+
+// 1601-01-01 から 1970-01-01 までの 100ns 数
+const FILETIME_UNIX_DIFF: u64 = 116_444_736_000_000_000;
+const TICKS_PER_SEC: u64 = 10_000_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileTimes {
+    pub created: u64,
+    pub modified: u64,
+    pub mft_changed: u64,
+    pub accessed: u64,
+}
+
+impl From<&StdInfo> for FileTimes {
+    fn from(si: &StdInfo) -> Self {
+        Self {
+            created: si.created,
+            modified: si.modified,
+            mft_changed: si.mft_changed,
+            accessed: si.accessed,
+        }
+    }
+}
+
+impl From<&FileNameAttr> for FileTimes {
+    fn from(f: &FileNameAttr) -> Self {
+        Self {
+            created: f.created,
+            modified: f.modified,
+            mft_changed: f.mft_changed,
+            accessed: f.accessed,
+        }
+    }
+}
+
+// 改ざんの疑いがある点
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimestompFlags {
+    // SI の時刻が FN の時刻より前になっている
+    pub si_before_fn: bool,
+    // SI の作成/更新時刻の秒未満がちょうど 0
+    pub si_zero_subsec: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecordTimes {
+    pub si: Option<FileTimes>,
+    // $FILE_NAME ごとの (名前空間, 時刻)
+    pub fn_times: Vec<(u8, FileTimes)>,
+    pub stomp: TimestompFlags,
+}
+
+impl RecordTimes {
+    pub fn new(si: Option<&StdInfo>, names: &[FileNameAttr]) -> Self {
+        let mut t = Self {
+            si: si.map(FileTimes::from),
+            fn_times: names
+                .iter()
+                .map(|f| (f.namespace, FileTimes::from(f)))
+                .collect(),
+            stomp: TimestompFlags::default(),
+        };
+        t.stomp = t.detect();
+        t
+    }
+
+    // SI は SetFileTime で自由に書き換えられるが、FN はカーネルしか書かない
+    // そのため SI が FN より古い、あるいは秒未満がきれいに 0 のものは書き換えの疑いがある
+    fn detect(&self) -> TimestompFlags {
+        let mut flags = TimestompFlags::default();
+        let si = match self.si {
+            Some(si) => si,
+            None => return flags,
+        };
+        for (_, fnt) in &self.fn_times {
+            let pairs = [
+                (si.created, fnt.created),
+                (si.modified, fnt.modified),
+                (si.mft_changed, fnt.mft_changed),
+            ];
+            if pairs.iter().any(|&(s, f)| s != 0 && f != 0 && s < f) {
+                flags.si_before_fn = true;
+            }
+        }
+        flags.si_zero_subsec = [si.created, si.modified]
+            .iter()
+            .any(|&t| t != 0 && t % TICKS_PER_SEC == 0);
+        flags
+    }
+
+    // Dokan 等に見せる時刻 (SI が無ければ最初の FN)
+    pub fn primary(&self) -> Option<FileTimes> {
+        self.si.or_else(|| self.fn_times.first().map(|&(_, t)| t))
+    }
+}
+
+// FILETIME を精度を落とさずに SystemTime にする (1970年より前もそのまま)
+// 0 は「未設定」として None
+pub fn filetime_to_system_time(ft: u64) -> Option<SystemTime> {
+    if ft == 0 {
+        return None;
+    }
+    let to_dur = |ticks: u64| {
+        Duration::new(
+            ticks / TICKS_PER_SEC,
+            ((ticks % TICKS_PER_SEC) * 100) as u32,
+        )
+    };
+    if ft >= FILETIME_UNIX_DIFF {
+        UNIX_EPOCH.checked_add(to_dur(ft - FILETIME_UNIX_DIFF))
+    } else {
+        UNIX_EPOCH.checked_sub(to_dur(FILETIME_UNIX_DIFF - ft))
+    }
+}
+
+// 暦の上の日時 (UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CivilTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+// FILETIME を UTC の日時にする (0 は None)
+pub fn filetime_to_civil(ft: u64) -> Option<CivilTime> {
+    if ft == 0 {
+        return None;
+    }
+    let secs = (ft / TICKS_PER_SEC) as i64 - (FILETIME_UNIX_DIFF / TICKS_PER_SEC) as i64;
+    Some(unix_to_civil(secs))
+}
+
+// FILETIME を 100ns 単位まで残した ISO 8601 (UTC) にする (0 は None)
+pub fn filetime_to_iso(ft: u64) -> Option<String> {
+    let c = filetime_to_civil(ft)?;
+    Some(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:07}Z",
+        c.year,
+        c.month,
+        c.day,
+        c.hour,
+        c.minute,
+        c.second,
+        ft % TICKS_PER_SEC
+    ))
+}
+
+// UNIX秒を UTC の日時にする
+pub fn unix_to_civil(secs: i64) -> CivilTime {
+    let days = secs.div_euclid(86_400);
+    let rem = secs.rem_euclid(86_400) as u32;
+    // 1970-01-01 からの日数をグレゴリオ暦に直す (Howard Hinnant の civil_from_days)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    CivilTime {
+        year,
+        month,
+        day,
+        hour: rem / 3600,
+        minute: rem / 60 % 60,
+        second: rem % 60,
+    }
+}
+
+// 暦の日付から 1970-01-01 からの日数にする (Howard Hinnant の days_from_civil)
+// month は 1-12 の範囲外でもよい (前後の年に繰り上げる)
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let (year, month) = (
+        year + (month - 1).div_euclid(12),
+        (month - 1).rem_euclid(12) + 1,
+    );
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn civil(year: i64, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> CivilTime {
+        CivilTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn zero_filetime_is_unset() {
+        assert_eq!(filetime_to_system_time(0), None);
+        assert_eq!(filetime_to_civil(0), None);
+        assert_eq!(filetime_to_iso(0), None);
+    }
+
+    #[test]
+    fn filetime_keeps_100ns_ticks() {
+        assert_eq!(
+            filetime_to_system_time(FILETIME_UNIX_DIFF),
+            Some(UNIX_EPOCH)
+        );
+        assert_eq!(
+            filetime_to_system_time(FILETIME_UNIX_DIFF + 12_345_678),
+            Some(UNIX_EPOCH + Duration::new(1, 234_567_800))
+        );
+        // 1970年の直前は UNIX_EPOCH より前
+        assert_eq!(
+            filetime_to_system_time(FILETIME_UNIX_DIFF - 1),
+            Some(UNIX_EPOCH - Duration::from_nanos(100))
+        );
+        assert_eq!(
+            filetime_to_civil(FILETIME_UNIX_DIFF - 1),
+            Some(civil(1969, 12, 31, 23, 59, 59))
+        );
+        assert_eq!(
+            filetime_to_iso(FILETIME_UNIX_DIFF - 1).as_deref(),
+            Some("1969-12-31T23:59:59.9999999Z")
+        );
+    }
+
+    #[test]
+    fn filetime_in_1601() {
+        // FILETIME の起点の直後
+        assert_eq!(filetime_to_civil(1), Some(civil(1601, 1, 1, 0, 0, 0)));
+        assert_eq!(
+            filetime_to_iso(1).as_deref(),
+            Some("1601-01-01T00:00:00.0000001Z")
+        );
+        assert_eq!(
+            filetime_to_system_time(1),
+            UNIX_EPOCH.checked_sub(Duration::new(11_644_473_599, 999_999_900))
+        );
+        let ft = 3 * 86_400 * TICKS_PER_SEC + 5;
+        assert_eq!(filetime_to_civil(ft), Some(civil(1601, 1, 4, 0, 0, 0)));
+    }
+}
@@ -0,0 +1,121 @@
+use crate::mft_stream::MftStream;
+use crate::record::{self, ATTR_DATA};
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+// NTFSと同じ大文字小文字の扱いをするための $UpCase 表
+// ボリュームから読めない場合 (イメージ等) は Rust の to_uppercase から作った表を使う
+
+const UPCASE_RECORD: u64 = 10;
+const UPCASE_LEN: usize = 0x10000;
+
+pub struct UpCaseTable {
+    map: Box<[u16]>,
+}
+
+impl UpCaseTable {
+    pub fn builtin() -> Self {
+        let map: Vec<u16> = (0..UPCASE_LEN as u32)
+            .map(|c| match char::from_u32(c) {
+                Some(ch) => {
+                    let mut up = ch.to_uppercase();
+                    match (up.next(), up.next()) {
+                        (Some(u), None) if (u as u32) <= 0xFFFF => u as u32 as u16,
+                        _ => c as u16,
+                    }
+                }
+                None => c as u16,
+            })
+            .collect();
+        Self {
+            map: map.into_boxed_slice(),
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < UPCASE_LEN * 2 {
+            bail!("$UpCase is too short: {} bytes", data.len());
+        }
+        let map: Vec<u16> = data[..UPCASE_LEN * 2]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        Ok(Self {
+            map: map.into_boxed_slice(),
+        })
+    }
+
+    pub fn load_from_volume(stream: &MftStream) -> Result<Self> {
+        let rec = stream
+            .read_record(UPCASE_RECORD)
+            .context("read $UpCase record")?;
+        let data = record::attributes(&rec)
+            .find(|a| a.type_code == ATTR_DATA && a.name_len == 0)
+            .context("$UpCase has no $DATA attribute")?;
+        let bytes = match (data.run_list(), data.resident_value()) {
+            (Some(runs), _) => stream.read_nonresident(runs, (UPCASE_LEN * 2) as u64)?,
+            (None, Some(v)) => v.to_vec(),
+            (None, None) => bail!("$UpCase $DATA is unreadable"),
+        };
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn fold(&self, c: u16) -> u16 {
+        self.map[c as usize]
+    }
+
+    pub fn eq(&self, a: &[u16], b: &[u16]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(&x, &y)| self.fold(x) == self.fold(y))
+    }
+
+    pub fn hash(&self, name: &[u16]) -> u64 {
+        let mut h: u64 = 0xcbf29ce484222325;
+        for &c in name {
+            h ^= self.fold(c) as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+        h
+    }
+
+    // 「*」と「?」だけのワイルドカード照合
+    pub fn glob_match(&self, name: &[u16], pat: &[u16]) -> bool {
+        let star = b'*' as u16;
+        let any = b'?' as u16;
+        let (mut i, mut j) = (0usize, 0usize);
+        let (mut star_i, mut star_j) = (None, None);
+        while i < name.len() {
+            if j < pat.len() && (pat[j] == any || self.fold(pat[j]) == self.fold(name[i])) {
+                i += 1;
+                j += 1;
+            } else if j < pat.len() && pat[j] == star {
+                star_i = Some(i);
+                star_j = Some(j);
+                j += 1;
+            } else if let (Some(si), Some(sj)) = (star_i, star_j) {
+                i = si + 1;
+                star_i = Some(i);
+                j = sj + 1;
+            } else {
+                return false;
+            }
+        }
+        while j < pat.len() && pat[j] == star {
+            j += 1;
+        }
+        j == pat.len()
+    }
+}
+
+static ACTIVE: Lazy<RwLock<Arc<UpCaseTable>>> =
+    Lazy::new(|| RwLock::new(Arc::new(UpCaseTable::builtin())));
+
+// 現在スキャン中/マウント中のボリュームの表
+pub fn active() -> Arc<UpCaseTable> {
+    ACTIVE.read().clone()
+}
+
+pub fn set_active(table: Arc<UpCaseTable>) {
+    *ACTIVE.write() = table;
+}
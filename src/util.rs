@@ -1,5 +1,5 @@
 use anyhow::{Result, bail};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // 諸々の便利関数
 
@@ -20,83 +20,81 @@ pub fn normalize_device(letter: &str) -> Result<String> {
     bail!("unsupported drive letter: {}", s)
 }
 
-pub fn normalize_candidate_path(raw: &str) -> String {
-    let mut p = raw.replace('/', "\\");
-    if p.starts_with(r"\??\") {
-        p = p.trim_start_matches(r"\??\").to_string();
-    } else if p.starts_with(r"\\?\") {
-        p = p.trim_start_matches(r"\\?\").to_string();
-    } else if p.starts_with(r"\\.\") {
-        p = p.trim_start_matches(r"\\.\").to_string();
-    }
-    if p.len() >= 3 && p.as_bytes()[1] == b':' && p.as_bytes()[2] == b'\\' {
-        p = format!("\\{}", &p[3..]);
-    }
-    if p.len() >= 4
-        && p.as_bytes()[0] == b'\\'
-        && p.as_bytes()[2] == b':'
-        && p.as_bytes()[3] == b'\\'
-    {
-        p = format!("\\{}", &p[4..]);
-    }
-    if !p.starts_with('\\') {
-        p.insert(0, '\\');
-    }
-    while p.starts_with("\\\\") {
-        p.remove(0);
+// UTF-16の名前を表示/書き出し用の文字列にする
+// 対になっていないサロゲートは「%uXXXX」で表し、元から「%uXXXX」の形をしている部分は「%」を「%u0025」にする
+// unescape_u16 で元のUTF-16にそのまま戻せる
+pub fn escape_u16(name: &[u16]) -> String {
+    let units: Vec<Result<char, u16>> = char::decode_utf16(name.iter().copied())
+        .map(|r| r.map_err(|e| e.unpaired_surrogate()))
+        .collect();
+    let mut out = String::with_capacity(name.len());
+    for (i, u) in units.iter().enumerate() {
+        match *u {
+            Ok('%') if looks_like_escape(&units[i + 1..]) => out.push_str("%u0025"),
+            Ok(c) => out.push(c),
+            Err(s) => out.push_str(&format!("%u{:04X}", s)),
+        }
     }
-    p
+    out
 }
 
-pub fn normalize_and_canonicalize_for_key(raw: &str) -> String {
-    let mut p = raw.replace('/', "\\");
-    if p.starts_with(r"\??\") {
-        p = p[r"\??\".len()..].to_string();
-    } else if p.starts_with(r"\\?\") {
-        p = p[r"\\?\".len()..].to_string();
-    } else if p.starts_with(r"\\.\") {
-        p = p[r"\\.\".len()..].to_string();
-    }
-    if p.len() >= 3 && p.as_bytes()[1] == b':' && p.as_bytes()[2] == b'\\' {
-        p = format!(r"\{}", &p[3..]);
-    }
-    if p.len() >= 4
-        && p.as_bytes()[0] == b'\\'
-        && p.as_bytes()[2] == b':'
-        && p.as_bytes()[3] == b'\\'
-    {
-        p = format!(r"\{}", &p[4..]);
-    }
-    if !p.starts_with('\\') {
-        p.insert(0, '\\');
-    }
-    let mut stack: Vec<&str> = Vec::new();
-    for part in p.split('\\') {
-        if part.is_empty() || part == "." {
-            continue;
-        }
-        if part == ".." {
-            if !stack.is_empty() {
-                stack.pop();
+// '%' の後ろが「uXXXX」になっているか
+fn looks_like_escape(rest: &[Result<char, u16>]) -> bool {
+    rest.len() >= 5
+        && rest[0] == Ok('u')
+        && rest[1..5]
+            .iter()
+            .all(|u| matches!(u, Ok(c) if c.is_ascii_hexdigit()))
+}
+
+pub fn unescape_u16(s: &str) -> Vec<u16> {
+    let mut out: Vec<u16> = Vec::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find('%') {
+        out.extend(rest[..pos].encode_utf16());
+        rest = &rest[pos..];
+        match parse_escape(rest) {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[6..];
+            }
+            None => {
+                out.push(b'%' as u16);
+                rest = &rest[1..];
             }
-            continue;
         }
-        stack.push(part);
     }
-    if stack.is_empty() {
-        "\\".to_string()
-    } else {
-        let mut out = String::from("\\");
-        out.push_str(&stack.join("\\"));
-        out.to_lowercase()
+    out.extend(rest.encode_utf16());
+    out
+}
+
+// 「%uXXXX」で始まっていればその値
+fn parse_escape(s: &str) -> Option<u16> {
+    let b = s.as_bytes();
+    if b.len() < 6 || b[0] != b'%' || b[1] != b'u' {
+        return None;
+    }
+    let hex = std::str::from_utf8(&b[2..6]).ok()?;
+    if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
     }
+    u16::from_str_radix(hex, 16).ok()
 }
 
-pub fn unix_ts_to_system_time(ts: i64) -> SystemTime {
-    if ts <= 0 {
-        UNIX_EPOCH
-    } else {
-        UNIX_EPOCH + Duration::from_secs(ts as u64)
+// SystemTime を (UNIX秒, 秒未満のナノ秒) にする
+// 1970年より前は秒が負になり、ナノ秒は常に 0..1e9 の範囲
+pub fn system_time_to_unix_parts(t: SystemTime) -> (i64, u32) {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            let d = e.duration();
+            let (secs, nanos) = (-(d.as_secs() as i64), d.subsec_nanos());
+            if nanos == 0 {
+                (secs, 0)
+            } else {
+                (secs - 1, 1_000_000_000 - nanos)
+            }
+        }
     }
 }
 
@@ -109,3 +107,89 @@ pub fn humanize_bytes(mut b: u64) -> String {
     }
     format!("{} {}", b, UNITS[idx])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamps::filetime_to_system_time;
+    use std::time::Duration;
+
+    fn u16s(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    fn roundtrip(name: &[u16]) -> String {
+        let s = escape_u16(name);
+        assert_eq!(unescape_u16(&s), name, "{}", s);
+        s
+    }
+
+    #[test]
+    fn unpaired_surrogates_are_escaped() {
+        let mut high = u16s("a");
+        high.extend([0xD83D, b'b' as u16]);
+        assert_eq!(roundtrip(&high), "a%uD83Db");
+        let mut low = u16s("a");
+        low.extend([0xDE00, b'b' as u16]);
+        assert_eq!(roundtrip(&low), "a%uDE00b");
+        // 末尾の上位サロゲートと、順番が逆の組
+        assert_eq!(roundtrip(&[b'x' as u16, 0xD83D]), "x%uD83D");
+        assert_eq!(roundtrip(&[0xDE00, 0xD83D]), "%uDE00%uD83D");
+        // 正しい組はそのまま
+        assert_eq!(roundtrip(&u16s("😀")), "😀");
+    }
+
+    #[test]
+    fn literal_escapes_in_names_survive() {
+        assert_eq!(roundtrip(&u16s("%u0041")), "%u0025u0041");
+        assert_eq!(roundtrip(&u16s("a%%u0041")), "a%%u0025u0041");
+        assert_eq!(roundtrip(&u16s("%u0025")), "%u0025u0025");
+    }
+
+    #[test]
+    fn percent_without_escape_is_kept() {
+        assert_eq!(roundtrip(&u16s("100%")), "100%");
+        assert_eq!(roundtrip(&u16s("%")), "%");
+        assert_eq!(roundtrip(&u16s("%u")), "%u");
+        assert_eq!(roundtrip(&u16s("%uZZZZ")), "%uZZZZ");
+        assert_eq!(roundtrip(&u16s("%u12")), "%u12");
+        assert_eq!(roundtrip(&u16s("%u12g4")), "%u12g4");
+        // 多バイト文字が続いても切り方を誤らない
+        assert_eq!(roundtrip(&u16s("%uあいう")), "%uあいう");
+        assert_eq!(roundtrip(&u16s("%u123あ")), "%u123あ");
+    }
+
+    #[test]
+    fn unix_parts_before_1970() {
+        let ns = Duration::from_nanos;
+        assert_eq!(system_time_to_unix_parts(UNIX_EPOCH), (0, 0));
+        assert_eq!(
+            system_time_to_unix_parts(UNIX_EPOCH + ns(1_250_000_000)),
+            (1, 250_000_000)
+        );
+        // 秒は切り捨て、ナノ秒は正のまま
+        assert_eq!(
+            system_time_to_unix_parts(UNIX_EPOCH - ns(1_500_000_000)),
+            (-2, 500_000_000)
+        );
+        assert_eq!(
+            system_time_to_unix_parts(UNIX_EPOCH - ns(100)),
+            (-1, 999_999_900)
+        );
+        assert_eq!(
+            system_time_to_unix_parts(UNIX_EPOCH - Duration::from_secs(1)),
+            (-1, 0)
+        );
+    }
+
+    #[test]
+    fn unix_parts_from_filetime() {
+        let parts = |ft| filetime_to_system_time(ft).map(system_time_to_unix_parts);
+        assert_eq!(parts(0), None);
+        // 1601-01-01T00:00:00.0000001Z
+        assert_eq!(parts(1), Some((-11_644_473_600, 100)));
+        // 1969-12-31T23:59:59.9999999Z
+        assert_eq!(parts(116_444_736_000_000_000 - 1), Some((-1, 999_999_900)));
+        assert_eq!(parts(116_444_736_012_345_678), Some((1, 234_567_800)));
+    }
+}